@@ -0,0 +1,328 @@
+//! Blocking until a set of processes has exited, without re-listing processes: kqueue
+//! `EVFILT_PROC` on macOS, pidfd and `poll` on Linux, and polling with `kill(pid, 0)`
+//! where neither is available.
+
+use super::ExitEvent;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+/// Block until every process in `pids` has exited or `timeout` has passed, returning
+/// the exits in the order they happened.
+pub fn wait_for_exits(pids: &[u32], timeout: Duration) -> Result<Vec<ExitEvent>> {
+    let start = Instant::now();
+    if pids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    #[cfg(target_os = "macos")]
+    if let Some(events) = kqueue::wait(pids, start, timeout)? {
+        return Ok(events);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(events) = pidfd::wait(pids, start, timeout)? {
+        return Ok(events);
+    }
+
+    Ok(poll_wait(pids, start, timeout))
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn poll_wait(pids: &[u32], start: Instant, timeout: Duration) -> Vec<ExitEvent> {
+    let mut remaining = pids.to_vec();
+    let mut events = Vec::new();
+    loop {
+        remaining.retain(|pid| {
+            if is_running(*pid) {
+                return true;
+            }
+            events.push(ExitEvent {
+                pid: *pid,
+                elapsed: start.elapsed(),
+            });
+            false
+        });
+        if remaining.is_empty() || start.elapsed() >= timeout {
+            return events;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod kqueue {
+    use super::ExitEvent;
+    use anyhow::Result;
+    use std::io;
+    use std::ptr;
+    use std::time::{Duration, Instant};
+
+    struct Kqueue(libc::c_int);
+
+    impl Drop for Kqueue {
+        fn drop(&mut self) {
+            // SAFETY: the descriptor is owned by this value.
+            unsafe { libc::close(self.0) };
+        }
+    }
+
+    fn exit_filter(pid: u32) -> libc::kevent {
+        libc::kevent {
+            ident: pid as libc::uintptr_t,
+            filter: libc::EVFILT_PROC,
+            flags: libc::EV_ADD | libc::EV_ONESHOT,
+            fflags: libc::NOTE_EXIT,
+            data: 0,
+            udata: ptr::null_mut(),
+        }
+    }
+
+    /// `None` if a kqueue could not be created.
+    pub fn wait(pids: &[u32], start: Instant, timeout: Duration) -> Result<Option<Vec<ExitEvent>>> {
+        // SAFETY: kqueue has no preconditions.
+        let kq = unsafe { libc::kqueue() };
+        if kq < 0 {
+            return Ok(None);
+        }
+        let kq = Kqueue(kq);
+
+        let mut events = Vec::new();
+        let mut watching = 0;
+        for pid in pids {
+            let change = exit_filter(*pid);
+            // SAFETY: `change` is a valid kevent and no events are requested.
+            let result = unsafe { libc::kevent(kq.0, &change, 1, ptr::null_mut(), 0, ptr::null()) };
+            if result == 0 {
+                watching += 1;
+                continue;
+            }
+            match io::Error::last_os_error().raw_os_error() {
+                // Already gone.
+                Some(libc::ESRCH) => events.push(ExitEvent {
+                    pid: *pid,
+                    elapsed: start.elapsed(),
+                }),
+                _ => anyhow::bail!(
+                    "Failed to watch PID {}: {}",
+                    pid,
+                    io::Error::last_os_error()
+                ),
+            }
+        }
+
+        while watching > 0 {
+            let Some(left) = timeout.checked_sub(start.elapsed()) else {
+                break;
+            };
+            let deadline = libc::timespec {
+                tv_sec: left.as_secs() as libc::time_t,
+                tv_nsec: left.subsec_nanos() as libc::c_long,
+            };
+            // SAFETY: all-zero is a valid kevent.
+            let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+            // SAFETY: `event` is valid for writes of one kevent.
+            let n = unsafe { libc::kevent(kq.0, ptr::null(), 0, &mut event, 1, &deadline) };
+            if n < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                anyhow::bail!("kevent failed: {}", error);
+            }
+            if n == 0 {
+                break; // timed out
+            }
+            watching -= 1;
+            events.push(ExitEvent {
+                pid: event.ident as u32,
+                elapsed: start.elapsed(),
+            });
+        }
+
+        Ok(Some(events))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pidfd {
+    use super::ExitEvent;
+    use anyhow::Result;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::{Duration, Instant};
+
+    /// `None` if the kernel does not support pidfds.
+    pub fn wait(pids: &[u32], start: Instant, timeout: Duration) -> Result<Option<Vec<ExitEvent>>> {
+        let mut events = Vec::new();
+        let mut watched: Vec<(u32, OwnedFd)> = Vec::new();
+        for pid in pids {
+            // SAFETY: pidfd_open takes a PID and flags and returns a new descriptor.
+            let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, *pid as libc::pid_t, 0) };
+            if fd >= 0 {
+                // SAFETY: the descriptor was just created and is owned by nobody else.
+                watched.push((*pid, unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) }));
+                continue;
+            }
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ESRCH) => events.push(ExitEvent {
+                    pid: *pid,
+                    elapsed: start.elapsed(),
+                }),
+                Some(libc::ENOSYS) => return Ok(None),
+                _ => anyhow::bail!(
+                    "Failed to watch PID {}: {}",
+                    pid,
+                    io::Error::last_os_error()
+                ),
+            }
+        }
+
+        while !watched.is_empty() {
+            let Some(left) = timeout.checked_sub(start.elapsed()) else {
+                break;
+            };
+            let mut fds: Vec<libc::pollfd> = watched
+                .iter()
+                .map(|(_, fd)| libc::pollfd {
+                    fd: fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            // Round up so that a sub-millisecond remainder does not spin.
+            let millis = left
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128);
+            // SAFETY: `fds` is valid for `fds.len()` pollfd structs.
+            let n = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    millis as libc::c_int,
+                )
+            };
+            if n < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                anyhow::bail!("poll failed: {}", error);
+            }
+            if n == 0 {
+                break; // timed out
+            }
+
+            let elapsed = start.elapsed();
+            let mut still_running = Vec::new();
+            for ((pid, fd), pollfd) in watched.into_iter().zip(&fds) {
+                if pollfd.revents != 0 {
+                    events.push(ExitEvent { pid, elapsed });
+                } else {
+                    still_running.push((pid, fd));
+                }
+            }
+            watched = still_running;
+        }
+
+        Ok(Some(events))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+    use std::thread::JoinHandle;
+
+    fn sleep(seconds: &str) -> Child {
+        Command::new("sleep").arg(seconds).spawn().unwrap()
+    }
+
+    /// A PID that belonged to a process which has exited and been reaped.
+    fn exited_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    /// Reap `child` as soon as it exits, so that it does not linger as a zombie that
+    /// `kill(pid, 0)` still finds.
+    fn reap(mut child: Child) -> (u32, JoinHandle<()>) {
+        let pid = child.id();
+        (
+            pid,
+            std::thread::spawn(move || {
+                child.wait().unwrap();
+            }),
+        )
+    }
+
+    #[test]
+    fn reports_a_child_that_exits() {
+        let mut child = sleep("0.2");
+
+        let events = wait_for_exits(&[child.id()], Duration::from_secs(10)).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pid, child.id());
+        assert!(events[0].elapsed >= Duration::from_millis(100));
+        assert!(events[0].elapsed < Duration::from_secs(10));
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn reports_an_already_exited_pid_right_away() {
+        let pid = exited_pid();
+
+        let events = wait_for_exits(&[pid], Duration::from_secs(10)).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pid, pid);
+        assert!(events[0].elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn returns_only_the_exits_seen_before_the_timeout() {
+        let mut running = sleep("30");
+        let mut exiting = sleep("0.1");
+        let start = Instant::now();
+
+        let events =
+            wait_for_exits(&[running.id(), exiting.id()], Duration::from_millis(500)).unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pid, exiting.id());
+        running.kill().unwrap();
+        running.wait().unwrap();
+        exiting.wait().unwrap();
+    }
+
+    #[test]
+    fn polling_reports_exits_and_times_out() {
+        let (exiting, exiting_reaper) = reap(sleep("0.1"));
+        let gone = exited_pid();
+        let mut running = sleep("30");
+
+        let events = poll_wait(
+            &[exiting, gone, running.id()],
+            Instant::now(),
+            Duration::from_millis(500),
+        );
+
+        let pids: Vec<u32> = events.iter().map(|e| e.pid).collect();
+        assert_eq!(pids, vec![gone, exiting]);
+        assert!(events[0].elapsed < events[1].elapsed);
+        running.kill().unwrap();
+        running.wait().unwrap();
+        exiting_reaper.join().unwrap();
+    }
+}
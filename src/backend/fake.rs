@@ -0,0 +1,915 @@
+use super::{Clock, Election, ExitEvent, ProcessInfo, Signal, SignalOutcome, SystemBackend};
+use crate::discovery::DROPBOX_TEAM_ID;
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A clock that only advances when something sleeps on it.
+pub struct FakeClock {
+    start: Instant,
+    elapsed: Cell<Duration>,
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+}
+
+/// A call made against [`FakeBackend`], in the order it was made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    ListProcesses,
+    ProcessInfo(u32),
+    WaitForExits(Vec<u32>),
+    CodeSigningTeamId(PathBuf),
+    QuitDropbox,
+    LaunchDropbox,
+    Signal(u32, Signal),
+    UserId,
+    LaunchctlBootout(String),
+    LaunchctlBootstrap(String, PathBuf),
+    LaunchctlPrint(String),
+    LaunchctlList,
+    LaunchctlDisable(String),
+    LaunchctlEnable(String),
+    LaunchctlPrintDisabled(String),
+    SetImmutable(PathBuf, bool),
+    PluginkitMatch(String),
+    PluginkitElect(String, Election),
+    PluginkitElectUuid(String, Election),
+    PluginkitAdd(PathBuf),
+    PluginkitRemove(PathBuf),
+    RestartFinder,
+    PsCpuTimes(Vec<u32>),
+    NettopBytes,
+}
+
+impl Call {
+    /// Whether the call changes the machine, as opposed to only looking at it.
+    pub fn mutates(&self) -> bool {
+        !matches!(
+            self,
+            Call::ListProcesses
+                | Call::ProcessInfo(_)
+                | Call::WaitForExits(_)
+                | Call::CodeSigningTeamId(_)
+                | Call::UserId
+                | Call::LaunchctlPrint(_)
+                | Call::LaunchctlList
+                | Call::LaunchctlPrintDisabled(_)
+                | Call::PluginkitMatch(_)
+                | Call::PsCpuTimes(_)
+                | Call::NettopBytes
+        )
+    }
+
+    fn method(&self) -> &'static str {
+        match self {
+            Call::ListProcesses => "list_processes",
+            Call::ProcessInfo(_) => "process_info",
+            Call::WaitForExits(_) => "wait_for_exits",
+            Call::CodeSigningTeamId(_) => "code_signing_team_id",
+            Call::QuitDropbox => "quit_dropbox",
+            Call::LaunchDropbox => "launch_dropbox",
+            Call::Signal(_, _) => "signal",
+            Call::UserId => "user_id",
+            Call::LaunchctlBootout(_) => "launchctl_bootout",
+            Call::LaunchctlBootstrap(_, _) => "launchctl_bootstrap",
+            Call::LaunchctlPrint(_) => "launchctl_print",
+            Call::LaunchctlList => "launchctl_list",
+            Call::LaunchctlDisable(_) => "launchctl_disable",
+            Call::LaunchctlEnable(_) => "launchctl_enable",
+            Call::LaunchctlPrintDisabled(_) => "launchctl_print_disabled",
+            Call::SetImmutable(_, _) => "set_immutable",
+            Call::PluginkitMatch(_) => "pluginkit_match",
+            Call::PluginkitElect(_, _) => "pluginkit_elect",
+            Call::PluginkitElectUuid(_, _) => "pluginkit_elect_uuid",
+            Call::PluginkitAdd(_) => "pluginkit_add",
+            Call::PluginkitRemove(_) => "pluginkit_remove",
+            Call::RestartFinder => "restart_finder",
+            Call::PsCpuTimes(_) => "ps_cpu_times",
+            Call::NettopBytes => "nettop_bytes",
+        }
+    }
+}
+
+const FAKE_UID: u32 = 501;
+
+/// Start time of the first fake process. Every later one starts a second after the
+/// previous.
+const FAKE_BOOT_TIME: Duration = Duration::from_secs(1_700_000_000);
+
+/// A registration in the fake pluginkit database.
+#[derive(Debug, Clone)]
+struct FakeRegistration {
+    bundle_id: String,
+    path: PathBuf,
+    uuid: String,
+    enabled: bool,
+}
+
+/// A job loaded into the fake launchd.
+#[derive(Debug, Clone, Default)]
+struct FakeJob {
+    program: Option<String>,
+    pid: Option<u32>,
+    runs: u32,
+    last_exit_status: Option<i32>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    /// Running processes as `(pid, command line)`.
+    processes: Vec<(u32, String)>,
+    /// When each running process started; a new start time is assigned whenever a
+    /// process appears.
+    start_times: HashMap<u32, SystemTime>,
+    started: u64,
+    /// Details reported for a PID instead of the ones derived from its command line,
+    /// e.g. those of a real process.
+    observed: HashMap<u32, ProcessInfo>,
+    /// Processes started by `launch_dropbox`.
+    launched_processes: Vec<(u32, String)>,
+    /// PIDs reused by a new process once the process list has been read.
+    recycled: Vec<(u32, String)>,
+    /// PIDs that ignore both quit requests and SIGTERM, but not SIGKILL.
+    unkillable: Vec<u32>,
+    /// Accumulated CPU time per PID, reported by `ps_cpu_times`.
+    cpu_times: HashMap<u32, Duration>,
+    /// Cumulative network bytes per PID, reported by `nettop_bytes`.
+    network_bytes: HashMap<u32, u64>,
+    /// launchd's override database: service target → disabled.
+    launchd_overrides: BTreeMap<String, bool>,
+    /// Jobs loaded into launchd, keyed by service target.
+    loaded_jobs: BTreeMap<String, FakeJob>,
+    /// Paths with the user-immutable flag set. The flag is not enforced.
+    immutable: HashSet<PathBuf>,
+    /// Registered extensions, in registration order.
+    extensions: Vec<FakeRegistration>,
+    /// Registrations ever made, so that UUIDs stay unique after removals.
+    registered: usize,
+    /// Scripted results, keyed by method name, consumed before the model is consulted.
+    scripted: HashMap<&'static str, VecDeque<Result<String, String>>>,
+}
+
+impl FakeState {
+    fn register(&mut self, bundle_id: &str, path: &Path, enabled: bool) {
+        self.registered += 1;
+        self.extensions.push(FakeRegistration {
+            bundle_id: bundle_id.to_string(),
+            path: path.to_path_buf(),
+            uuid: format!("00000000-0000-0000-0000-{:012X}", self.registered),
+            enabled,
+        });
+    }
+
+    fn info(&self, pid: u32, command: &str) -> ProcessInfo {
+        if let Some(info) = self.observed.get(&pid) {
+            return info.clone();
+        }
+        ProcessInfo {
+            pid,
+            ppid: 1,
+            uid: FAKE_UID,
+            executable: command.split_whitespace().next().map(PathBuf::from),
+            argv: command.split_whitespace().map(str::to_string).collect(),
+            start_time: self.start_times.get(&pid).copied(),
+        }
+    }
+
+    fn spawn(&mut self, pid: u32, command: String) {
+        self.started += 1;
+        self.start_times.insert(
+            pid,
+            UNIX_EPOCH + FAKE_BOOT_TIME + Duration::from_secs(self.started),
+        );
+        self.processes.push((pid, command));
+    }
+}
+
+/// An in-memory model of a Mac running Dropbox.
+///
+/// Calls mutate the model the way the real tools would (quitting Dropbox stops the
+/// non-FileProvider processes inside Dropbox.app, a signal removes a PID, `pluginkit -e`
+/// flips an election, `pluginkit -a` registers a plugin, `launchctl bootstrap` loads a
+/// job) and are recorded for inspection. Individual calls can be
+/// overridden with scripted results via [`FakeBackend::script`].
+pub struct FakeBackend {
+    clock: FakeClock,
+    state: RefCell<FakeState>,
+    calls: RefCell<Vec<Call>>,
+    /// Changes scheduled with [`FakeBackend::at`] that have not happened yet.
+    events: RefCell<Vec<(Duration, FakeEvent)>>,
+}
+
+type FakeEvent = Box<dyn FnOnce(&FakeBackend)>;
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self {
+            clock: FakeClock::new(),
+            state: RefCell::new(FakeState::default()),
+            calls: RefCell::new(Vec::new()),
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// A running process of the current user. Its executable is the first word of
+    /// `command`.
+    pub fn with_process(self, pid: u32, command: &str) -> Self {
+        self.state.borrow_mut().spawn(pid, command.to_string());
+        self
+    }
+
+    /// A running process reported exactly as `info` describes it.
+    pub fn with_process_info(self, info: ProcessInfo) -> Self {
+        let mut state = self.state.borrow_mut();
+        state.spawn(info.pid, info.command_line());
+        state.observed.insert(info.pid, info);
+        drop(state);
+        self
+    }
+
+    /// A process that appears when `launch_dropbox` is called.
+    pub fn with_launched_process(self, pid: u32, command: &str) -> Self {
+        self.state
+            .borrow_mut()
+            .launched_processes
+            .push((pid, command.to_string()));
+        self
+    }
+
+    /// Simulate `pid` exiting and the PID being reused by a new process running
+    /// `command` right after the next process listing, i.e. between a caller reading
+    /// the list and acting on it.
+    pub fn recycle_pid_after_listing(&self, pid: u32, command: &str) {
+        self.state
+            .borrow_mut()
+            .recycled
+            .push((pid, command.to_string()));
+    }
+
+    pub fn with_unkillable(self, pid: u32) -> Self {
+        self.state.borrow_mut().unkillable.push(pid);
+        self
+    }
+
+    /// Set the accumulated CPU time of `pid`, e.g. to simulate a busy process.
+    pub fn set_cpu_time(&self, pid: u32, cpu_time: Duration) {
+        self.state.borrow_mut().cpu_times.insert(pid, cpu_time);
+    }
+
+    /// Set the cumulative network byte count of `pid`.
+    pub fn set_network_bytes(&self, pid: u32, bytes: u64) {
+        self.state.borrow_mut().network_bytes.insert(pid, bytes);
+    }
+
+    /// A job already loaded into launchd, e.g. `gui/501/com.dropbox.agent`, running as
+    /// `pid` if given.
+    pub fn with_loaded_job(self, service_target: &str, program: &str, pid: Option<u32>) -> Self {
+        self.state.borrow_mut().loaded_jobs.insert(
+            service_target.to_string(),
+            FakeJob {
+                program: Some(program.to_string()),
+                pid,
+                runs: u32::from(pid.is_some()),
+                last_exit_status: None,
+            },
+        );
+        self
+    }
+
+    pub fn is_job_loaded(&self, service_target: &str) -> bool {
+        self.state.borrow().loaded_jobs.contains_key(service_target)
+    }
+
+    /// An extension registered from `/Applications/Dropbox.app`.
+    pub fn with_extension(self, bundle_id: &str, enabled: bool) -> Self {
+        let name = bundle_id.rsplit('.').next().unwrap_or(bundle_id);
+        let path = PathBuf::from(format!(
+            "/Applications/Dropbox.app/Contents/PlugIns/{}.appex",
+            name
+        ));
+        self.with_registration(bundle_id, &path, enabled)
+    }
+
+    /// Another registration of `bundle_id`, e.g. from a stale copy of Dropbox.app.
+    pub fn with_registration(self, bundle_id: &str, path: &Path, enabled: bool) -> Self {
+        self.state.borrow_mut().register(bundle_id, path, enabled);
+        self
+    }
+
+    /// Whether pluginkit has a registration of the plugin at `path`.
+    pub fn is_registered(&self, path: &Path) -> bool {
+        self.state
+            .borrow()
+            .extensions
+            .iter()
+            .any(|r| r.path == path)
+    }
+
+    /// Queue a result for the next call to `method` (e.g. `"pluginkit_match"`). `Ok`
+    /// carries the output for calls that return text and is ignored otherwise.
+    pub fn script(&self, method: &'static str, result: Result<String, String>) {
+        self.state
+            .borrow_mut()
+            .scripted
+            .entry(method)
+            .or_default()
+            .push_back(result);
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.borrow().clone()
+    }
+
+    pub fn fake_clock(&self) -> &FakeClock {
+        &self.clock
+    }
+
+    pub fn running_pids(&self) -> Vec<u32> {
+        self.state
+            .borrow()
+            .processes
+            .iter()
+            .map(|(pid, _)| *pid)
+            .collect()
+    }
+
+    pub fn is_immutable(&self, path: &Path) -> bool {
+        self.state.borrow().immutable.contains(path)
+    }
+
+    pub fn extension_enabled(&self, bundle_id: &str) -> Option<bool> {
+        self.state
+            .borrow()
+            .extensions
+            .iter()
+            .find(|r| r.bundle_id == bundle_id)
+            .map(|r| r.enabled)
+    }
+
+    /// Whether the registration at `path` is elected for use.
+    pub fn registration_enabled(&self, path: &Path) -> Option<bool> {
+        self.state
+            .borrow()
+            .extensions
+            .iter()
+            .find(|r| r.path == path)
+            .map(|r| r.enabled)
+    }
+
+    /// Apply `event` to the model once the fake clock has advanced by `elapsed`, e.g. to
+    /// change what later polls see while a command waits. Events happen in time order,
+    /// before the first call made after they are due.
+    pub fn at(&self, elapsed: Duration, event: impl FnOnce(&FakeBackend) + 'static) {
+        self.events.borrow_mut().push((elapsed, Box::new(event)));
+    }
+
+    fn run_due_events(&self) {
+        let now = self.clock.elapsed();
+        let events = std::mem::take(&mut *self.events.borrow_mut());
+        let (mut due, later): (Vec<_>, Vec<_>) = events.into_iter().partition(|(at, _)| *at <= now);
+        *self.events.borrow_mut() = later;
+        due.sort_by_key(|(at, _)| *at);
+        for (_, event) in due {
+            event(self);
+        }
+    }
+
+    /// Record `call` and return its scripted result, if any.
+    fn record(&self, call: Call) -> Option<Result<String>> {
+        self.run_due_events();
+        let method = call.method();
+        self.calls.borrow_mut().push(call);
+        self.state
+            .borrow_mut()
+            .scripted
+            .get_mut(method)
+            .and_then(|queue| queue.pop_front())
+            .map(|result| result.map_err(|message| anyhow::anyhow!(message)))
+    }
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemBackend for FakeBackend {
+    fn clock(&self) -> &dyn Clock {
+        &self.clock
+    }
+
+    /// A scripted `Ok` is ignored.
+    fn list_processes(&self) -> Result<Vec<ProcessInfo>> {
+        if let Some(Err(e)) = self.record(Call::ListProcesses) {
+            return Err(e);
+        }
+        let mut state = self.state.borrow_mut();
+        let processes = state
+            .processes
+            .iter()
+            .map(|(pid, command)| state.info(*pid, command))
+            .collect();
+        for (pid, command) in std::mem::take(&mut state.recycled) {
+            state.processes.retain(|(p, _)| *p != pid);
+            state.observed.remove(&pid);
+            state.spawn(pid, command);
+        }
+        Ok(processes)
+    }
+
+    /// A scripted `Ok` is ignored.
+    fn process_info(&self, pid: u32) -> Result<Option<ProcessInfo>> {
+        if let Some(Err(e)) = self.record(Call::ProcessInfo(pid)) {
+            return Err(e);
+        }
+        let state = self.state.borrow();
+        Ok(state
+            .processes
+            .iter()
+            .find(|(p, _)| *p == pid)
+            .map(|(pid, command)| state.info(*pid, command)))
+    }
+
+    /// Processes only disappear in response to other calls, so anything not already
+    /// gone is still running once the fake clock has advanced by `timeout`. A scripted
+    /// `Ok` is ignored.
+    fn wait_for_exits(&self, pids: &[u32], timeout: Duration) -> Result<Vec<ExitEvent>> {
+        if let Some(Err(e)) = self.record(Call::WaitForExits(pids.to_vec())) {
+            return Err(e);
+        }
+        let running = self.running_pids();
+        let exited: Vec<ExitEvent> = pids
+            .iter()
+            .filter(|pid| !running.contains(pid))
+            .map(|pid| ExitEvent {
+                pid: *pid,
+                elapsed: Duration::ZERO,
+            })
+            .collect();
+        if exited.len() < pids.len() {
+            self.clock.sleep(timeout);
+        }
+        Ok(exited)
+    }
+
+    /// Dropbox's team ID for anything inside a `Dropbox.app`, `None` otherwise. A
+    /// scripted empty string means `None`.
+    fn code_signing_team_id(&self, path: &Path) -> Result<Option<String>> {
+        if let Some(result) = self.record(Call::CodeSigningTeamId(path.to_path_buf())) {
+            return result.map(|id| Some(id).filter(|id| !id.is_empty()));
+        }
+        Ok(path
+            .components()
+            .any(|c| c.as_os_str() == "Dropbox.app")
+            .then(|| DROPBOX_TEAM_ID.to_string()))
+    }
+
+    fn quit_dropbox(&self) -> Result<()> {
+        if let Some(result) = self.record(Call::QuitDropbox) {
+            return result.map(|_| ());
+        }
+        let mut state = self.state.borrow_mut();
+        let unkillable = state.unkillable.clone();
+        state.processes.retain(|(pid, command)| {
+            !command.contains("Dropbox.app")
+                || command.contains("DropboxFileProvider")
+                || unkillable.contains(pid)
+        });
+        Ok(())
+    }
+
+    fn launch_dropbox(&self) -> Result<()> {
+        if let Some(result) = self.record(Call::LaunchDropbox) {
+            return result.map(|_| ());
+        }
+        let mut state = self.state.borrow_mut();
+        for (pid, command) in state.launched_processes.clone() {
+            state.spawn(pid, command);
+        }
+        Ok(())
+    }
+
+    /// A scripted `Err("ESRCH")` or `Err("EPERM")` maps to the matching outcome, any
+    /// other error to [`SignalOutcome::Failed`].
+    fn signal(&self, pid: u32, signal: Signal) -> SignalOutcome {
+        if let Some(result) = self.record(Call::Signal(pid, signal)) {
+            return match result {
+                Ok(_) => SignalOutcome::Delivered,
+                Err(e) if e.to_string() == "ESRCH" => SignalOutcome::NoSuchProcess,
+                Err(e) if e.to_string() == "EPERM" => SignalOutcome::PermissionDenied,
+                Err(e) => SignalOutcome::Failed(e.to_string()),
+            };
+        }
+        let mut state = self.state.borrow_mut();
+        if !state.processes.iter().any(|(p, _)| *p == pid) {
+            return SignalOutcome::NoSuchProcess;
+        }
+        if signal == Signal::Kill || !state.unkillable.contains(&pid) {
+            state.processes.retain(|(p, _)| *p != pid);
+        }
+        SignalOutcome::Delivered
+    }
+
+    fn user_id(&self) -> Result<String> {
+        if let Some(result) = self.record(Call::UserId) {
+            return result;
+        }
+        Ok(FAKE_UID.to_string())
+    }
+
+    /// Fails the way launchctl does when the service is not loaded.
+    fn launchctl_bootout(&self, service_target: &str) -> Result<()> {
+        if let Some(result) = self.record(Call::LaunchctlBootout(service_target.to_string())) {
+            return result.map(|_| ());
+        }
+        if self
+            .state
+            .borrow_mut()
+            .loaded_jobs
+            .remove(service_target)
+            .is_none()
+        {
+            anyhow::bail!(
+                "`launchctl bootout {}` failed with exit status 3: Boot-out failed: 3: No such \
+                 process",
+                service_target
+            );
+        }
+        Ok(())
+    }
+
+    /// Loads the job named by the plist's `Label`. Fails the way launchctl does when
+    /// the plist cannot be read or the job is already loaded.
+    fn launchctl_bootstrap(&self, domain_target: &str, plist: &Path) -> Result<()> {
+        if let Some(result) = self.record(Call::LaunchctlBootstrap(
+            domain_target.to_string(),
+            plist.to_path_buf(),
+        )) {
+            return result.map(|_| ());
+        }
+        let failure = || {
+            anyhow::anyhow!(
+                "`launchctl bootstrap {} {}` failed with exit status 5: Bootstrap failed: 5: \
+                 Input/output error",
+                domain_target,
+                plist.display()
+            )
+        };
+        let value = plist::Value::from_file(plist).map_err(|_| failure())?;
+        let dict = value.as_dictionary().ok_or_else(failure)?;
+        let label = dict
+            .get("Label")
+            .and_then(|v| v.as_string())
+            .ok_or_else(failure)?;
+        let program = dict
+            .get("Program")
+            .and_then(|v| v.as_string())
+            .or_else(|| {
+                dict.get("ProgramArguments")
+                    .and_then(|v| v.as_array())
+                    .and_then(|a| a.first())
+                    .and_then(|v| v.as_string())
+            })
+            .map(str::to_string);
+
+        let service_target = format!("{}/{}", domain_target, label);
+        let mut state = self.state.borrow_mut();
+        if state.loaded_jobs.contains_key(&service_target) {
+            return Err(failure());
+        }
+        state.loaded_jobs.insert(
+            service_target,
+            FakeJob {
+                program,
+                ..FakeJob::default()
+            },
+        );
+        Ok(())
+    }
+
+    /// A scripted `Ok("")` means the service is not loaded.
+    fn launchctl_print(&self, service_target: &str) -> Result<Option<String>> {
+        if let Some(result) = self.record(Call::LaunchctlPrint(service_target.to_string())) {
+            return result.map(|output| Some(output).filter(|o| !o.is_empty()));
+        }
+        let state = self.state.borrow();
+        let Some(job) = state.loaded_jobs.get(service_target) else {
+            return Ok(None);
+        };
+        let mut output = format!("{} = {{\n\tactive count = 0\n", service_target);
+        let running = if job.pid.is_some() {
+            "running"
+        } else {
+            "not running"
+        };
+        output.push_str(&format!("\tstate = {}\n\n", running));
+        if let Some(program) = &job.program {
+            output.push_str(&format!(
+                "\tprogram = {}\n\targuments = {{\n\t\t{}\n\t}}\n\n",
+                program, program
+            ));
+        }
+        output.push_str(&format!("\truns = {}\n", job.runs));
+        if let Some(pid) = job.pid {
+            output.push_str(&format!("\tpid = {}\n", pid));
+        }
+        match job.last_exit_status {
+            Some(status) => output.push_str(&format!("\tlast exit code = {}\n", status)),
+            None => output.push_str("\tlast exit code = (never exited)\n"),
+        }
+        output.push_str("}\n");
+        Ok(Some(output))
+    }
+
+    fn launchctl_list(&self) -> Result<String> {
+        if let Some(result) = self.record(Call::LaunchctlList) {
+            return result;
+        }
+        let prefix = format!("gui/{}/", FAKE_UID);
+        let mut output = "PID\tStatus\tLabel\n".to_string();
+        for (service_target, job) in &self.state.borrow().loaded_jobs {
+            if let Some(label) = service_target.strip_prefix(&prefix) {
+                let pid = job
+                    .pid
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                let status = job.last_exit_status.unwrap_or(0);
+                output.push_str(&format!("{}\t{}\t{}\n", pid, status, label));
+            }
+        }
+        Ok(output)
+    }
+
+    fn launchctl_disable(&self, service_target: &str) -> Result<()> {
+        if let Some(result) = self.record(Call::LaunchctlDisable(service_target.to_string())) {
+            return result.map(|_| ());
+        }
+        self.state
+            .borrow_mut()
+            .launchd_overrides
+            .insert(service_target.to_string(), true);
+        Ok(())
+    }
+
+    fn launchctl_enable(&self, service_target: &str) -> Result<()> {
+        if let Some(result) = self.record(Call::LaunchctlEnable(service_target.to_string())) {
+            return result.map(|_| ());
+        }
+        self.state
+            .borrow_mut()
+            .launchd_overrides
+            .insert(service_target.to_string(), false);
+        Ok(())
+    }
+
+    fn launchctl_print_disabled(&self, domain_target: &str) -> Result<String> {
+        if let Some(result) = self.record(Call::LaunchctlPrintDisabled(domain_target.to_string())) {
+            return result;
+        }
+        let prefix = format!("{}/", domain_target);
+        let mut output = "disabled services = {\n".to_string();
+        for (service_target, disabled) in &self.state.borrow().launchd_overrides {
+            if let Some(label) = service_target.strip_prefix(&prefix) {
+                let value = if *disabled { "disabled" } else { "enabled" };
+                output.push_str(&format!("\t\"{}\" => {}\n", label, value));
+            }
+        }
+        output.push_str("}\n");
+        Ok(output)
+    }
+
+    fn set_immutable(&self, path: &Path, immutable: bool) -> Result<()> {
+        if let Some(result) = self.record(Call::SetImmutable(path.to_path_buf(), immutable)) {
+            return result.map(|_| ());
+        }
+        let mut state = self.state.borrow_mut();
+        if immutable {
+            state.immutable.insert(path.to_path_buf());
+        } else {
+            state.immutable.remove(path);
+        }
+        Ok(())
+    }
+
+    fn pluginkit_match(&self, bundle_id: &str) -> Result<String> {
+        if let Some(result) = self.record(Call::PluginkitMatch(bundle_id.to_string())) {
+            return result;
+        }
+        let mut output = String::new();
+        for registration in &self.state.borrow().extensions {
+            if registration.bundle_id.starts_with(bundle_id) {
+                output.push_str(&format!(
+                    "{}    {}(1.0)\t{}\t2024-01-01 00:00:00 +0000\t{}\n",
+                    if registration.enabled { '+' } else { '-' },
+                    registration.bundle_id,
+                    registration.uuid,
+                    registration.path.display()
+                ));
+            }
+        }
+        Ok(output)
+    }
+
+    fn pluginkit_elect(&self, bundle_id: &str, election: Election) -> Result<()> {
+        if let Some(result) = self.record(Call::PluginkitElect(bundle_id.to_string(), election)) {
+            return result.map(|_| ());
+        }
+        for registration in self.state.borrow_mut().extensions.iter_mut() {
+            if registration.bundle_id == bundle_id {
+                registration.enabled = election == Election::Use;
+            }
+        }
+        Ok(())
+    }
+
+    fn pluginkit_elect_uuid(&self, uuid: &str, election: Election) -> Result<()> {
+        if let Some(result) = self.record(Call::PluginkitElectUuid(uuid.to_string(), election)) {
+            return result.map(|_| ());
+        }
+        for registration in self.state.borrow_mut().extensions.iter_mut() {
+            if registration.uuid == uuid {
+                registration.enabled = election == Election::Use;
+            }
+        }
+        Ok(())
+    }
+
+    fn pluginkit_add(&self, path: &Path) -> Result<()> {
+        if let Some(result) = self.record(Call::PluginkitAdd(path.to_path_buf())) {
+            return result.map(|_| ());
+        }
+        let info_plist = path.join("Contents/Info.plist");
+        let bundle_id = plist::Value::from_file(&info_plist)
+            .ok()
+            .and_then(|v| {
+                v.as_dictionary()?
+                    .get("CFBundleIdentifier")?
+                    .as_string()
+                    .map(str::to_string)
+            })
+            .ok_or_else(|| anyhow::anyhow!("pluginkit failed"))?;
+        let mut state = self.state.borrow_mut();
+        if !state.extensions.iter().any(|r| r.path == path) {
+            // A newly registered plugin is used unless the user ignores it.
+            state.register(&bundle_id, path, true);
+        }
+        Ok(())
+    }
+
+    fn pluginkit_remove(&self, path: &Path) -> Result<()> {
+        if let Some(result) = self.record(Call::PluginkitRemove(path.to_path_buf())) {
+            return result.map(|_| ());
+        }
+        self.state
+            .borrow_mut()
+            .extensions
+            .retain(|r| r.path != path);
+        Ok(())
+    }
+
+    fn restart_finder(&self) -> Result<()> {
+        self.record(Call::RestartFinder)
+            .unwrap_or(Ok(String::new()))
+            .map(|_| ())
+    }
+
+    fn ps_cpu_times(&self, pids: &[u32]) -> Result<String> {
+        if let Some(result) = self.record(Call::PsCpuTimes(pids.to_vec())) {
+            return result;
+        }
+        let state = self.state.borrow();
+        Ok(state
+            .processes
+            .iter()
+            .filter(|(pid, _)| pids.contains(pid))
+            .map(|(pid, _)| {
+                let cpu = state.cpu_times.get(pid).copied().unwrap_or_default();
+                format!(
+                    "{:5} {}:{:02}.{:02}\n",
+                    pid,
+                    cpu.as_secs() / 60,
+                    cpu.as_secs() % 60,
+                    cpu.subsec_millis() / 10
+                )
+            })
+            .collect())
+    }
+
+    fn nettop_bytes(&self) -> Result<String> {
+        if let Some(result) = self.record(Call::NettopBytes) {
+            return result;
+        }
+        let state = self.state.borrow();
+        let mut output = ",bytes_in,bytes_out,\n".to_string();
+        for (pid, command) in &state.processes {
+            let name = command.rsplit('/').next().unwrap_or(command);
+            let bytes = state.network_bytes.get(pid).copied().unwrap_or(0);
+            output.push_str(&format!("{}.{},{},0,\n", name, pid, bytes));
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn scripted_results_are_used_once_each_in_order() {
+        let sys = FakeBackend::new();
+        sys.script("user_id", Ok("502".to_string()));
+        sys.script("user_id", Err("id failed".to_string()));
+
+        assert_eq!(sys.user_id().unwrap(), "502");
+        assert_eq!(sys.user_id().unwrap_err().to_string(), "id failed");
+        assert_eq!(sys.user_id().unwrap(), FAKE_UID.to_string());
+        assert_eq!(sys.calls(), [Call::UserId, Call::UserId, Call::UserId]);
+    }
+
+    #[test]
+    fn events_happen_in_time_order_before_the_first_call_once_due() {
+        let sys = FakeBackend::new().with_process(100, DROPBOX);
+        sys.at(Duration::from_secs(2), |sys| {
+            sys.signal(100, Signal::Kill);
+        });
+        sys.at(Duration::from_secs(1), |sys| {
+            sys.set_cpu_time(100, Duration::from_secs(7))
+        });
+
+        sys.clock().sleep(Duration::from_millis(1500));
+        assert_eq!(sys.running_pids(), [100]);
+        sys.list_processes().unwrap();
+        assert_eq!(sys.state.borrow().cpu_times[&100], Duration::from_secs(7));
+        assert_eq!(sys.running_pids(), [100]);
+
+        sys.clock().sleep(Duration::from_secs(1));
+        assert!(sys.list_processes().unwrap().is_empty());
+        assert_eq!(
+            sys.calls(),
+            [
+                Call::ListProcesses,
+                Call::Signal(100, Signal::Kill),
+                Call::ListProcesses
+            ]
+        );
+    }
+
+    #[test]
+    fn quitting_leaves_the_file_provider_and_only_sigkill_stops_the_unkillable() {
+        let sys = running_dropbox().with_unkillable(101);
+
+        sys.quit_dropbox().unwrap();
+        assert_eq!(sys.running_pids(), [101]);
+
+        assert_eq!(sys.signal(101, Signal::Term), SignalOutcome::Delivered);
+        assert_eq!(sys.running_pids(), [101]);
+        assert_eq!(sys.signal(101, Signal::Kill), SignalOutcome::Delivered);
+        assert!(sys.running_pids().is_empty());
+        assert_eq!(sys.signal(101, Signal::Kill), SignalOutcome::NoSuchProcess);
+    }
+
+    #[test]
+    fn waiting_advances_the_clock_only_while_a_process_is_left() {
+        let sys = FakeBackend::new().with_process(100, DROPBOX);
+        let timeout = Duration::from_secs(10);
+
+        assert!(sys.wait_for_exits(&[100], timeout).unwrap().is_empty());
+        assert_eq!(sys.fake_clock().elapsed(), timeout);
+
+        sys.signal(100, Signal::Term);
+        let exits = sys.wait_for_exits(&[100], timeout).unwrap();
+        assert_eq!(exits.len(), 1);
+        assert_eq!(sys.fake_clock().elapsed(), timeout);
+    }
+}
@@ -0,0 +1,317 @@
+use super::{
+    exitwatch, native, Clock, Election, ExitEvent, ProcessInfo, Signal, SignalOutcome,
+    SystemBackend, SystemClock,
+};
+use anyhow::{Context, Result};
+use duct::cmd;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+
+/// `launchctl`'s exit status for a service that is not loaded.
+const LAUNCHCTL_SERVICE_NOT_FOUND: i32 = 113;
+
+/// Run `program` and return its standard output, or fail with its exit status and
+/// message.
+fn run_tool(program: &str, args: &[&str]) -> Result<String> {
+    let output = cmd(program, args)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(tool_failure(program, args, &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn launchctl(args: &[&str]) -> Result<String> {
+    run_tool("launchctl", args)
+}
+
+fn tool_failure(program: &str, args: &[&str], output: &Output) -> anyhow::Error {
+    let status = output
+        .status
+        .code()
+        .map(|code| code.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let message = String::from_utf8_lossy(&output.stderr);
+    let message = match message.trim() {
+        "" => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        message => message.to_string(),
+    };
+    anyhow::anyhow!(
+        "`{} {}` failed with exit status {}: {}",
+        program,
+        args.join(" "),
+        status,
+        message
+    )
+}
+
+/// The real backend, shelling out to the macOS command line tools.
+pub struct MacOsBackend {
+    clock: SystemClock,
+    /// `codesign` is slow and process lists are polled, so team IDs are looked up once
+    /// per executable.
+    team_ids: RefCell<HashMap<PathBuf, Option<String>>>,
+}
+
+impl MacOsBackend {
+    pub fn new() -> Self {
+        Self {
+            clock: SystemClock,
+            team_ids: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MacOsBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemBackend for MacOsBackend {
+    fn clock(&self) -> &dyn Clock {
+        &self.clock
+    }
+
+    fn list_processes(&self) -> Result<Vec<ProcessInfo>> {
+        native::list_processes()
+    }
+
+    fn process_info(&self, pid: u32) -> Result<Option<ProcessInfo>> {
+        Ok(native::process_info(pid))
+    }
+
+    fn wait_for_exits(&self, pids: &[u32], timeout: Duration) -> Result<Vec<ExitEvent>> {
+        exitwatch::wait_for_exits(pids, timeout)
+    }
+
+    fn code_signing_team_id(&self, path: &Path) -> Result<Option<String>> {
+        if let Some(team_id) = self.team_ids.borrow().get(path) {
+            return Ok(team_id.clone());
+        }
+
+        // codesign prints the details to stderr, and exits non-zero for unsigned code.
+        let output = cmd!("codesign", "-d", "--verbose=2", path)
+            .stdout_null()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .context("Failed to run codesign")?;
+        let team_id = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .find_map(|line| line.strip_prefix("TeamIdentifier="))
+            .map(str::trim)
+            .filter(|id| *id != "not set")
+            .map(str::to_string);
+
+        self.team_ids
+            .borrow_mut()
+            .insert(path.to_path_buf(), team_id.clone());
+        Ok(team_id)
+    }
+
+    fn quit_dropbox(&self) -> Result<()> {
+        let script = r#"tell application "Dropbox" to quit"#;
+        cmd!("osascript", "-e", script)
+            .stdout_null()
+            .stderr_null()
+            .unchecked()
+            .run()
+            .context("Failed to send quit request to Dropbox")?;
+        Ok(())
+    }
+
+    fn launch_dropbox(&self) -> Result<()> {
+        cmd!("open", "-a", "Dropbox")
+            .stdout_null()
+            .stderr_null()
+            .run()
+            .context("Failed to launch Dropbox")?;
+        Ok(())
+    }
+
+    fn signal(&self, pid: u32, signal: Signal) -> SignalOutcome {
+        // kill(2) treats 0 and negative PIDs as process groups.
+        let pid = match libc::pid_t::try_from(pid) {
+            Ok(pid) if pid > 0 => pid,
+            _ => return SignalOutcome::Failed(format!("invalid PID {}", pid)),
+        };
+        let signo = match signal {
+            Signal::Term => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        };
+
+        // SAFETY: kill(2) has no memory-safety preconditions.
+        if unsafe { libc::kill(pid, signo) } == 0 {
+            return SignalOutcome::Delivered;
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ESRCH) => SignalOutcome::NoSuchProcess,
+            Some(libc::EPERM) => SignalOutcome::PermissionDenied,
+            _ => SignalOutcome::Failed(error.to_string()),
+        }
+    }
+
+    fn user_id(&self) -> Result<String> {
+        // SAFETY: getuid has no preconditions and cannot fail.
+        Ok(unsafe { libc::getuid() }.to_string())
+    }
+
+    fn launchctl_bootout(&self, service_target: &str) -> Result<()> {
+        launchctl(&["bootout", service_target])?;
+        Ok(())
+    }
+
+    fn launchctl_bootstrap(&self, domain_target: &str, plist: &Path) -> Result<()> {
+        launchctl(&["bootstrap", domain_target, &plist.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn launchctl_print(&self, service_target: &str) -> Result<Option<String>> {
+        let output = cmd!("launchctl", "print", service_target)
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .context("Failed to run launchctl")?;
+        match output.status.code() {
+            Some(0) => Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned())),
+            Some(LAUNCHCTL_SERVICE_NOT_FOUND) => Ok(None),
+            _ => Err(tool_failure(
+                "launchctl",
+                &["print", service_target],
+                &output,
+            )),
+        }
+    }
+
+    fn launchctl_list(&self) -> Result<String> {
+        launchctl(&["list"])
+    }
+
+    fn launchctl_disable(&self, service_target: &str) -> Result<()> {
+        launchctl(&["disable", service_target])?;
+        Ok(())
+    }
+
+    fn launchctl_enable(&self, service_target: &str) -> Result<()> {
+        launchctl(&["enable", service_target])?;
+        Ok(())
+    }
+
+    fn launchctl_print_disabled(&self, domain_target: &str) -> Result<String> {
+        launchctl(&["print-disabled", domain_target])
+    }
+
+    fn set_immutable(&self, path: &Path, immutable: bool) -> Result<()> {
+        let flag = if immutable { "uchg" } else { "nouchg" };
+        run_tool("chflags", &[flag, &path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn pluginkit_match(&self, bundle_id: &str) -> Result<String> {
+        run_tool("pluginkit", &["-m", "-A", "-D", "-v", "-i", bundle_id])
+    }
+
+    fn pluginkit_elect(&self, bundle_id: &str, election: Election) -> Result<()> {
+        run_tool("pluginkit", &["-e", election.as_str(), "-i", bundle_id])?;
+        Ok(())
+    }
+
+    fn pluginkit_elect_uuid(&self, uuid: &str, election: Election) -> Result<()> {
+        run_tool("pluginkit", &["-e", election.as_str(), "-u", uuid])?;
+        Ok(())
+    }
+
+    fn pluginkit_add(&self, path: &Path) -> Result<()> {
+        run_tool("pluginkit", &["-a", &path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn pluginkit_remove(&self, path: &Path) -> Result<()> {
+        run_tool("pluginkit", &["-r", &path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn restart_finder(&self) -> Result<()> {
+        cmd!("killall", "Finder")
+            .stdout_null()
+            .stderr_null()
+            .unchecked() // Finder may not be running
+            .run()
+            .context("Failed to restart Finder")?;
+        Ok(())
+    }
+
+    fn ps_cpu_times(&self, pids: &[u32]) -> Result<String> {
+        if pids.is_empty() {
+            return Ok(String::new());
+        }
+        let pid_list = pids
+            .iter()
+            .map(|pid| pid.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let output = cmd!("ps", "-o", "pid=,time=", "-p", &pid_list)
+            .stdout_capture()
+            .stderr_null()
+            .unchecked() // exits 1 when some of the processes are gone
+            .run()
+            .context("Failed to run ps")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn nettop_bytes(&self) -> Result<String> {
+        let output = cmd!("nettop", "-P", "-L", "1", "-x", "-J", "bytes_in,bytes_out")
+            .stdout_capture()
+            .stderr_null()
+            .run()
+            .context("Failed to run nettop")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_output_is_returned_on_success() {
+        assert_eq!(run_tool("sh", &["-c", "echo done"]).unwrap(), "done\n");
+    }
+
+    #[test]
+    fn tool_failures_carry_the_exit_status_and_message() {
+        let error = run_tool(
+            "sh",
+            &[
+                "-c",
+                "echo ignored; echo 'Operation not permitted' >&2; exit 3",
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`sh -c echo ignored; echo 'Operation not permitted' >&2; exit 3` failed with exit \
+             status 3: Operation not permitted"
+        );
+
+        // Some tools report errors on standard output.
+        let error = run_tool("sh", &["-c", "echo 'Could not find service'; exit 113"]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("exit status 113: Could not find service"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Every interaction with the operating system that droponoff performs goes through
+//! [`SystemBackend`], so that the on/off logic can be driven by [`FakeBackend`] on
+//! machines that are not macOS.
+
+mod exitwatch;
+mod fake;
+mod macos;
+mod native;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+pub use fake::{Call, FakeBackend, FakeClock};
+pub use macos::MacOsBackend;
+
+/// Source of time for timeouts and polling loops.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A running process, as reported by [`SystemBackend::list_processes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub uid: u32,
+    /// `None` if it could not be determined, e.g. for another user's process.
+    pub executable: Option<PathBuf>,
+    /// Empty if the arguments could not be read.
+    pub argv: Vec<String>,
+    pub start_time: Option<SystemTime>,
+}
+
+impl ProcessInfo {
+    /// The arguments joined by spaces, falling back to the executable path.
+    pub fn command_line(&self) -> String {
+        if !self.argv.is_empty() {
+            self.argv.join(" ")
+        } else if let Some(executable) = &self.executable {
+            executable.display().to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// A process exit observed by [`SystemBackend::wait_for_exits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitEvent {
+    pub pid: u32,
+    /// Time from the start of the wait until the exit was noticed.
+    pub elapsed: Duration,
+}
+
+/// Election passed to `pluginkit -e`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Election {
+    Use,
+    Ignore,
+}
+
+impl Election {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Election::Use => "use",
+            Election::Ignore => "ignore",
+        }
+    }
+}
+
+/// Signal sent by [`SystemBackend::signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Signal {
+    #[default]
+    Term,
+    Kill,
+}
+
+impl Signal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Signal::Term => "TERM",
+            Signal::Kill => "KILL",
+        }
+    }
+}
+
+/// What happened when a signal was sent to a single process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignalOutcome {
+    Delivered,
+    /// `ESRCH`: the process had already exited.
+    NoSuchProcess,
+    /// `EPERM`: the process belongs to someone else.
+    PermissionDenied,
+    Failed(String),
+    /// Not sent, because the PID no longer identifies the process it was meant for.
+    Skipped(String),
+}
+
+impl SignalOutcome {
+    pub fn describe(&self) -> String {
+        match self {
+            SignalOutcome::Delivered => "delivered".to_string(),
+            SignalOutcome::NoSuchProcess => "already exited (ESRCH)".to_string(),
+            SignalOutcome::PermissionDenied => "permission denied (EPERM)".to_string(),
+            SignalOutcome::Failed(message) => format!("failed: {}", message),
+            SignalOutcome::Skipped(reason) => format!("not sent: {}", reason),
+        }
+    }
+}
+
+pub trait SystemBackend {
+    fn clock(&self) -> &dyn Clock;
+
+    /// Every running process, of all users.
+    fn list_processes(&self) -> Result<Vec<ProcessInfo>>;
+
+    /// The process currently running as `pid`, or `None` if there is none.
+    fn process_info(&self, pid: u32) -> Result<Option<ProcessInfo>>;
+
+    /// Block until every process in `pids` has exited or `timeout` has passed. Returns
+    /// the exits in the order they happened; PIDs that are missing are still running.
+    fn wait_for_exits(&self, pids: &[u32], timeout: Duration) -> Result<Vec<ExitEvent>>;
+
+    /// The `TeamIdentifier` `codesign` reports for the code at `path`, or `None` if it
+    /// is unsigned or ad-hoc signed.
+    fn code_signing_team_id(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Ask Dropbox to quit via AppleScript.
+    fn quit_dropbox(&self) -> Result<()>;
+
+    fn launch_dropbox(&self) -> Result<()>;
+
+    /// Send `signal` to `pid` with the `kill(2)` system call.
+    fn signal(&self, pid: u32, signal: Signal) -> SignalOutcome;
+
+    fn user_id(&self) -> Result<String>;
+
+    /// `launchctl bootout <service_target>`. Fails with launchctl's message if the
+    /// service was not removed, including when it was not loaded.
+    fn launchctl_bootout(&self, service_target: &str) -> Result<()>;
+
+    /// `launchctl bootstrap <domain_target> <plist>`. Fails with launchctl's message if
+    /// the plist was not loaded, including when it already was.
+    fn launchctl_bootstrap(&self, domain_target: &str, plist: &Path) -> Result<()>;
+
+    /// Output of `launchctl print <service_target>`, or `None` if launchd has no such
+    /// service loaded.
+    fn launchctl_print(&self, service_target: &str) -> Result<Option<String>>;
+
+    /// Output of `launchctl list`: the PID, last exit status and label of every job in
+    /// the caller's domain.
+    fn launchctl_list(&self) -> Result<String>;
+
+    /// `launchctl disable <service_target>`: record the service as disabled in launchd's
+    /// persistent override database.
+    fn launchctl_disable(&self, service_target: &str) -> Result<()>;
+
+    fn launchctl_enable(&self, service_target: &str) -> Result<()>;
+
+    /// Output of `launchctl print-disabled <domain_target>`.
+    fn launchctl_print_disabled(&self, domain_target: &str) -> Result<String>;
+
+    /// Set or clear the user-immutable flag (`chflags uchg`/`nouchg`) on `path`.
+    fn set_immutable(&self, path: &Path, immutable: bool) -> Result<()>;
+
+    /// Output of `pluginkit -m -A -D -v -i <bundle_id>`: every registration of the
+    /// bundle ID, including other versions and duplicates, with UUIDs and paths.
+    fn pluginkit_match(&self, bundle_id: &str) -> Result<String>;
+
+    /// `pluginkit -e <election> -i <bundle_id>`, which elects every registration of
+    /// the bundle ID.
+    fn pluginkit_elect(&self, bundle_id: &str, election: Election) -> Result<()>;
+
+    /// `pluginkit -e <election> -u <uuid>`, which elects a single registration.
+    fn pluginkit_elect_uuid(&self, uuid: &str, election: Election) -> Result<()>;
+
+    /// `pluginkit -a <path>`: register the plugin bundle at `path`.
+    fn pluginkit_add(&self, path: &Path) -> Result<()>;
+
+    /// `pluginkit -r <path>`: remove the registration of the plugin at `path`.
+    fn pluginkit_remove(&self, path: &Path) -> Result<()>;
+
+    fn restart_finder(&self) -> Result<()>;
+
+    /// Output of `ps -o pid=,time= -p <pids>`: accumulated CPU time per process.
+    fn ps_cpu_times(&self, pids: &[u32]) -> Result<String>;
+
+    /// Output of `nettop -P -L 1 -x -J bytes_in,bytes_out`: cumulative network byte
+    /// counters per process as CSV, with rows named `<process>.<pid>`.
+    fn nettop_bytes(&self) -> Result<String>;
+}
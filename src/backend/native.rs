@@ -0,0 +1,409 @@
+//! In-process enumeration of running processes: libproc and sysctl on macOS, `/proc` on
+//! Linux. Processes that exit while being read, or whose details we are not allowed to
+//! read, are skipped or reported with the fields that could be read.
+
+use super::ProcessInfo;
+use anyhow::Result;
+
+#[cfg(target_os = "macos")]
+pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+    macos::list_processes()
+}
+
+#[cfg(target_os = "linux")]
+pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+    linux::list_processes()
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+    anyhow::bail!("Process enumeration is not supported on this platform")
+}
+
+#[cfg(target_os = "macos")]
+pub fn process_info(pid: u32) -> Option<ProcessInfo> {
+    macos::process_info(libc::c_int::try_from(pid).ok()?)
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_info(pid: u32) -> Option<ProcessInfo> {
+    linux::process_info(pid)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn process_info(_pid: u32) -> Option<ProcessInfo> {
+    None
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use super::ProcessInfo;
+    use anyhow::Result;
+    use std::ffi::OsStr;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::ptr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+        // Processes that exited since the PIDs were listed are skipped.
+        Ok(all_pids()?.into_iter().filter_map(process_info).collect())
+    }
+
+    pub fn process_info(pid: libc::c_int) -> Option<ProcessInfo> {
+        let info = bsd_info(pid)?;
+        Some(ProcessInfo {
+            pid: info.pbi_pid,
+            ppid: info.pbi_ppid,
+            uid: info.pbi_uid,
+            executable: executable(pid),
+            argv: argv(pid),
+            start_time: Some(
+                UNIX_EPOCH
+                    + Duration::from_secs(info.pbi_start_tvsec)
+                    + Duration::from_micros(info.pbi_start_tvusec),
+            ),
+        })
+    }
+
+    fn all_pids() -> Result<Vec<libc::c_int>> {
+        // SAFETY: a null buffer asks for the number of processes.
+        let count = unsafe { libc::proc_listallpids(ptr::null_mut(), 0) };
+        if count <= 0 {
+            anyhow::bail!(
+                "proc_listallpids failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        // Leave room for processes started in between the two calls.
+        let mut pids: Vec<libc::c_int> = vec![0; count as usize + 64];
+        let size = (pids.len() * mem::size_of::<libc::c_int>()) as libc::c_int;
+        // SAFETY: the buffer is valid for writes of `size` bytes.
+        let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut libc::c_void, size) };
+        if count <= 0 {
+            anyhow::bail!(
+                "proc_listallpids failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        pids.truncate(count as usize);
+        pids.retain(|pid| *pid > 0);
+        Ok(pids)
+    }
+
+    fn bsd_info(pid: libc::c_int) -> Option<libc::proc_bsdinfo> {
+        // SAFETY: proc_bsdinfo is plain old data, so all zeroes is a valid value.
+        let mut info: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+        // SAFETY: `info` is valid for writes of `size` bytes.
+        let written = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                libc::PROC_PIDTBSDINFO,
+                0,
+                &mut info as *mut _ as *mut libc::c_void,
+                size,
+            )
+        };
+        (written == size).then_some(info)
+    }
+
+    fn executable(pid: libc::c_int) -> Option<PathBuf> {
+        let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+        // SAFETY: the buffer is valid for writes of the length passed.
+        let len = unsafe {
+            libc::proc_pidpath(
+                pid,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len() as u32,
+            )
+        };
+        if len <= 0 {
+            return None;
+        }
+        buffer.truncate(len as usize);
+        Some(PathBuf::from(OsStr::from_bytes(&buffer)))
+    }
+
+    /// The arguments from `KERN_PROCARGS2`, which is laid out as `argc`, the executable
+    /// path, NUL padding, then `argc` NUL-terminated arguments and the environment.
+    /// Empty for processes of other users.
+    fn argv(pid: libc::c_int) -> Vec<String> {
+        let Some(buffer) = procargs(pid) else {
+            return Vec::new();
+        };
+        if buffer.len() < mem::size_of::<libc::c_int>() {
+            return Vec::new();
+        }
+
+        let (argc, rest) = buffer.split_at(mem::size_of::<libc::c_int>());
+        let argc = libc::c_int::from_ne_bytes(argc.try_into().unwrap()) as usize;
+        let Some(exec_path_end) = rest.iter().position(|b| *b == 0) else {
+            return Vec::new();
+        };
+        let Some(args_start) = rest[exec_path_end..].iter().position(|b| *b != 0) else {
+            return Vec::new();
+        };
+
+        rest[exec_path_end + args_start..]
+            .split(|b| *b == 0)
+            .take(argc)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    }
+
+    fn procargs(pid: libc::c_int) -> Option<Vec<u8>> {
+        let mut arg_max: libc::c_int = 0;
+        let mut size = mem::size_of::<libc::c_int>();
+        let mut mib = [libc::CTL_KERN, libc::KERN_ARGMAX];
+        // SAFETY: `arg_max` is valid for writes of `size` bytes.
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as libc::c_uint,
+                &mut arg_max as *mut _ as *mut libc::c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 || arg_max <= 0 {
+            return None;
+        }
+
+        let mut buffer = vec![0u8; arg_max as usize];
+        let mut size = buffer.len();
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROCARGS2, pid];
+        // SAFETY: the buffer is valid for writes of `size` bytes.
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as libc::c_uint,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        buffer.truncate(size);
+        Some(buffer)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::ProcessInfo;
+    use anyhow::{Context, Result};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn process_info(pid: u32) -> Option<ProcessInfo> {
+        read_process(
+            &Path::new("/proc").join(pid.to_string()),
+            pid,
+            boot_time(),
+            ticks_per_second(),
+        )
+    }
+
+    fn ticks_per_second() -> u64 {
+        // SAFETY: sysconf has no memory-safety preconditions.
+        unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64
+    }
+
+    pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+        let boot_time = boot_time();
+        let ticks_per_second = ticks_per_second();
+
+        let mut processes = Vec::new();
+        for entry in fs::read_dir("/proc")
+            .context("Failed to read /proc")?
+            .flatten()
+        {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            if let Some(process) = read_process(&entry.path(), pid, boot_time, ticks_per_second) {
+                processes.push(process);
+            }
+        }
+        Ok(processes)
+    }
+
+    /// `None` if the process exited while it was being read.
+    fn read_process(
+        dir: &Path,
+        pid: u32,
+        boot_time: Option<SystemTime>,
+        ticks_per_second: u64,
+    ) -> Option<ProcessInfo> {
+        // The command name in parentheses may itself contain spaces and parentheses.
+        let stat = fs::read_to_string(dir.join("stat")).ok()?;
+        let fields: Vec<&str> = stat
+            .get(stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .collect();
+        let ppid = fields.get(1)?.parse().ok()?;
+        let start_ticks: u64 = fields.get(19)?.parse().ok()?;
+
+        let status = fs::read_to_string(dir.join("status")).ok()?;
+        let uid = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()?;
+
+        let argv = fs::read(dir.join("cmdline"))
+            .map(|cmdline| {
+                cmdline
+                    .split(|b| *b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(ProcessInfo {
+            pid,
+            ppid,
+            uid,
+            executable: fs::read_link(dir.join("exe")).ok(),
+            argv,
+            start_time: boot_time.map(|boot| {
+                boot + Duration::from_secs_f64(start_ticks as f64 / ticks_per_second as f64)
+            }),
+        })
+    }
+
+    fn boot_time() -> Option<SystemTime> {
+        let stat = fs::read_to_string("/proc/stat").ok()?;
+        let seconds = stat
+            .lines()
+            .find_map(|line| line.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::discovery::Layout;
+    use crate::processes::{self, DropboxProcess};
+    use crate::testutil::DummyProcess;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    /// A dummy Dropbox running from `~/Applications/Dropbox.app` in a temporary home.
+    fn dummy_dropbox() -> (tempfile::TempDir, Layout, PathBuf, DummyProcess) {
+        let home = tempfile::tempdir().unwrap();
+        let home_path = home.path().canonicalize().unwrap();
+        let executable = home_path.join("Applications/Dropbox.app/Contents/MacOS/Dropbox");
+        let process = DummyProcess::spawn(&executable, 30);
+        (home, Layout::new("/", home_path), executable, process)
+    }
+
+    #[test]
+    fn enumerates_a_running_process() {
+        let started_after = SystemTime::now() - Duration::from_secs(2);
+        let (_home, _layout, executable, dummy) = dummy_dropbox();
+
+        let info = list_processes()
+            .unwrap()
+            .into_iter()
+            .find(|p| p.pid == dummy.pid())
+            .expect("the dummy process is listed");
+
+        assert_eq!(info.ppid, std::process::id());
+        // SAFETY: getuid has no preconditions.
+        assert_eq!(info.uid, unsafe { libc::getuid() });
+        assert_eq!(info.executable.as_ref(), Some(&executable));
+        assert_eq!(
+            info.argv,
+            vec![executable.display().to_string(), "30".to_string()]
+        );
+        let start_time = info.start_time.expect("start time");
+        assert!(start_time >= started_after, "{:?}", start_time);
+        assert!(start_time <= SystemTime::now() + Duration::from_secs(2));
+
+        assert_eq!(process_info(dummy.pid()), Some(info));
+    }
+
+    #[test]
+    fn exited_processes_have_no_info() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        assert_eq!(process_info(pid), None);
+    }
+
+    #[test]
+    fn classifies_a_running_process_inside_dropbox_app() {
+        let (_home, layout, executable, dummy) = dummy_dropbox();
+        let info = process_info(dummy.pid()).unwrap();
+        let sys = FakeBackend::new().with_process_info(info.clone());
+        // Once for the classification and once for the process lists.
+        for _ in 0..2 {
+            sys.script("user_id", Ok(info.uid.to_string()));
+        }
+
+        let classifications = processes::classify_processes(&sys, &layout).unwrap();
+
+        assert_eq!(classifications.len(), 1);
+        let classification = &classifications[0];
+        assert!(classification.is_dropbox, "{}", classification.reason);
+        assert_eq!(classification.process.pid, dummy.pid());
+        assert_eq!(
+            classification.process.executable.as_ref(),
+            Some(&executable)
+        );
+
+        let lists = processes::list_all_dropbox_processes(&sys, &layout).unwrap();
+        assert_eq!(lists.non_fileprovider.len(), 1);
+        assert_eq!(lists.non_fileprovider[0].ppid, std::process::id());
+        assert!(lists.fileprovider.is_empty());
+    }
+
+    #[test]
+    fn identity_check_notices_a_changed_start_time() {
+        let (_home, _layout, executable, dummy) = dummy_dropbox();
+        let info = process_info(dummy.pid()).unwrap();
+        let sys = FakeBackend::new().with_process_info(info.clone());
+        let remembered = DropboxProcess {
+            pid: info.pid,
+            ppid: info.ppid,
+            name: info.command_line(),
+            executable: Some(executable),
+            start_time: info.start_time,
+        };
+
+        assert_eq!(remembered.verify_identity(&sys), Ok(()));
+
+        let earlier = DropboxProcess {
+            start_time: info.start_time.map(|t| t - Duration::from_secs(60)),
+            ..remembered
+        };
+        let mismatch = earlier.verify_identity(&sys).unwrap_err();
+        assert!(
+            mismatch.starts_with("PID reused: started at"),
+            "{}",
+            mismatch
+        );
+    }
+}
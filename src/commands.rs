@@ -0,0 +1,1326 @@
+use crate::backend::{SignalOutcome, SystemBackend};
+use crate::config::{format_duration, Timings};
+use crate::discovery::{Layout, DROPBOX_TEAM_ID};
+use crate::extensions::ExtensionPolicy;
+use crate::idle::{self, IdleOptions};
+use crate::journal::{Action, Journal, LaunchAgentChanges};
+use crate::launchagent::{LaunchAgentStrategy, LaunchdJob};
+use crate::processes::TerminationPolicy;
+use crate::reconcile::{self, ComponentState, DesiredState, ProcessesState, Reconciliation};
+use crate::state::{self, Target};
+use crate::status::Status;
+use crate::transaction::Transaction;
+use crate::verify::{self, Expectation};
+use crate::{extensions, finder, launchagent, plan, processes, repair, status};
+use anyhow::Result;
+use std::path::Path;
+use tracing::{info, warn};
+
+pub struct OffOptions {
+    /// Undo completed steps in reverse order if a later one fails.
+    pub rollback: bool,
+    /// Only print what would be done.
+    pub dry_run: bool,
+    /// Wait for Dropbox to stop syncing before changing anything.
+    pub wait_for_idle: Option<IdleOptions>,
+    pub termination: TerminationPolicy,
+    pub launch_agent_strategy: LaunchAgentStrategy,
+    pub timings: Timings,
+    /// Print the verification report as JSON on standard output.
+    pub report_json: bool,
+}
+
+pub struct OnOptions {
+    /// Undo completed steps in reverse order if a later one fails.
+    pub rollback: bool,
+    /// Only print what would be done.
+    pub dry_run: bool,
+    pub timings: Timings,
+    /// Print the verification report as JSON on standard output.
+    pub report_json: bool,
+}
+
+pub struct SetOptions {
+    pub desired: DesiredState,
+    /// Undo completed steps in reverse order if a later one fails.
+    pub rollback: bool,
+    /// Only print what would be done.
+    pub dry_run: bool,
+    pub launch_agent_strategy: LaunchAgentStrategy,
+    pub timings: Timings,
+    /// Print the verification report as JSON on standard output.
+    pub report_json: bool,
+}
+
+pub fn cmd_off(sys: &dyn SystemBackend, layout: &Layout, options: &OffOptions) -> Result<()> {
+    if options.dry_run {
+        plan::plan_off(sys, layout, options)?.print();
+        return Ok(());
+    }
+
+    info!("Disabling Dropbox...\n");
+
+    if let Some(idle) = &options.wait_for_idle {
+        info!(
+            "→ Waiting for sync activity to stop (quiet for {}, up to {})...",
+            format_duration(idle.window),
+            format_duration(idle.timeout)
+        );
+        idle::wait_for_idle(sys, layout, idle)?;
+    }
+
+    // If a previous `off` was never undone its journal still describes the state to
+    // return to, so keep appending to it rather than starting over.
+    let (mut journal, is_new) = match Journal::load(layout)? {
+        Some(journal) => {
+            info!(
+                "  Continuing journal from a previous `off` at {}",
+                Journal::path(layout).display()
+            );
+            (journal, false)
+        }
+        None => (Journal::new(status::get_status(sys, layout)?), true),
+    };
+    journal.save(layout)?;
+
+    let mut tx = Transaction::journaled(sys, layout, &options.timings, &mut journal, is_new);
+    if let Err(e) = turn_off(sys, layout, &mut tx, options) {
+        return Err(tx.abort(e, options.rollback));
+    }
+
+    journal.after = Some(status::get_status(sys, layout)?);
+    journal.save(layout)?;
+
+    info!("");
+    info!("  Journal written to {}", Journal::path(layout).display());
+    info!("✓ Dropbox is now OFF");
+    Ok(())
+}
+
+fn turn_off(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    options: &OffOptions,
+) -> Result<()> {
+    let timings = &options.timings;
+    let force = options.termination == TerminationPolicy::Force;
+
+    if !force {
+        request_quit(sys, layout, tx)?;
+    }
+
+    let strategy = options.launch_agent_strategy;
+    info!(
+        "→ Disabling LaunchAgents and LaunchDaemons ({})...",
+        strategy.as_str()
+    );
+    let mut disabled_jobs = Vec::new();
+    let jobs = launchagent::discover_jobs(layout)?;
+    if jobs.is_empty() {
+        info!("  No Dropbox LaunchAgents or LaunchDaemons found");
+    }
+    for job in jobs {
+        if let Some(reason) = job.unchangeable_reason(sys, strategy)? {
+            warn!("  Skipping {}: {}", job.label, reason);
+            continue;
+        }
+        disable_job(sys, layout, tx, &job, strategy)?;
+        disabled_jobs.push(job);
+    }
+
+    info!("→ Disabling Dropbox extensions...");
+    let report = extensions::disable_all_extensions(sys, layout, timings)?;
+    for (bundle_id, path) in &report.changed {
+        tx.record(Action::DisableExtension {
+            bundle_id: bundle_id.clone(),
+            path: path.clone(),
+        })?;
+    }
+    report.result()?;
+
+    // Refresh finder in the hopes it will relinquish any active
+    // file provider dependencies.
+    info!("→ Restarting Finder...");
+    finder::restart_finder(sys)?;
+    tx.record(Action::RestartFinder)?;
+
+    stop(sys, layout, tx, options.termination, timings)?;
+
+    info!("→ Checking status...");
+    let mut expectations = vec![Expectation::ProcessesRunning(false)];
+    for job in disabled_jobs {
+        expectations.push(match strategy {
+            LaunchAgentStrategy::LaunchctlDisable => Expectation::LaunchdOverrideDisabled {
+                job: job.clone(),
+                disabled: true,
+            },
+            LaunchAgentStrategy::Rename | LaunchAgentStrategy::Immutable => {
+                Expectation::LaunchAgentState {
+                    job: job.clone(),
+                    state: status::LaunchAgentState::Disabled,
+                }
+            }
+        });
+        expectations.push(Expectation::JobLoaded { job, loaded: false });
+    }
+    for bundle_id in extensions::dropbox_bundle_ids(layout)? {
+        if extensions::policy(&bundle_id, Target::Off) == ExtensionPolicy::Required {
+            expectations.push(Expectation::ExtensionEnabled {
+                bundle_id,
+                path: None,
+                enabled: false,
+            });
+        }
+    }
+    verify::verify(
+        sys,
+        layout,
+        Target::Off.as_str(),
+        &expectations,
+        timings,
+        options.report_json,
+    )?;
+    Ok(())
+}
+
+/// Ask Dropbox to quit, without waiting for it to.
+fn request_quit(sys: &dyn SystemBackend, layout: &Layout, tx: &mut Transaction) -> Result<()> {
+    info!("→ Requesting Dropbox to quit...");
+    let was_running = !processes::list_dropbox_processes(sys, layout)?.is_empty();
+    if let Err(e) = processes::quit_dropbox_gracefully(sys) {
+        warn!("  Note: {}", e);
+    }
+    if was_running {
+        tx.record(Action::QuitDropbox)?;
+    }
+    Ok(())
+}
+
+/// Stop every Dropbox process once it has been asked to quit: wait for it to, then
+/// signal whatever is left as `termination` says.
+fn stop(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    termination: TerminationPolicy,
+    timings: &Timings,
+) -> Result<()> {
+    let force = termination == TerminationPolicy::Force;
+
+    if !force {
+        info!("→ Waiting for non-FileProvider processes to stop...");
+        // Only the FileProvider processes are expected to ignore the quit request.
+        // Signalling Dropbox itself mid-write is what --force is for.
+        processes::wait_for_non_fileprovider_processes_to_die(sys, layout, timings).map_err(
+            |e| {
+                anyhow::anyhow!(
+                    "{} Dropbox did not quit when asked; `off --force` kills it",
+                    e
+                )
+            },
+        )?;
+    }
+
+    // Note: We have found no other way to gracefully terminate FileProvider processes
+    // using command-line tools. SIGTERM should be reasonably safe unless the provider
+    // already has other bugs - it's at the very least as graceful as an actual normal
+    // crash or similar event. There used to be a fileproviderctl command to
+    // remove "domains" - but the command disappeared in Sonoma.
+    if force {
+        info!("→ Killing all Dropbox processes (--force)...");
+    } else {
+        info!("→ Terminating remaining Dropbox processes...");
+    }
+    for report in processes::terminate_processes(sys, layout, termination, timings)? {
+        if report.outcome == SignalOutcome::Delivered {
+            tx.record(Action::KillProcess {
+                pid: report.process.pid,
+                name: report.process.name,
+                signal: report.signal,
+            })?;
+        }
+    }
+
+    info!("→ Waiting for all processes to stop...");
+    processes::wait_for_processes_to_die(sys, layout, timings)
+}
+
+/// Keep one job from being loaded again: unload it and apply `strategy`.
+fn disable_job(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    job: &LaunchdJob,
+    strategy: LaunchAgentStrategy,
+) -> Result<()> {
+    info!("  {} ({})", job.label, job.plist.display());
+
+    resolve_launch_agent_conflict(layout, tx, job, Target::Off)?;
+    if launchagent::unload_launch_agent(sys, job)? {
+        tx.record(Action::UnloadLaunchAgent { job: job.clone() })?;
+    }
+    if strategy == LaunchAgentStrategy::LaunchctlDisable {
+        if launchagent::disable_service(sys, job)? {
+            tx.record(Action::DisableLaunchAgentService { job: job.clone() })?;
+        }
+    } else if launchagent::disable_launch_agent(job)? {
+        tx.record(Action::RenameLaunchAgent {
+            job: job.clone(),
+            from: job.plist.clone(),
+            to: job.disabled_path(),
+        })?;
+    }
+    if strategy == LaunchAgentStrategy::Immutable && launchagent::create_placeholder(sys, job)? {
+        tx.record(Action::CreateLaunchAgentPlaceholder { job: job.clone() })?;
+    }
+    Ok(())
+}
+
+/// Undo what `off` did to one job, as described by `changes`.
+fn restore_job(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    changes: &LaunchAgentChanges,
+) -> Result<()> {
+    let job = &changes.job;
+    info!("  {} ({})", job.label, job.plist.display());
+
+    if changes.created_placeholder && launchagent::remove_placeholder(sys, job)? {
+        tx.record(Action::RemoveLaunchAgentPlaceholder { job: job.clone() })?;
+    }
+    resolve_launch_agent_conflict(layout, tx, job, Target::On)?;
+    if changes.renamed && launchagent::enable_launch_agent(job)? {
+        tx.record(Action::RenameLaunchAgent {
+            job: job.clone(),
+            from: job.disabled_path(),
+            to: job.plist.clone(),
+        })?;
+    }
+    if changes.disabled_service && launchagent::enable_service(sys, job)? {
+        tx.record(Action::EnableLaunchAgentService { job: job.clone() })?;
+    }
+    if changes.unloaded && launchagent::load_launch_agent(sys, job)? {
+        tx.record(Action::LoadLaunchAgent { job: job.clone() })?;
+    }
+    Ok(())
+}
+
+/// Move one of a job's two conflicting plists out of the way, if they conflict.
+fn resolve_launch_agent_conflict(
+    layout: &Layout,
+    tx: &mut Transaction,
+    job: &LaunchdJob,
+    target: Target,
+) -> Result<()> {
+    if let Some(resolution) = launchagent::resolve_conflict(layout, job, target)? {
+        tx.record(Action::BackUpLaunchAgent {
+            job: job.clone(),
+            from: resolution.discard,
+            to: resolution.backup,
+        })?;
+    }
+    Ok(())
+}
+
+pub fn cmd_on(sys: &dyn SystemBackend, layout: &Layout, options: &OnOptions) -> Result<()> {
+    if options.dry_run {
+        plan::plan_on(sys, layout, options)?.print();
+        return Ok(());
+    }
+
+    info!("Enabling Dropbox...\n");
+
+    let journal = Journal::load(layout)?;
+    let mut tx = Transaction::new(sys, layout, &options.timings);
+    let result = match &journal {
+        Some(journal) => restore_from_journal(sys, layout, journal, &mut tx, options),
+        None => {
+            info!(
+                "  No journal found at {}, restoring every component",
+                Journal::path(layout).display()
+            );
+            restore_everything(sys, layout, &mut tx, options)
+        }
+    };
+    if let Err(e) = result {
+        return Err(tx.abort(e, options.rollback));
+    }
+
+    if journal.is_some() {
+        Journal::remove(layout)?;
+    }
+
+    info!("");
+    info!("✓ Dropbox is now ON");
+    Ok(())
+}
+
+fn launch(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    timings: &Timings,
+) -> Result<()> {
+    info!("→ Launching Dropbox...");
+    let was_running = !processes::list_dropbox_processes(sys, layout)?.is_empty();
+    processes::launch_dropbox(sys)?;
+    if !was_running {
+        tx.record(Action::LaunchDropbox)?;
+    }
+
+    info!("→ Waiting for Dropbox to start...");
+    processes::wait_for_dropbox_to_start(sys, layout, timings)
+}
+
+/// Undo exactly what the journaled `off` did.
+fn restore_from_journal(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    journal: &Journal,
+    tx: &mut Transaction,
+    options: &OnOptions,
+) -> Result<()> {
+    let timings = &options.timings;
+    info!("→ Comparing against journal...");
+    let current = status::get_status(sys, layout)?;
+    let drift = journal.drift(&current);
+    if drift.is_empty() {
+        info!("  Nothing changed since `off`");
+    }
+    for difference in &drift {
+        warn!("  Drift since `off`: {}", difference);
+    }
+
+    let changes = journal.launch_agent_changes();
+    if !changes.is_empty() {
+        info!("→ Restoring LaunchAgents and LaunchDaemons...");
+    }
+    for change in &changes {
+        restore_job(sys, layout, tx, change)?;
+    }
+    // The Dropbox updater may have recreated a plist `off` never touched.
+    for job in launchagent::discover_jobs(layout)? {
+        if !changes.iter().any(|c| c.job.plist == job.plist) {
+            resolve_launch_agent_conflict(layout, tx, &job, Target::On)?;
+        }
+    }
+
+    let disabled_exts = journal.disabled_extensions();
+    if !disabled_exts.is_empty() {
+        info!("→ Enabling Dropbox extensions...");
+        let report = extensions::enable_extensions(sys, layout, &disabled_exts, timings)?;
+        for (bundle_id, path) in &report.changed {
+            tx.record(Action::EnableExtension {
+                bundle_id: bundle_id.clone(),
+                path: path.clone(),
+            })?;
+        }
+        report.result()?;
+    }
+
+    if journal.stopped_dropbox() {
+        launch(sys, layout, tx, timings)?;
+    }
+
+    info!("→ Checking status...");
+    let before = &journal.before;
+    let mut expectations = Vec::new();
+    if !before.processes.is_empty() {
+        expectations.push(Expectation::ProcessesRunning(true));
+    }
+    for previous in &before.launch_agents {
+        let job = &previous.job;
+        // A conflict found by `off` has been resolved, leaving the job enabled.
+        let state = match previous.state {
+            status::LaunchAgentState::Conflict => status::LaunchAgentState::Enabled,
+            ref state => state.clone(),
+        };
+        expectations.push(Expectation::LaunchAgentState {
+            job: job.clone(),
+            state,
+        });
+        let change = changes.iter().find(|c| c.job.plist == job.plist);
+        if change.is_some_and(|c| c.disabled_service) {
+            expectations.push(Expectation::LaunchdOverrideDisabled {
+                job: job.clone(),
+                disabled: false,
+            });
+        }
+        if change.is_some_and(|c| c.unloaded) {
+            expectations.push(Expectation::JobLoaded {
+                job: job.clone(),
+                loaded: true,
+            });
+        }
+    }
+    for (bundle_id, path) in &disabled_exts {
+        if extensions::policy(bundle_id, Target::On) == ExtensionPolicy::Required {
+            expectations.push(Expectation::ExtensionEnabled {
+                bundle_id: bundle_id.to_string(),
+                path: path.map(Path::to_path_buf),
+                enabled: true,
+            });
+        }
+    }
+    verify::verify(
+        sys,
+        layout,
+        Target::On.as_str(),
+        &expectations,
+        timings,
+        options.report_json,
+    )?;
+    Ok(())
+}
+
+/// Turn every component on, for when there is no journal describing what `off` did.
+fn restore_everything(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    options: &OnOptions,
+) -> Result<()> {
+    let timings = &options.timings;
+    info!("→ Restoring LaunchAgents and LaunchDaemons...");
+    let mut restored_jobs = Vec::new();
+    let jobs = launchagent::discover_jobs(layout)?;
+    if jobs.is_empty() {
+        info!("  No Dropbox LaunchAgents or LaunchDaemons found");
+    }
+    for job in jobs {
+        // Restoring may take a rename as well as `launchctl enable`.
+        let reason = match job.unchangeable_reason(sys, LaunchAgentStrategy::Rename)? {
+            Some(reason) => Some(reason),
+            None => job.unchangeable_reason(sys, LaunchAgentStrategy::LaunchctlDisable)?,
+        };
+        if let Some(reason) = reason {
+            warn!("  Skipping {}: {}", job.label, reason);
+            continue;
+        }
+        restore_job(
+            sys,
+            layout,
+            tx,
+            &LaunchAgentChanges::everything(job.clone()),
+        )?;
+        restored_jobs.push(job);
+    }
+
+    info!("→ Enabling Dropbox extensions...");
+    let report = extensions::enable_all_extensions(sys, layout, timings)?;
+    for (bundle_id, path) in &report.changed {
+        tx.record(Action::EnableExtension {
+            bundle_id: bundle_id.clone(),
+            path: path.clone(),
+        })?;
+    }
+    report.result()?;
+
+    launch(sys, layout, tx, timings)?;
+
+    info!("→ Checking status...");
+    let mut expectations = vec![Expectation::ProcessesRunning(true)];
+    for job in restored_jobs {
+        expectations.push(Expectation::LaunchAgentState {
+            job: job.clone(),
+            state: status::LaunchAgentState::Enabled,
+        });
+        expectations.push(Expectation::LaunchdOverrideDisabled {
+            job: job.clone(),
+            disabled: false,
+        });
+        expectations.push(Expectation::JobLoaded { job, loaded: true });
+    }
+    for bundle_id in extensions::dropbox_bundle_ids(layout)? {
+        if extensions::policy(&bundle_id, Target::On) == ExtensionPolicy::Required {
+            expectations.push(Expectation::ExtensionEnabled {
+                bundle_id,
+                path: None,
+                enabled: true,
+            });
+        }
+    }
+    verify::verify(
+        sys,
+        layout,
+        Target::On.as_str(),
+        &expectations,
+        timings,
+        options.report_json,
+    )?;
+    Ok(())
+}
+
+pub fn cmd_set(sys: &dyn SystemBackend, layout: &Layout, options: &SetOptions) -> Result<()> {
+    if options.dry_run {
+        plan::plan_set(sys, layout, options)?.print();
+        return Ok(());
+    }
+
+    info!("Setting {}...\n", options.desired.describe());
+
+    info!("→ Comparing against the current state...");
+    let status = status::get_status(sys, layout)?;
+    let reconciliation = reconcile::reconcile(
+        sys,
+        layout,
+        &status,
+        &options.desired,
+        options.launch_agent_strategy,
+    )?;
+    for (component, reason) in &reconciliation.skipped {
+        warn!("  Skipping {}: {}", component, reason);
+    }
+    let pending = reconciliation.pending(&status);
+    if pending.is_empty() {
+        info!("");
+        info!("✓ Every component is already in the requested state");
+        return Ok(());
+    }
+    for goal in &pending {
+        let (actual, _) = goal.expectation.check(&status);
+        info!(
+            "  {}: {}, want {}",
+            goal.expectation.component(),
+            actual,
+            goal.expectation.expected()
+        );
+    }
+
+    let mut tx = Transaction::new(sys, layout, &options.timings);
+    if let Err(e) = set_components(sys, layout, &mut tx, &reconciliation, &status, options) {
+        return Err(tx.abort(e, options.rollback));
+    }
+
+    if Journal::load(layout)?.is_some() {
+        warn!(
+            "  A journal from `droponoff off` is still in place; `droponoff on` will undo \
+             what it recorded"
+        );
+    }
+
+    info!("");
+    info!("✓ Dropbox components set: {}", options.desired.describe());
+    Ok(())
+}
+
+/// Change only the components whose goals `status` does not meet, LaunchAgents first,
+/// then extensions, then processes, and verify every required goal.
+fn set_components(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    tx: &mut Transaction,
+    reconciliation: &Reconciliation,
+    status: &Status,
+    options: &SetOptions,
+) -> Result<()> {
+    let desired = &options.desired;
+    let timings = &options.timings;
+
+    let jobs = reconciliation.pending_jobs(status);
+    let targets: Vec<(&str, Option<&Path>)> = reconciliation
+        .pending_extensions(status)
+        .into_iter()
+        .map(|bundle_id| (bundle_id, None))
+        .collect();
+
+    match desired.agent {
+        Some(ComponentState::Disabled) if !jobs.is_empty() => {
+            let strategy = options.launch_agent_strategy;
+            info!(
+                "→ Disabling LaunchAgents and LaunchDaemons ({})...",
+                strategy.as_str()
+            );
+            for job in jobs {
+                disable_job(sys, layout, tx, job, strategy)?;
+            }
+        }
+        Some(ComponentState::Enabled) if !jobs.is_empty() => {
+            info!("→ Restoring LaunchAgents and LaunchDaemons...");
+            for job in jobs {
+                restore_job(
+                    sys,
+                    layout,
+                    tx,
+                    &LaunchAgentChanges::everything(job.clone()),
+                )?;
+            }
+        }
+        _ => {}
+    }
+
+    match desired.extensions {
+        Some(ComponentState::Disabled) if !targets.is_empty() => {
+            info!("→ Disabling Dropbox extensions...");
+            let report = extensions::disable_extensions(sys, layout, &targets, timings)?;
+            for (bundle_id, path) in &report.changed {
+                tx.record(Action::DisableExtension {
+                    bundle_id: bundle_id.clone(),
+                    path: path.clone(),
+                })?;
+            }
+            report.result()?;
+
+            if !report.changed.is_empty() {
+                info!("→ Restarting Finder...");
+                finder::restart_finder(sys)?;
+                tx.record(Action::RestartFinder)?;
+            }
+        }
+        Some(ComponentState::Enabled) if !targets.is_empty() => {
+            info!("→ Enabling Dropbox extensions...");
+            let report = extensions::enable_extensions(sys, layout, &targets, timings)?;
+            for (bundle_id, path) in &report.changed {
+                tx.record(Action::EnableExtension {
+                    bundle_id: bundle_id.clone(),
+                    path: path.clone(),
+                })?;
+            }
+            report.result()?;
+        }
+        _ => {}
+    }
+
+    // Loading a LaunchAgent may have started Dropbox, so look at the processes again.
+    if let Some(state) = desired.processes {
+        let running = !processes::list_dropbox_processes(sys, layout)?.is_empty();
+        match state {
+            ProcessesState::Stopped if running => {
+                request_quit(sys, layout, tx)?;
+                stop(sys, layout, tx, TerminationPolicy::Escalate, timings)?;
+            }
+            ProcessesState::Running if !running => launch(sys, layout, tx, timings)?,
+            _ => {}
+        }
+    }
+
+    info!("→ Checking status...");
+    verify::verify(
+        sys,
+        layout,
+        "set",
+        &reconciliation.expectations(),
+        timings,
+        options.report_json,
+    )?;
+    Ok(())
+}
+
+/// Returns the process exit code: always 0 without `check`, otherwise one of the
+/// `state::EXIT_*` codes.
+pub fn cmd_status(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    format: status::StatusFormat,
+    check: Option<Target>,
+) -> Result<u8> {
+    let status = status::get_status(sys, layout)?;
+    status::print_status_as(&status, format);
+
+    Ok(match check {
+        Some(target) => state::classify(&status).check_exit_code(target),
+        None => state::EXIT_MATCH,
+    })
+}
+
+/// Show every process that mentions Dropbox and whether droponoff
+/// treats it as part of Dropbox, and why.
+pub fn cmd_explain(sys: &dyn SystemBackend, layout: &Layout) -> Result<()> {
+    match layout.find_dropbox_app() {
+        Ok(path) => info!("Dropbox.app: {}", layout.unresolve(path).display()),
+        Err(_) => info!("Dropbox.app: NOT FOUND"),
+    }
+    info!("Expected code-signing team: {}", DROPBOX_TEAM_ID);
+    info!("");
+
+    let classifications = processes::classify_processes(sys, layout)?;
+    if classifications.is_empty() {
+        info!("No processes of the current user mention Dropbox");
+    }
+    for c in &classifications {
+        info!("PID {}: {}", c.process.pid, c.process.command_line());
+        match &c.process.executable {
+            Some(path) => info!("  executable: {}", path.display()),
+            None => info!("  executable: unknown"),
+        }
+        if let Some(team_id) = &c.team_id {
+            info!("  team ID:    {}", team_id);
+        }
+        if c.is_dropbox {
+            info!("  Dropbox process: {}", c.reason);
+        } else {
+            info!("  Ignored: {}", c.reason);
+        }
+    }
+    Ok(())
+}
+
+/// With `dry_run`, list every file that would be deleted without deleting anything.
+pub fn cmd_nuke_scratch(sys: &dyn SystemBackend, layout: &Layout, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("Dry run: droponoff nuke-scratch (nothing will be changed)\n");
+    } else {
+        info!("Deleting scratch_files contents...\n");
+    }
+
+    info!("→ Checking Dropbox status...");
+    let status = status::get_status(sys, layout)?;
+    if !status.processes.is_empty() {
+        if !dry_run {
+            anyhow::bail!(
+                "Dropbox appears to be running. Run `droponoff status` to check and `droponoff off` to turn it off."
+            );
+        }
+        warn!("  Dropbox appears to be running; nuke-scratch would refuse to continue");
+    }
+
+    info!("→ Cleaning scratch_files directories...");
+    status::clean_scratch_files(layout, dry_run)?;
+
+    if !dry_run {
+        info!("");
+        info!("✓ scratch_files contents deleted");
+    }
+    Ok(())
+}
+
+pub fn cmd_repair(sys: &dyn SystemBackend, layout: &Layout, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("Dry run: droponoff repair (nothing will be changed)\n");
+    } else {
+        info!("Repairing extension registrations...\n");
+    }
+
+    info!("→ Reading registrations...");
+    let before = repair::registrations(sys, layout)?;
+    info!("  {} registration(s) of Dropbox extensions", before.len());
+
+    let steps = repair::plan_repair(layout, &before)?;
+    if steps.is_empty() {
+        info!("");
+        info!("✓ Nothing to repair");
+        return Ok(());
+    }
+
+    if dry_run {
+        info!("→ Would:");
+        for step in &steps {
+            info!("  {}", step.describe());
+            info!("     $ {}", step.command());
+        }
+        return Ok(());
+    }
+
+    info!("→ Updating pluginkit...");
+    let mut failures = Vec::new();
+    for step in &steps {
+        match step.apply(sys) {
+            Ok(()) => info!("  Did {}", step.describe()),
+            Err(e) => {
+                warn!("  Could not {}: {:#}", step.describe(), e);
+                failures.push(format!("`{}`: {:#}", step.command(), e));
+            }
+        }
+    }
+
+    info!("→ Registration changes:");
+    let after = repair::registrations(sys, layout)?;
+    let diff = repair::diff_registrations(&before, &after);
+    if diff.is_empty() {
+        info!("  (none)");
+    }
+    for line in &diff {
+        info!("  {}", line);
+    }
+
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} repair step(s) failed:\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        );
+    }
+
+    if Journal::load(layout)?.is_some() {
+        warn!(
+            "  Dropbox was turned off by droponoff; newly registered extensions may be \
+             enabled. Run `droponoff off` again to disable them"
+        );
+    }
+
+    info!("");
+    info!("✓ Extension registrations repaired");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Call, Election, FakeBackend, Signal};
+    use crate::testutil::*;
+    use crate::verify::VerificationReport;
+    use std::time::Duration;
+
+    fn disabled_agent_plist(mac: &TestMac) -> std::path::PathBuf {
+        mac.agent_plist().with_extension("plist.disabled")
+    }
+
+    fn journaled_actions(mac: &TestMac) -> Vec<Action> {
+        Journal::load(&mac.layout)
+            .unwrap()
+            .expect("journal written")
+            .actions
+    }
+
+    #[test]
+    fn off_stops_dropbox_and_journals_every_change() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+
+        cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+
+        assert!(sys.running_pids().is_empty());
+        assert!(!mac.agent_plist().exists());
+        assert!(disabled_agent_plist(&mac).exists());
+        assert!(!sys.is_job_loaded(&agent_service_target()));
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(false));
+        assert_eq!(sys.extension_enabled(GARCON_EXTENSION), Some(false));
+
+        let calls = sys.calls();
+        for expected in [
+            Call::QuitDropbox,
+            Call::LaunchctlBootout(agent_service_target()),
+            Call::PluginkitElect(FILEPROVIDER_EXTENSION.to_string(), Election::Ignore),
+            Call::PluginkitElect(GARCON_EXTENSION.to_string(), Election::Ignore),
+            Call::RestartFinder,
+            Call::Signal(101, Signal::Term),
+        ] {
+            assert!(calls.contains(&expected), "missing {:?}", expected);
+        }
+        // The main process quit when asked, so only the FileProvider is signalled.
+        assert!(!calls.iter().any(|c| matches!(c, Call::Signal(100, _))));
+
+        let journal = Journal::load(&mac.layout).unwrap().unwrap();
+        assert_eq!(journal.before.processes.len(), 2);
+        assert!(journal.after.unwrap().processes.is_empty());
+        let job = journal.before.launch_agents[0].job.clone();
+        assert_eq!(
+            journal.actions,
+            vec![
+                Action::QuitDropbox,
+                Action::UnloadLaunchAgent { job: job.clone() },
+                Action::RenameLaunchAgent {
+                    job: job.clone(),
+                    from: mac.agent_plist(),
+                    to: disabled_agent_plist(&mac),
+                },
+                Action::DisableExtension {
+                    bundle_id: FILEPROVIDER_EXTENSION.to_string(),
+                    path: None,
+                },
+                Action::DisableExtension {
+                    bundle_id: GARCON_EXTENSION.to_string(),
+                    path: None,
+                },
+                Action::RestartFinder,
+                Action::KillProcess {
+                    pid: 101,
+                    name: FILEPROVIDER.to_string(),
+                    signal: Signal::Term,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn on_undoes_what_off_did_and_removes_the_journal() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+
+        cmd_on(&sys, &mac.layout, &on_options()).unwrap();
+
+        assert_eq!(sys.running_pids(), vec![100, 101]);
+        assert!(mac.agent_plist().exists());
+        assert!(!disabled_agent_plist(&mac).exists());
+        assert!(sys.is_job_loaded(&agent_service_target()));
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(true));
+        assert_eq!(sys.extension_enabled(GARCON_EXTENSION), Some(true));
+        assert!(sys.calls().contains(&Call::LaunchDropbox));
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    #[test]
+    fn on_leaves_an_extension_alone_that_was_disabled_before_off() {
+        let mac = TestMac::new().with_agent();
+        let sys = FakeBackend::new()
+            .with_process(100, DROPBOX)
+            .with_launched_process(100, DROPBOX)
+            .with_extension(FILEPROVIDER_EXTENSION, true)
+            .with_extension(GARCON_EXTENSION, false);
+        cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+        assert!(!journaled_actions(&mac).iter().any(|a| matches!(
+            a,
+            Action::DisableExtension { bundle_id, .. } if bundle_id == GARCON_EXTENSION
+        )));
+
+        cmd_on(&sys, &mac.layout, &on_options()).unwrap();
+
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(true));
+        assert_eq!(sys.extension_enabled(GARCON_EXTENSION), Some(false));
+        assert!(!sys.calls().contains(&Call::PluginkitElect(
+            GARCON_EXTENSION.to_string(),
+            Election::Use
+        )));
+    }
+
+    #[test]
+    fn off_rolls_back_when_an_extension_never_changes() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        // pluginkit accepts every election of the fileprovider but never reflects it.
+        for _ in 0..Timings::default().verify_attempts {
+            sys.script("pluginkit_elect", Ok(String::new()));
+        }
+
+        let error = cmd_off(&sys, &mac.layout, &off_options()).unwrap_err();
+
+        assert!(
+            format!("{:#}", error).contains(FILEPROVIDER_EXTENSION),
+            "{:#}",
+            error
+        );
+        assert!(mac.agent_plist().exists());
+        assert!(!disabled_agent_plist(&mac).exists());
+        assert!(sys.is_job_loaded(&agent_service_target()));
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(true));
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    #[test]
+    fn off_fails_when_dropbox_ignores_the_quit_request() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox().with_unkillable(100);
+
+        let error = cmd_off(&sys, &mac.layout, &off_options()).unwrap_err();
+
+        assert!(error.to_string().contains("quit-timeout"), "{}", error);
+        assert!(error.to_string().contains("--force"), "{}", error);
+        assert!(!sys
+            .calls()
+            .iter()
+            .any(|c| matches!(c, Call::Signal(100, _) | Call::Signal(101, _))));
+        assert!(sys.running_pids().contains(&100));
+        assert!(mac.agent_plist().exists());
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    #[test]
+    fn off_with_force_kills_dropbox_without_asking_it_to_quit() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox().with_unkillable(100);
+        let options = OffOptions {
+            termination: TerminationPolicy::Force,
+            ..off_options()
+        };
+
+        cmd_off(&sys, &mac.layout, &options).unwrap();
+
+        let calls = sys.calls();
+        assert!(!calls.contains(&Call::QuitDropbox));
+        assert!(calls.contains(&Call::Signal(100, Signal::Kill)));
+        assert!(calls.contains(&Call::Signal(101, Signal::Kill)));
+        assert!(sys.running_pids().is_empty());
+    }
+
+    #[test]
+    fn off_with_force_launches_dropbox_again_when_rolling_back() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        // Dropbox survives SIGKILL, so the final wait fails after both kills.
+        sys.script("signal", Ok(String::new()));
+        let options = OffOptions {
+            termination: TerminationPolicy::Force,
+            ..off_options()
+        };
+
+        let error = cmd_off(&sys, &mac.layout, &options).unwrap_err();
+
+        assert!(error.to_string().contains("stop-timeout"), "{}", error);
+        let calls = sys.calls();
+        assert!(!calls.contains(&Call::QuitDropbox));
+        let killed = calls
+            .iter()
+            .position(|c| *c == Call::Signal(101, Signal::Kill))
+            .expect("FileProvider killed");
+        assert!(calls[killed..].contains(&Call::LaunchDropbox));
+        assert!(sys.running_pids().contains(&101));
+        assert!(mac.agent_plist().exists());
+        assert!(sys.is_job_loaded(&agent_service_target()));
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    fn agent_service_disabled(sys: &FakeBackend) -> bool {
+        sys.launchctl_print_disabled("gui/501")
+            .unwrap()
+            .contains(&format!("\"{}\" => disabled", AGENT))
+    }
+
+    #[test]
+    fn off_with_launchctl_disable_leaves_the_plist_and_on_enables_the_service() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let options = OffOptions {
+            launch_agent_strategy: LaunchAgentStrategy::LaunchctlDisable,
+            ..off_options()
+        };
+
+        cmd_off(&sys, &mac.layout, &options).unwrap();
+
+        assert!(mac.agent_plist().exists());
+        assert!(!disabled_agent_plist(&mac).exists());
+        assert!(agent_service_disabled(&sys));
+        assert!(!sys.is_job_loaded(&agent_service_target()));
+        let actions = journaled_actions(&mac);
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, Action::DisableLaunchAgentService { .. })));
+        assert!(!actions
+            .iter()
+            .any(|a| matches!(a, Action::RenameLaunchAgent { .. })));
+
+        cmd_on(&sys, &mac.layout, &on_options()).unwrap();
+
+        assert!(sys
+            .calls()
+            .contains(&Call::LaunchctlEnable(agent_service_target())));
+        assert!(!agent_service_disabled(&sys));
+        assert!(sys.is_job_loaded(&agent_service_target()));
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    #[test]
+    fn off_with_immutable_locks_a_placeholder_and_on_restores_the_plist() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let options = OffOptions {
+            launch_agent_strategy: LaunchAgentStrategy::Immutable,
+            ..off_options()
+        };
+
+        cmd_off(&sys, &mac.layout, &options).unwrap();
+
+        let plist = mac.agent_plist();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        assert!(launchagent::has_placeholder(&job));
+        assert!(sys.is_immutable(&plist));
+        assert!(disabled_agent_plist(&mac).exists());
+        assert!(sys
+            .calls()
+            .contains(&Call::SetImmutable(plist.clone(), true)));
+        assert!(journaled_actions(&mac)
+            .iter()
+            .any(|a| matches!(a, Action::CreateLaunchAgentPlaceholder { .. })));
+
+        cmd_on(&sys, &mac.layout, &on_options()).unwrap();
+
+        assert!(!sys.is_immutable(&plist));
+        assert_eq!(
+            std::fs::read_to_string(&plist).unwrap(),
+            agent_plist_contents()
+        );
+        assert!(!disabled_agent_plist(&mac).exists());
+        assert!(sys.is_job_loaded(&agent_service_target()));
+    }
+
+    #[test]
+    fn off_with_immutable_removes_the_placeholder_when_rolling_back() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        for _ in 0..Timings::default().verify_attempts {
+            sys.script("pluginkit_elect", Ok(String::new()));
+        }
+        let options = OffOptions {
+            launch_agent_strategy: LaunchAgentStrategy::Immutable,
+            ..off_options()
+        };
+
+        cmd_off(&sys, &mac.layout, &options).unwrap_err();
+
+        assert!(sys
+            .calls()
+            .contains(&Call::SetImmutable(mac.agent_plist(), false)));
+        assert!(!sys.is_immutable(&mac.agent_plist()));
+        assert_eq!(
+            std::fs::read_to_string(mac.agent_plist()).unwrap(),
+            agent_plist_contents()
+        );
+        assert!(!disabled_agent_plist(&mac).exists());
+    }
+
+    #[test]
+    fn off_waiting_for_idle_changes_nothing_while_dropbox_syncs() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        for i in 1..=400u64 {
+            sys.at(Duration::from_secs(2 * i), move |sys| {
+                sys.set_cpu_time(100, Duration::from_secs(i))
+            });
+        }
+        let options = OffOptions {
+            wait_for_idle: Some(IdleOptions::default()),
+            ..off_options()
+        };
+
+        let error = cmd_off(&sys, &mac.layout, &options).unwrap_err();
+
+        assert!(error.to_string().contains("Last activity"), "{}", error);
+        assert!(!sys.calls().contains(&Call::QuitDropbox));
+        assert_eq!(sys.running_pids(), [100, 101]);
+        assert!(mac.agent_plist().exists());
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    fn mutating_calls(sys: &FakeBackend) -> Vec<Call> {
+        sys.calls().into_iter().filter(Call::mutates).collect()
+    }
+
+    #[test]
+    fn dry_run_off_changes_nothing() {
+        let mac = TestMac::new().with_agent();
+        for termination in [TerminationPolicy::Escalate, TerminationPolicy::Force] {
+            let sys = running_dropbox();
+            let options = OffOptions {
+                dry_run: true,
+                termination,
+                ..off_options()
+            };
+
+            cmd_off(&sys, &mac.layout, &options).unwrap();
+
+            assert_eq!(mutating_calls(&sys), []);
+            assert_eq!(sys.running_pids(), [100, 101]);
+            assert!(mac.agent_plist().exists());
+            assert!(Journal::load(&mac.layout).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn dry_run_on_and_set_change_nothing() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+        let journal = std::fs::read(Journal::path(&mac.layout)).unwrap();
+        let changes_before = mutating_calls(&sys);
+
+        let on = OnOptions {
+            dry_run: true,
+            ..on_options()
+        };
+        cmd_on(&sys, &mac.layout, &on).unwrap();
+        let set = SetOptions {
+            desired: DesiredState {
+                processes: Some(ProcessesState::Running),
+                agent: Some(ComponentState::Enabled),
+                extensions: Some(ComponentState::Enabled),
+            },
+            rollback: true,
+            dry_run: true,
+            launch_agent_strategy: LaunchAgentStrategy::Rename,
+            timings: Timings::default(),
+            report_json: false,
+        };
+        cmd_set(&sys, &mac.layout, &set).unwrap();
+
+        assert_eq!(mutating_calls(&sys), changes_before);
+        assert!(sys.running_pids().is_empty());
+        assert!(disabled_agent_plist(&mac).exists());
+        assert_eq!(std::fs::read(Journal::path(&mac.layout)).unwrap(), journal);
+    }
+
+    #[test]
+    fn dry_run_repair_changes_nothing() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+        std::fs::copy(disabled_agent_plist(&mac), mac.agent_plist()).unwrap();
+        let changes_before = mutating_calls(&sys);
+
+        cmd_repair(&sys, &mac.layout, true).unwrap();
+
+        assert_eq!(mutating_calls(&sys), changes_before);
+        assert!(mac.agent_plist().exists());
+        assert!(disabled_agent_plist(&mac).exists());
+    }
+
+    #[test]
+    fn nuke_scratch_refuses_while_dropbox_runs() {
+        let mac = TestMac::new().with_scratch_file("chunk", 10);
+        let sys = running_dropbox();
+
+        let error = cmd_nuke_scratch(&sys, &mac.layout, false).unwrap_err();
+
+        assert!(error.to_string().contains("running"), "{}", error);
+        assert!(mac.scratch_dir().join("chunk").exists());
+    }
+
+    #[test]
+    fn nuke_scratch_deletes_scratch_files_once_dropbox_is_off() {
+        let mac = TestMac::new()
+            .with_scratch_file("chunk-1", 10)
+            .with_scratch_file("chunk-2", 20);
+        let sys = FakeBackend::new();
+
+        cmd_nuke_scratch(&sys, &mac.layout, true).unwrap();
+        assert!(mac.scratch_dir().join("chunk-1").exists());
+
+        cmd_nuke_scratch(&sys, &mac.layout, false).unwrap();
+        assert!(mac.scratch_dir().exists());
+        assert_eq!(fs_entries(&mac.scratch_dir()), 0);
+    }
+
+    fn fs_entries(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn verification_retries_until_every_component_is_met() {
+        let mac = TestMac::new();
+        let sys = running_dropbox();
+        let timings = Timings::default();
+        let expectations = [
+            Expectation::ProcessesRunning(false),
+            Expectation::ExtensionEnabled {
+                bundle_id: FILEPROVIDER_EXTENSION.to_string(),
+                path: None,
+                enabled: true,
+            },
+        ];
+
+        let error =
+            verify::verify(&sys, &mac.layout, "off", &expectations, &timings, false).unwrap_err();
+
+        assert!(error.to_string().contains("processes"), "{}", error);
+        assert_eq!(
+            sys.fake_clock().elapsed(),
+            timings.verify_interval * (timings.verify_attempts - 1)
+        );
+        let path = VerificationReport::path(&mac.layout);
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(report["passed"], false);
+        assert_eq!(report["attempts"], timings.verify_attempts);
+        assert_eq!(report["components"][0]["met"], false);
+        assert_eq!(report["components"][1]["converged_after"], 1);
+
+        sys.signal(100, Signal::Kill);
+        sys.signal(101, Signal::Kill);
+        let report =
+            verify::verify(&sys, &mac.layout, "off", &expectations, &timings, false).unwrap();
+        assert!(report.passed);
+        assert_eq!(report.attempts, 1);
+        assert!(!path.exists());
+    }
+}
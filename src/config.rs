@@ -0,0 +1,471 @@
+//! Settings that can be given on the command line or in
+//! `~/Library/Application Support/droponoff/config.toml`. Command-line flags win over
+//! the config file, which wins over the built-in defaults.
+
+use crate::discovery::Layout;
+use crate::launchagent::LaunchAgentStrategy;
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+const MAX_VERIFY_ATTEMPTS: u32 = 100;
+
+/// How long `off` and `on` wait for things to happen, and how often they check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timings {
+    /// How long `off` waits for the processes other than DropboxFileProvider to exit
+    /// after asking Dropbox to quit.
+    pub quit_timeout: Duration,
+    /// How long `off` waits for every Dropbox process to exit after terminating the
+    /// DropboxFileProvider processes.
+    pub stop_timeout: Duration,
+    /// How long `off` waits after SIGTERM before sending SIGKILL.
+    pub term_grace: Duration,
+    /// How long `on` waits for Dropbox to start.
+    pub start_timeout: Duration,
+    /// How often the process list is checked while waiting for Dropbox to start.
+    pub poll_interval: Duration,
+    /// How many times the final state, and each extension's new election, is checked
+    /// before giving up.
+    pub verify_attempts: u32,
+    /// Delay between those checks.
+    pub verify_interval: Duration,
+    /// How long `off --wait-for-idle` waits for Dropbox to go quiet before aborting.
+    pub idle_timeout: Duration,
+    /// How long Dropbox's activity must stay quiet to count as idle.
+    pub idle_window: Duration,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            quit_timeout: Duration::from_secs(10),
+            stop_timeout: Duration::from_secs(10),
+            term_grace: Duration::from_secs(5),
+            start_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(100),
+            verify_attempts: 5,
+            verify_interval: Duration::from_millis(500),
+            idle_timeout: Duration::from_secs(600),
+            idle_window: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Overrides for [`Timings`]; unset fields keep their previous value. This is both the
+/// `[timing]` table of the config file and the set of command-line flags.
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimingOverrides {
+    /// How long to wait for Dropbox to quit, e.g. `30s` [default: 10s]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub quit_timeout: Option<Duration>,
+    /// How long to wait for all Dropbox processes to stop [default: 10s]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub stop_timeout: Option<Duration>,
+    /// How long to wait after SIGTERM before sending SIGKILL [default: 5s]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub term_grace: Option<Duration>,
+    /// How long to wait for Dropbox to start [default: 10s]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub start_timeout: Option<Duration>,
+    /// How often to check whether Dropbox has started [default: 100ms]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub poll_interval: Option<Duration>,
+    /// How many times to check the final state before failing [default: 5]
+    #[arg(long, value_name = "N")]
+    pub verify_attempts: Option<u32>,
+    /// Delay between those checks [default: 500ms]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub verify_interval: Option<Duration>,
+    /// Set on the command line with `off --wait-for-idle=DURATION`.
+    #[arg(skip)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Option<Duration>,
+    /// Set on the command line with `off --idle-window DURATION`.
+    #[arg(skip)]
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub idle_window: Option<Duration>,
+}
+
+/// Durations are written like `10s`, `1m 30s` or `250ms`.
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid duration {:?}: {}", s, e)))
+}
+
+impl Timings {
+    /// Apply `overrides`, naming `source` in the error if a resulting value is invalid.
+    pub fn apply(&mut self, overrides: &TimingOverrides, source: &str) -> Result<()> {
+        let mut timings = self.clone();
+        if let Some(v) = overrides.quit_timeout {
+            timings.quit_timeout = v;
+        }
+        if let Some(v) = overrides.stop_timeout {
+            timings.stop_timeout = v;
+        }
+        if let Some(v) = overrides.term_grace {
+            timings.term_grace = v;
+        }
+        if let Some(v) = overrides.start_timeout {
+            timings.start_timeout = v;
+        }
+        if let Some(v) = overrides.poll_interval {
+            timings.poll_interval = v;
+        }
+        if let Some(v) = overrides.verify_attempts {
+            timings.verify_attempts = v;
+        }
+        if let Some(v) = overrides.verify_interval {
+            timings.verify_interval = v;
+        }
+        if let Some(v) = overrides.idle_timeout {
+            timings.idle_timeout = v;
+        }
+        if let Some(v) = overrides.idle_window {
+            timings.idle_window = v;
+        }
+        if let Err(e) = timings.validate() {
+            anyhow::bail!("Invalid timing settings in {}: {}", source, e);
+        }
+        *self = timings;
+        Ok(())
+    }
+
+    /// How long verification keeps checking, naming the settings that control it, e.g.
+    /// `5 attempts (verify-attempts) 500ms apart (verify-interval)`.
+    pub fn describe_verify(&self) -> String {
+        format!(
+            "{} attempts (verify-attempts) {} apart (verify-interval)",
+            self.verify_attempts,
+            format_duration(self.verify_interval)
+        )
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, timeout) in [
+            ("quit-timeout", self.quit_timeout),
+            ("stop-timeout", self.stop_timeout),
+            ("start-timeout", self.start_timeout),
+            ("idle-timeout", self.idle_timeout),
+        ] {
+            if timeout.is_zero() || timeout > MAX_TIMEOUT {
+                anyhow::bail!(
+                    "{} must be between 1ms and {}, got {}",
+                    name,
+                    format_duration(MAX_TIMEOUT),
+                    format_duration(timeout)
+                );
+            }
+            if self.poll_interval > timeout {
+                anyhow::bail!(
+                    "poll-interval ({}) must not be longer than {} ({})",
+                    format_duration(self.poll_interval),
+                    name,
+                    format_duration(timeout)
+                );
+            }
+        }
+
+        if self.term_grace > MAX_TIMEOUT {
+            anyhow::bail!(
+                "term-grace must be at most {}, got {}",
+                format_duration(MAX_TIMEOUT),
+                format_duration(self.term_grace)
+            );
+        }
+
+        if self.poll_interval < MIN_POLL_INTERVAL {
+            anyhow::bail!(
+                "poll-interval must be at least {}, got {}",
+                format_duration(MIN_POLL_INTERVAL),
+                format_duration(self.poll_interval)
+            );
+        }
+
+        if self.verify_attempts == 0 || self.verify_attempts > MAX_VERIFY_ATTEMPTS {
+            anyhow::bail!(
+                "verify-attempts must be between 1 and {}, got {}",
+                MAX_VERIFY_ATTEMPTS,
+                self.verify_attempts
+            );
+        }
+
+        if self.verify_interval > MAX_TIMEOUT {
+            anyhow::bail!(
+                "verify-interval must be at most {}, got {}",
+                format_duration(MAX_TIMEOUT),
+                format_duration(self.verify_interval)
+            );
+        }
+
+        if self.idle_window.is_zero() || self.idle_window > self.idle_timeout {
+            anyhow::bail!(
+                "idle-window must be between 1ms and idle-timeout ({}), got {}",
+                format_duration(self.idle_timeout),
+                format_duration(self.idle_window)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The contents of the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub timing: TimingOverrides,
+    #[serde(default, rename = "launch-agent")]
+    pub launch_agent: LaunchAgentConfig,
+}
+
+/// The `[launch-agent]` table of the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaunchAgentConfig {
+    pub strategy: Option<LaunchAgentStrategy>,
+}
+
+impl Config {
+    pub fn default_path(layout: &Layout) -> PathBuf {
+        layout.app_support_dir().join(CONFIG_FILE_NAME)
+    }
+
+    /// Load the config file at `path`, or at [`Config::default_path`] if `None`. A
+    /// missing default config file is not an error.
+    pub fn load(layout: &Layout, path: Option<&Path>) -> Result<(Self, PathBuf)> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => (Self::default_path(layout), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok((Self::default(), path));
+            }
+            Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+        };
+
+        let config = toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+        Ok((config, path))
+    }
+}
+
+/// Timings from the defaults, then the config file, then the command line.
+pub fn resolve_timings(
+    layout: &Layout,
+    config_path: Option<&Path>,
+    cli: &TimingOverrides,
+) -> Result<Timings> {
+    let (config, path) = Config::load(layout, config_path)?;
+    let mut timings = Timings::default();
+    timings.apply(&config.timing, &path.display().to_string())?;
+    timings.apply(cli, "command-line flags")?;
+    Ok(timings)
+}
+
+/// The LaunchAgent strategy from the command line, else the config file, else
+/// [`LaunchAgentStrategy::Rename`].
+pub fn resolve_launch_agent_strategy(
+    layout: &Layout,
+    config_path: Option<&Path>,
+    cli: Option<LaunchAgentStrategy>,
+) -> Result<LaunchAgentStrategy> {
+    if let Some(strategy) = cli {
+        return Ok(strategy);
+    }
+    let (config, _) = Config::load(layout, config_path)?;
+    Ok(config.launch_agent.strategy.unwrap_or_default())
+}
+
+/// Format `duration` like `10s` or `1s 500ms`, to millisecond precision.
+pub fn format_duration(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_millis(duration.as_millis() as u64)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn timings_from(toml: &str) -> Result<Timings> {
+        let config: Config = toml::from_str(toml)?;
+        let mut timings = Timings::default();
+        timings.apply(&config.timing, "config.toml")?;
+        Ok(timings)
+    }
+
+    #[test]
+    fn describes_each_verification_setting_once() {
+        assert_eq!(
+            Timings::default().describe_verify(),
+            "5 attempts (verify-attempts) 500ms apart (verify-interval)"
+        );
+    }
+
+    #[test]
+    fn idle_timings_come_from_the_timing_table() {
+        let timings =
+            timings_from("[timing]\nidle-timeout = \"15m\"\nidle-window = \"1m\"\n").unwrap();
+
+        assert_eq!(timings.idle_timeout, Duration::from_secs(900));
+        assert_eq!(timings.idle_window, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn idle_window_must_fit_in_idle_timeout() {
+        let error =
+            timings_from("[timing]\nidle-timeout = \"1m\"\nidle-window = \"2m\"\n").unwrap_err();
+
+        assert!(error.to_string().contains("idle-window"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_zero_and_out_of_range_values() {
+        for (setting, message) in [
+            (
+                "quit-timeout = \"0s\"",
+                "quit-timeout must be between 1ms and 1h",
+            ),
+            (
+                "stop-timeout = \"2h\"",
+                "stop-timeout must be between 1ms and 1h",
+            ),
+            (
+                "start-timeout = \"0s\"",
+                "start-timeout must be between 1ms and 1h",
+            ),
+            ("term-grace = \"2h\"", "term-grace must be at most 1h"),
+            (
+                "poll-interval = \"1ms\"",
+                "poll-interval must be at least 10ms",
+            ),
+            (
+                "poll-interval = \"20s\"",
+                "poll-interval (20s) must not be longer",
+            ),
+            (
+                "verify-interval = \"2h\"",
+                "verify-interval must be at most 1h",
+            ),
+            ("idle-window = \"0s\"", "idle-window must be between 1ms"),
+        ] {
+            let error = timings_from(&format!("[timing]\n{}\n", setting)).unwrap_err();
+
+            assert!(
+                error.to_string().contains(message),
+                "{}: {}",
+                setting,
+                error
+            );
+            assert!(error.to_string().contains("config.toml"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn verify_attempts_must_be_between_one_and_the_maximum() {
+        for attempts in [0, MAX_VERIFY_ATTEMPTS + 1] {
+            let error =
+                timings_from(&format!("[timing]\nverify-attempts = {}\n", attempts)).unwrap_err();
+
+            assert!(
+                error
+                    .to_string()
+                    .contains("verify-attempts must be between 1 and 100"),
+                "{}",
+                error
+            );
+        }
+        let timings = timings_from("[timing]\nverify-attempts = 1\n").unwrap();
+        assert_eq!(timings.verify_attempts, 1);
+    }
+
+    #[test]
+    fn an_invalid_value_leaves_the_timings_unchanged() {
+        let mut timings = Timings::default();
+        let overrides = TimingOverrides {
+            quit_timeout: Some(Duration::from_secs(30)),
+            verify_attempts: Some(0),
+            ..TimingOverrides::default()
+        };
+
+        assert!(timings.apply(&overrides, "command-line flags").is_err());
+        assert_eq!(timings, Timings::default());
+    }
+
+    #[test]
+    fn command_line_wins_over_config_file_which_wins_over_defaults() {
+        let mac = TestMac::new();
+        let path = Config::default_path(&mac.layout);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            "[timing]\nquit-timeout = \"20s\"\nstop-timeout = \"30s\"\n",
+        )
+        .unwrap();
+        let cli = TimingOverrides {
+            stop_timeout: Some(Duration::from_secs(40)),
+            ..TimingOverrides::default()
+        };
+
+        let timings = resolve_timings(&mac.layout, None, &cli).unwrap();
+
+        assert_eq!(timings.quit_timeout, Duration::from_secs(20));
+        assert_eq!(timings.stop_timeout, Duration::from_secs(40));
+        assert_eq!(timings.term_grace, Timings::default().term_grace);
+    }
+
+    #[test]
+    fn a_missing_default_config_file_gives_the_defaults() {
+        let mac = TestMac::new();
+
+        let timings = resolve_timings(&mac.layout, None, &TimingOverrides::default()).unwrap();
+
+        assert_eq!(timings, Timings::default());
+    }
+
+    #[test]
+    fn a_missing_explicit_config_file_is_an_error() {
+        let mac = TestMac::new();
+        let path = mac.layout.app_support_dir().join("missing.toml");
+
+        let error =
+            resolve_timings(&mac.layout, Some(&path), &TimingOverrides::default()).unwrap_err();
+
+        assert!(error.to_string().contains("missing.toml"), "{}", error);
+    }
+
+    #[test]
+    fn an_invalid_config_file_value_names_the_file() {
+        let mac = TestMac::new();
+        let path = mac.layout.app_support_dir().join("custom.toml");
+        fs::create_dir_all(mac.layout.app_support_dir()).unwrap();
+        fs::write(&path, "[timing]\nverify-attempts = 0\n").unwrap();
+
+        let error =
+            resolve_timings(&mac.layout, Some(&path), &TimingOverrides::default()).unwrap_err();
+
+        assert!(error.to_string().contains("custom.toml"), "{}", error);
+    }
+}
@@ -1,41 +1,177 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-pub const DROPBOX_BUNDLE_IDS: &[&str] = &[
-    "com.getdropbox.dropbox.fileprovider",
-    "com.getdropbox.dropbox.TransferExtension",
-    "com.getdropbox.dropbox.garcon",
-];
+/// The code-signing team ID of Dropbox, Inc.
+pub const DROPBOX_TEAM_ID: &str = "G7HH3F8CAK";
+const GROUP_CONTAINER_SUFFIX: &str = ".com.getdropbox.dropbox.sync";
 
-pub const LAUNCH_AGENT_NAME: &str = "com.dropbox.DropboxMacUpdate.agent.plist";
-
-pub fn get_home_dir() -> Result<PathBuf> {
-    dirs::home_dir().context("Could not determine home directory")
+/// Where droponoff looks for things on disk.
+///
+/// All paths are resolved beneath `root`, which is `/` for the running system but can
+/// point at a fake macOS home, a mounted disk image or a Time Machine restore.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    root: PathBuf,
+    /// The user's home directory as seen from inside `root` (e.g. `/Users/scode`).
+    home: PathBuf,
 }
 
-pub fn find_dropbox_app() -> Result<PathBuf> {
-    let system_path = PathBuf::from("/Applications/Dropbox.app");
-    if system_path.exists() {
-        return Ok(system_path);
+impl Layout {
+    pub fn new(root: impl Into<PathBuf>, home: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            home: home.into(),
+        }
     }
 
-    let home = get_home_dir()?;
-    let user_path = home.join("Applications/Dropbox.app");
-    if user_path.exists() {
-        return Ok(user_path);
+    /// The running system, or the given root with the current user's home directory.
+    pub fn detect(root: Option<&Path>) -> Result<Self> {
+        let home = dirs::home_dir().context("Could not determine home directory")?;
+        Ok(Self::new(root.unwrap_or(Path::new("/")), home))
     }
 
-    anyhow::bail!("Dropbox.app not found in /Applications or ~/Applications")
-}
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve an absolute path on the target system to a path beneath `root`.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        self.root
+            .join(path.strip_prefix(Path::new("/")).unwrap_or(path))
+    }
+
+    /// The inverse of [`Layout::resolve`]: the absolute path on the target system of a
+    /// path beneath `root`.
+    pub fn unresolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    pub fn home_dir(&self) -> PathBuf {
+        self.resolve(&self.home)
+    }
+
+    pub fn find_dropbox_app(&self) -> Result<PathBuf> {
+        let system_path = self.resolve("/Applications/Dropbox.app");
+        if system_path.exists() {
+            return Ok(system_path);
+        }
+
+        let user_path = self.home_dir().join("Applications/Dropbox.app");
+        if user_path.exists() {
+            return Ok(user_path);
+        }
+
+        anyhow::bail!(
+            "Dropbox.app not found in {} or {}",
+            system_path.display(),
+            user_path.display()
+        )
+    }
+
+    /// Where droponoff keeps its own state.
+    pub fn app_support_dir(&self) -> PathBuf {
+        self.home_dir()
+            .join("Library/Application Support/droponoff")
+    }
+
+    pub fn launch_agents_dir(&self) -> PathBuf {
+        self.home_dir().join("Library/LaunchAgents")
+    }
+
+    pub fn global_launch_agents_dir(&self) -> PathBuf {
+        self.resolve("/Library/LaunchAgents")
+    }
+
+    pub fn launch_daemons_dir(&self) -> PathBuf {
+        self.resolve("/Library/LaunchDaemons")
+    }
 
-pub fn get_launch_agent_path() -> Result<PathBuf> {
-    let home = get_home_dir()?;
-    Ok(home.join("Library/LaunchAgents").join(LAUNCH_AGENT_NAME))
+    /// The Dropbox File Provider root mount, preferring the Data volume's view of the
+    /// home directory when it exists.
+    pub fn root_mount(&self) -> PathBuf {
+        let data_home = self.resolve(
+            Path::new("/System/Volumes/Data")
+                .join(self.home.strip_prefix(Path::new("/")).unwrap_or(&self.home)),
+        );
+        let base_home = if data_home.exists() {
+            data_home
+        } else {
+            self.home_dir()
+        };
+
+        let container_name = format!("{}{}", DROPBOX_TEAM_ID, GROUP_CONTAINER_SUFFIX);
+        base_home
+            .join("Library/Group Containers")
+            .join(container_name)
+            .join("root-mount")
+    }
 }
 
-pub fn get_launch_agent_disabled_path() -> Result<PathBuf> {
-    let home = get_home_dir()?;
-    Ok(home
-        .join("Library/LaunchAgents")
-        .join(format!("{}.disabled", LAUNCH_AGENT_NAME)))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn paths_resolve_beneath_the_root_and_back() {
+        let layout = Layout::new("/Volumes/Backup", "/Users/me");
+
+        let resolved = layout.resolve("/Library/LaunchAgents");
+
+        assert_eq!(resolved, Path::new("/Volumes/Backup/Library/LaunchAgents"));
+        assert_eq!(
+            layout.unresolve(&resolved),
+            Path::new("/Library/LaunchAgents")
+        );
+        assert_eq!(layout.home_dir(), Path::new("/Volumes/Backup/Users/me"));
+        assert_eq!(
+            layout.unresolve("/elsewhere/file"),
+            Path::new("/elsewhere/file")
+        );
+    }
+
+    #[test]
+    fn dropbox_app_is_found_in_the_users_applications_when_not_in_the_systems() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout::new(dir.path(), "/Users/me");
+
+        let error = layout.find_dropbox_app().unwrap_err();
+        assert!(
+            error.to_string().contains("Users/me/Applications"),
+            "{}",
+            error
+        );
+
+        let user_app = layout.home_dir().join("Applications/Dropbox.app");
+        fs::create_dir_all(&user_app).unwrap();
+        assert_eq!(layout.find_dropbox_app().unwrap(), user_app);
+
+        let system_app = layout.resolve("/Applications/Dropbox.app");
+        fs::create_dir_all(&system_app).unwrap();
+        assert_eq!(layout.find_dropbox_app().unwrap(), system_app);
+    }
+
+    #[test]
+    fn root_mount_prefers_the_data_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout::new(dir.path(), "/Users/me");
+        let container = "Library/Group Containers/G7HH3F8CAK.com.getdropbox.dropbox.sync";
+
+        assert_eq!(
+            layout.root_mount(),
+            layout.home_dir().join(container).join("root-mount")
+        );
+
+        let data_home = layout.resolve("/System/Volumes/Data/Users/me");
+        fs::create_dir_all(&data_home).unwrap();
+        assert_eq!(
+            layout.root_mount(),
+            data_home.join(container).join("root-mount")
+        );
+    }
 }
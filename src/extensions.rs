@@ -1,85 +1,889 @@
-use crate::discovery;
+use crate::backend::{Election, SystemBackend};
+use crate::config::Timings;
+use crate::discovery::Layout;
+use crate::state::Target;
 use anyhow::{Context, Result};
-use duct::cmd;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExtensionState {
-    #[allow(dead_code)]
     pub bundle_id: String,
     pub enabled: bool,
     pub found: bool,
+    /// Shipped in Dropbox.app but not in [`KNOWN_EXTENSIONS`].
+    #[serde(default)]
+    pub unknown: bool,
+    /// From the extension's `Info.plist`, when it was found in Dropbox.app.
+    #[serde(default)]
+    pub extension_point: Option<String>,
+    /// Every registration pluginkit reports for the bundle ID, the one `enabled`
+    /// describes first. There is more than one when stale copies of Dropbox registered
+    /// the same extension.
+    #[serde(default)]
+    pub registrations: Vec<PluginRegistration>,
 }
 
-pub fn get_extension_state(bundle_id: &str) -> Result<ExtensionState> {
-    // Query pluginkit for this specific bundle ID
-    let output = cmd!("pluginkit", "-m", "-i", bundle_id)
-        .stdout_capture()
-        .stderr_capture()
-        .unchecked()
-        .run()
-        .context("Failed to query pluginkit")?;
+impl ExtensionState {
+    /// The path to elect by, so that stale duplicates are left alone. `None` when
+    /// there are no duplicates and the bundle ID is enough.
+    pub fn target_path(&self) -> Option<&Path> {
+        if self.registrations.len() > 1 {
+            self.registrations.first().and_then(|r| r.path.as_deref())
+        } else {
+            None
+        }
+    }
+
+    /// The registration at `path`, or the one that decides whether the extension is
+    /// enabled.
+    pub fn registration(&self, path: Option<&Path>) -> Option<&PluginRegistration> {
+        match path {
+            Some(path) => self
+                .registrations
+                .iter()
+                .find(|r| r.path.as_deref() == Some(path)),
+            None => self.registrations.first(),
+        }
+    }
+}
+
+/// What `on` or `off` does with an extension, and whether its state counts towards
+/// Dropbox being on or off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionPolicy {
+    /// Toggled and verified; the command fails if it does not reach the new state.
+    Required,
+    /// Toggled, but only warned about if it does not reach the new state, and
+    /// consistent with the target whichever state it is in.
+    Optional,
+    /// Left alone, and consistent with the target whichever state it is in.
+    Ignored,
+}
+
+impl ExtensionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExtensionPolicy::Required => "required",
+            ExtensionPolicy::Optional => "optional",
+            ExtensionPolicy::Ignored => "ignored",
+        }
+    }
+}
+
+/// An extension droponoff knows by bundle ID, with its policy for each target.
+#[derive(Debug, Clone, Copy)]
+pub struct KnownExtension {
+    pub bundle_id: &'static str,
+    pub on: ExtensionPolicy,
+    pub off: ExtensionPolicy,
+}
+
+pub const KNOWN_EXTENSIONS: &[KnownExtension] = &[
+    KnownExtension {
+        bundle_id: "com.getdropbox.dropbox.fileprovider",
+        on: ExtensionPolicy::Required,
+        off: ExtensionPolicy::Required,
+    },
+    KnownExtension {
+        bundle_id: "com.getdropbox.dropbox.TransferExtension",
+        on: ExtensionPolicy::Required,
+        off: ExtensionPolicy::Required,
+    },
+    // garcon seems to have to do with old school non-file system provider operation,
+    // and may legitimately stay disabled while Dropbox is on.
+    KnownExtension {
+        bundle_id: "com.getdropbox.dropbox.garcon",
+        on: ExtensionPolicy::Optional,
+        off: ExtensionPolicy::Required,
+    },
+];
+
+pub fn known_extension(bundle_id: &str) -> Option<&'static KnownExtension> {
+    KNOWN_EXTENSIONS.iter().find(|e| e.bundle_id == bundle_id)
+}
+
+/// The policy for `bundle_id` when turning Dropbox to `target`. Extensions droponoff
+/// does not know are required either way.
+pub fn policy(bundle_id: &str, target: Target) -> ExtensionPolicy {
+    match (known_extension(bundle_id), target) {
+        (Some(known), Target::On) => known.on,
+        (Some(known), Target::Off) => known.off,
+        (None, _) => ExtensionPolicy::Required,
+    }
+}
+
+/// The marker pluginkit prints before each registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginElection {
+    /// `+`: the user elected to use it.
+    Use,
+    /// `-`: the user elected to ignore it.
+    Ignore,
+    /// `=`: superseded by another registration of the same bundle ID.
+    Superseded,
+    /// `!`: a development build.
+    Development,
+    /// `?`: pluginkit could not determine the election.
+    Unknown,
+    /// No marker: no election was made.
+    Default,
+}
+
+impl PluginElection {
+    fn from_marker(marker: char) -> Option<Self> {
+        match marker {
+            '+' => Some(PluginElection::Use),
+            '-' => Some(PluginElection::Ignore),
+            '=' => Some(PluginElection::Superseded),
+            '!' => Some(PluginElection::Development),
+            '?' => Some(PluginElection::Unknown),
+            _ => None,
+        }
+    }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PluginElection::Use => "use",
+            PluginElection::Ignore => "ignore",
+            PluginElection::Superseded => "superseded",
+            PluginElection::Development => "development",
+            PluginElection::Unknown => "unknown",
+            PluginElection::Default => "default",
+        }
+    }
+}
+
+/// One registration in `pluginkit -m -v` output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginRegistration {
+    pub election: PluginElection,
+    pub bundle_id: String,
+    pub version: Option<String>,
+    pub uuid: Option<String>,
+    /// When the registration was made, as pluginkit prints it.
+    pub timestamp: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+/// Parse `pluginkit -m -v` output. Each line looks like
+/// `+    com.example.ext(1.0)\t<UUID>\t<timestamp>\t<path>`; without `-v` only the
+/// marker and `com.example.ext(1.0)` are there. With `-vvv`, the UUID, timestamp and
+/// path are instead on indented `Key = value` lines below the registration.
+pub fn parse_pluginkit(output: &str) -> Vec<PluginRegistration> {
+    let mut registrations: Vec<PluginRegistration> = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        if let Some((key, value)) = line.trim().split_once(" = ") {
+            let Some(registration) = registrations.last_mut() else {
+                continue;
+            };
+            let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+            match key.trim() {
+                "UUID" => registration.uuid = value,
+                "Timestamp" => registration.timestamp = value,
+                "Path" => registration.path = value.map(PathBuf::from),
+                _ => {}
+            }
+            continue;
+        }
 
-    if stdout.trim().is_empty() {
-        return Ok(ExtensionState {
+        let (election, rest) = match line.chars().next().and_then(PluginElection::from_marker) {
+            Some(election) => (election, &line[1..]),
+            None => (PluginElection::Default, line),
+        };
+        let mut fields = rest.split('\t').map(str::trim);
+        let Some(name) = fields.next().and_then(|f| f.split_whitespace().next()) else {
+            continue;
+        };
+        let (bundle_id, version) = match name.split_once('(') {
+            Some((bundle_id, version)) => {
+                (bundle_id, Some(version.trim_end_matches(')').to_string()))
+            }
+            None => (name, None),
+        };
+        let mut field = || fields.next().filter(|f| !f.is_empty()).map(str::to_string);
+        registrations.push(PluginRegistration {
+            election,
             bundle_id: bundle_id.to_string(),
-            enabled: false,
-            found: false,
+            version,
+            uuid: field(),
+            timestamp: field(),
+            path: field().map(PathBuf::from),
         });
     }
+    registrations
+}
+
+/// An app extension (`.appex`) inside Dropbox.app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundledExtension {
+    pub bundle_id: String,
+    /// e.g. `com.apple.fileprovider-nonui`.
+    pub extension_point: Option<String>,
+    pub path: PathBuf,
+}
+
+/// The keys of an extension's `Info.plist` that identify it. App extensions name their
+/// extension point under `NSExtension`, ExtensionKit extensions under
+/// `EXAppExtensionAttributes`.
+#[derive(Deserialize)]
+struct ExtensionInfo {
+    #[serde(rename = "CFBundleIdentifier")]
+    bundle_id: String,
+    #[serde(rename = "NSExtension")]
+    ns_extension: Option<ExtensionPoint>,
+    #[serde(rename = "EXAppExtensionAttributes")]
+    ex_extension: Option<ExtensionPoint>,
+}
+
+#[derive(Deserialize)]
+struct ExtensionPoint {
+    #[serde(
+        rename = "NSExtensionPointIdentifier",
+        alias = "EXExtensionPointIdentifier"
+    )]
+    identifier: Option<String>,
+}
+
+/// Directories in an app bundle's `Contents` that hold resources or code libraries
+/// rather than other bundles, and are too large to walk.
+const SKIPPED_DIRS: &[&str] = &["Frameworks", "Resources", "_CodeSignature"];
 
-    // The output format: lines start with "+" for enabled, "-" for disabled
-    let enabled = stdout.trim_start().starts_with('+');
+/// Every `.appex` in the Dropbox.app at `app`, including extensions nested inside other
+/// bundles, sorted by bundle ID. Extensions without a readable `Info.plist` are
+/// skipped.
+pub fn find_bundled_extensions(app: &Path) -> Result<Vec<BundledExtension>> {
+    let mut extensions = Vec::new();
+    walk_bundle_dir(&app.join("Contents"), &mut extensions)?;
+    extensions.sort_by(|a, b| a.bundle_id.cmp(&b.bundle_id));
+    extensions.dedup_by(|a, b| a.bundle_id == b.bundle_id);
+    Ok(extensions)
+}
+
+fn walk_bundle_dir(dir: &Path, extensions: &mut Vec<BundledExtension>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => anyhow::bail!("Failed to read {}: {}", dir.display(), e),
+    };
+    for entry in entries.flatten() {
+        // Symlinks inside bundles point back into the same bundle.
+        if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if SKIPPED_DIRS.contains(&name.as_str()) {
+            continue;
+        }
+        if name.ends_with(".appex") {
+            let info_path = path.join("Contents/Info.plist");
+            if let Ok(info) = plist::from_file::<_, ExtensionInfo>(&info_path) {
+                extensions.push(BundledExtension {
+                    bundle_id: info.bundle_id,
+                    extension_point: info
+                        .ns_extension
+                        .or(info.ex_extension)
+                        .and_then(|point| point.identifier),
+                    path: path.clone(),
+                });
+            }
+        }
+        walk_bundle_dir(&path, extensions)?;
+    }
+    Ok(())
+}
+
+/// The extensions Dropbox.app ships, or none if it is not installed.
+pub fn installed_extensions(layout: &Layout) -> Result<Vec<BundledExtension>> {
+    match layout.find_dropbox_app() {
+        Ok(app) => find_bundled_extensions(&app),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// The known extension bundle IDs, followed by any others the installed Dropbox.app
+/// ships.
+pub fn dropbox_bundle_ids(layout: &Layout) -> Result<Vec<String>> {
+    Ok(merge_bundle_ids(&installed_extensions(layout)?))
+}
+
+/// The known extension bundle IDs, followed by any others in `installed`.
+pub fn merge_bundle_ids(installed: &[BundledExtension]) -> Vec<String> {
+    let mut bundle_ids: Vec<String> = KNOWN_EXTENSIONS
+        .iter()
+        .map(|e| e.bundle_id.to_string())
+        .collect();
+    for extension in installed {
+        if !bundle_ids.contains(&extension.bundle_id) {
+            bundle_ids.push(extension.bundle_id.clone());
+        }
+    }
+    bundle_ids
+}
+
+/// The registrations of `bundle_id`, aggregated. The one inside the installed
+/// Dropbox.app decides whether the extension is enabled; failing that, the first one
+/// that is not superseded.
+pub fn get_extension_state(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    bundle_id: &str,
+) -> Result<ExtensionState> {
+    let mut registrations = get_registrations(sys, bundle_id)?;
+
+    let app = layout
+        .find_dropbox_app()
+        .ok()
+        .map(|app| layout.unresolve(app));
+    let in_app = |r: &PluginRegistration| {
+        r.path
+            .as_deref()
+            .zip(app.as_deref())
+            .is_some_and(|(path, app)| layout.unresolve(path).starts_with(app))
+    };
+    let primary = registrations
+        .iter()
+        .position(in_app)
+        .or_else(|| {
+            registrations
+                .iter()
+                .position(|r| r.election != PluginElection::Superseded)
+        })
+        .unwrap_or(0);
+    if primary < registrations.len() {
+        let registration = registrations.remove(primary);
+        registrations.insert(0, registration);
+    }
 
     Ok(ExtensionState {
         bundle_id: bundle_id.to_string(),
-        enabled,
-        found: true,
+        enabled: registrations
+            .first()
+            .is_some_and(|r| r.election == PluginElection::Use),
+        found: !registrations.is_empty(),
+        unknown: false,
+        extension_point: None,
+        registrations,
     })
 }
 
-pub fn disable_extension(bundle_id: &str) -> Result<()> {
-    cmd!("pluginkit", "-e", "ignore", "-i", bundle_id)
-        .stdout_null()
-        .stderr_null()
-        .run()
-        .with_context(|| format!("Failed to disable extension {}", bundle_id))?;
-    info!("  Disabled {}", bundle_id);
+/// Every registration pluginkit reports for exactly `bundle_id`.
+pub fn get_registrations(
+    sys: &dyn SystemBackend,
+    bundle_id: &str,
+) -> Result<Vec<PluginRegistration>> {
+    // pluginkit matches bundle IDs by prefix.
+    Ok(parse_pluginkit(&sys.pluginkit_match(bundle_id)?)
+        .into_iter()
+        .filter(|r| r.bundle_id == bundle_id)
+        .collect())
+}
+
+/// Elect `bundle_id`, or only its registration at `path`.
+fn elect(
+    sys: &dyn SystemBackend,
+    bundle_id: &str,
+    path: Option<&Path>,
+    election: Election,
+) -> Result<()> {
+    let Some(path) = path else {
+        return sys.pluginkit_elect(bundle_id, election);
+    };
+    let registration = get_registrations(sys, bundle_id)?
+        .into_iter()
+        .find(|r| r.path.as_deref() == Some(path));
+    match registration.and_then(|r| r.uuid) {
+        Some(uuid) => sys.pluginkit_elect_uuid(&uuid, election),
+        None => anyhow::bail!(
+            "pluginkit has no registration of {} at {}",
+            bundle_id,
+            path.display()
+        ),
+    }
+}
+
+fn describe(bundle_id: &str, path: Option<&Path>) -> String {
+    match path {
+        Some(path) => format!("{} at {}", bundle_id, path.display()),
+        None => bundle_id.to_string(),
+    }
+}
+
+/// Whether pluginkit reports `registration` as enabled when `target` is reached.
+fn reached(registration: &PluginRegistration, target: Target) -> bool {
+    (registration.election == PluginElection::Use) == (target == Target::On)
+}
+
+/// Elect `bundle_id` (or its registration at `path`) for `target`, then query pluginkit
+/// until it reflects the new election, electing again after every miss.
+fn set_extension(
+    sys: &dyn SystemBackend,
+    bundle_id: &str,
+    path: Option<&Path>,
+    target: Target,
+    timings: &Timings,
+) -> Result<()> {
+    let election = match target {
+        Target::On => Election::Use,
+        Target::Off => Election::Ignore,
+    };
+    elect(sys, bundle_id, path, election)?;
+
+    let max_attempts = timings.verify_attempts;
+    for attempt in 1..=max_attempts {
+        let registrations: Vec<_> = get_registrations(sys, bundle_id)?
+            .into_iter()
+            .filter(|r| path.is_none() || r.path.as_deref() == path)
+            .collect();
+        if registrations.is_empty() {
+            anyhow::bail!("pluginkit no longer reports {}", describe(bundle_id, path));
+        }
+        let Some(pending) = registrations.iter().find(|r| !reached(r, target)) else {
+            return Ok(());
+        };
+        if attempt == max_attempts {
+            anyhow::bail!(
+                "pluginkit still reports {} as {} after {}",
+                describe(bundle_id, pending.path.as_deref()),
+                pending.election.as_str(),
+                timings.describe_verify()
+            );
+        }
+        sys.clock().sleep(timings.verify_interval);
+        elect(sys, bundle_id, path, election)?;
+    }
     Ok(())
 }
 
-pub fn enable_extension(bundle_id: &str) -> Result<()> {
-    cmd!("pluginkit", "-e", "use", "-i", bundle_id)
-        .stdout_null()
-        .stderr_null()
-        .run()
-        .with_context(|| format!("Failed to enable extension {}", bundle_id))?;
-    info!("  Enabled {}", bundle_id);
+pub fn disable_extension(
+    sys: &dyn SystemBackend,
+    bundle_id: &str,
+    path: Option<&Path>,
+    timings: &Timings,
+) -> Result<()> {
+    set_extension(sys, bundle_id, path, Target::Off, timings)
+        .with_context(|| format!("Failed to disable extension {}", describe(bundle_id, path)))?;
+    info!("  Disabled {}", describe(bundle_id, path));
     Ok(())
 }
 
-pub fn disable_all_extensions() -> Result<()> {
-    for bundle_id in discovery::DROPBOX_BUNDLE_IDS {
-        let state = get_extension_state(bundle_id)?;
-        if state.found {
-            disable_extension(bundle_id)?;
-        } else {
-            info!("  {} not found, skipping", bundle_id);
+pub fn enable_extension(
+    sys: &dyn SystemBackend,
+    bundle_id: &str,
+    path: Option<&Path>,
+    timings: &Timings,
+) -> Result<()> {
+    set_extension(sys, bundle_id, path, Target::On, timings)
+        .with_context(|| format!("Failed to enable extension {}", describe(bundle_id, path)))?;
+    info!("  Enabled {}", describe(bundle_id, path));
+    Ok(())
+}
+
+/// The outcome of toggling several extensions.
+#[derive(Debug, Default)]
+pub struct ToggleReport {
+    /// Every extension that was elected, with the path of the registration when only
+    /// that one was. This includes elections that failed, which may still have
+    /// changed something; undoing one that did not is harmless.
+    pub changed: Vec<(String, Option<PathBuf>)>,
+    /// Each required extension that did not reach the new state, and why.
+    pub failures: Vec<(String, anyhow::Error)>,
+}
+
+impl ToggleReport {
+    /// Fail listing every extension that did not reach the new state.
+    pub fn result(&self) -> Result<()> {
+        if self.failures.is_empty() {
+            return Ok(());
         }
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|(_, e)| format!("{:#}", e))
+            .collect();
+        anyhow::bail!(
+            "{} extension(s) did not reach the new state:\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        )
     }
-    Ok(())
 }
 
-pub fn enable_all_extensions() -> Result<()> {
-    for bundle_id in discovery::DROPBOX_BUNDLE_IDS {
-        let state = get_extension_state(bundle_id)?;
-        if state.found {
-            enable_extension(bundle_id)?;
-        } else {
-            info!("  {} not found, skipping", bundle_id);
+pub fn disable_all_extensions(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timings: &Timings,
+) -> Result<ToggleReport> {
+    let bundle_ids = dropbox_bundle_ids(layout)?;
+    let targets: Vec<(&str, Option<&Path>)> =
+        bundle_ids.iter().map(|id| (id.as_str(), None)).collect();
+    set_extensions(sys, layout, &targets, Target::Off, timings)
+}
+
+pub fn enable_all_extensions(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timings: &Timings,
+) -> Result<ToggleReport> {
+    let bundle_ids = dropbox_bundle_ids(layout)?;
+    let targets: Vec<(&str, Option<&Path>)> =
+        bundle_ids.iter().map(|id| (id.as_str(), None)).collect();
+    set_extensions(sys, layout, &targets, Target::On, timings)
+}
+
+pub fn enable_extensions(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    targets: &[(&str, Option<&Path>)],
+    timings: &Timings,
+) -> Result<ToggleReport> {
+    set_extensions(sys, layout, targets, Target::On, timings)
+}
+
+pub fn disable_extensions(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    targets: &[(&str, Option<&Path>)],
+    timings: &Timings,
+) -> Result<ToggleReport> {
+    set_extensions(sys, layout, targets, Target::Off, timings)
+}
+
+/// Bring each `(bundle_id, path)` to `target` as its policy asks, electing only the
+/// registration at `path` if one is given and otherwise the one that decides whether
+/// the extension is enabled. Every extension is attempted even after one fails; an
+/// optional extension that fails is only warned about.
+fn set_extensions(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    targets: &[(&str, Option<&Path>)],
+    target: Target,
+    timings: &Timings,
+) -> Result<ToggleReport> {
+    let installed = installed_extensions(layout)?;
+    let mut report = ToggleReport::default();
+    for (bundle_id, path) in targets {
+        let policy = policy(bundle_id, target);
+        if policy == ExtensionPolicy::Ignored {
+            info!(
+                "  {} is ignored when turning Dropbox {}, skipping",
+                bundle_id,
+                target.as_str()
+            );
+            continue;
+        }
+
+        let mut fail = |e: anyhow::Error| {
+            if policy == ExtensionPolicy::Required {
+                warn!("  {:#}", e);
+                report.failures.push((bundle_id.to_string(), e));
+            } else {
+                warn!("  {:#} (optional, continuing)", e);
+            }
+        };
+
+        let state = match get_extension_state(sys, layout, bundle_id) {
+            Ok(state) => state,
+            Err(e) => {
+                fail(e);
+                continue;
+            }
+        };
+        let path = path.or(state.target_path());
+        let name = describe(bundle_id, path);
+        match state.registration(path) {
+            None if target == Target::On && installed.iter().any(|e| e.bundle_id == *bundle_id) => {
+                warn!(
+                    "  {} not found although Dropbox.app ships it, skipping; run `droponoff \
+                     repair` to register it again",
+                    name
+                )
+            }
+            None => info!("  {} not found, skipping", name),
+            Some(r) if reached(r, target) => info!(
+                "  {} already {}",
+                name,
+                if target == Target::On {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            ),
+            Some(_) => {
+                let result = match target {
+                    Target::On => enable_extension(sys, bundle_id, path, timings),
+                    Target::Off => disable_extension(sys, bundle_id, path, timings),
+                };
+                report
+                    .changed
+                    .push((bundle_id.to_string(), path.map(Path::to_path_buf)));
+                if let Err(e) = result {
+                    fail(e);
+                }
+            }
         }
     }
-    Ok(())
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestMac;
+
+    const FILEPROVIDER: &str = "com.getdropbox.dropbox.fileprovider";
+
+    /// `pluginkit -m -A -D -v -i com.getdropbox.dropbox.fileprovider` with the
+    /// installed Dropbox.app, a stale copy in Downloads and a development build.
+    const MATCH_VERBOSE: &str = "\
++    com.getdropbox.dropbox.fileprovider(234.4.5091)\t7F5E1C2A-3B4D-4E6F-8A9B-0C1D2E3F4A5B\t2025-06-02 09:14:31 +0000\t/Applications/Dropbox.app/Contents/PlugIns/FileProvider.appex
+=    com.getdropbox.dropbox.fileprovider(229.3.4512)\t1A2B3C4D-5E6F-4A8B-9C0D-1E2F3A4B5C6D\t2025-03-11 17:02:55 +0000\t/Users/me/Downloads/Dropbox.app/Contents/PlugIns/FileProvider.appex
+!    com.getdropbox.dropbox.fileprovider(235.0.1)\tAABBCCDD-0000-4000-8000-112233445566\t2025-06-20 08:00:00 +0000\t/Users/me/src/build/Dropbox.app/Contents/PlugIns/FileProvider.appex
+";
+
+    /// `pluginkit -mAvvv -i com.getdropbox.dropbox.fileprovider` after the user turned
+    /// the extension off and the copy it was registered from was deleted.
+    const MATCH_VERY_VERBOSE: &str = "\
+-    com.getdropbox.dropbox.fileprovider(234.4.5091)
+\t            Path = /Applications/Dropbox.app/Contents/PlugIns/FileProvider.appex
+\t            UUID = 7F5E1C2A-3B4D-4E6F-8A9B-0C1D2E3F4A5B
+\t       Timestamp = 2025-06-02 09:14:31 +0000
+\t             SDK = com.apple.fileprovider-nonui
+\t   Parent Bundle = /Applications/Dropbox.app
+\t    Display Name = Dropbox
+\t      Short Name = Dropbox
+\t     Parent Name = Dropbox
+
+?    com.getdropbox.dropbox.fileprovider(229.3.4512)
+\t            UUID = 1A2B3C4D-5E6F-4A8B-9C0D-1E2F3A4B5C6D
+\t       Timestamp = 2025-03-11 17:02:55 +0000
+\t             SDK = com.apple.fileprovider-nonui
+
+";
+
+    fn elections(registrations: &[PluginRegistration]) -> Vec<PluginElection> {
+        registrations.iter().map(|r| r.election).collect()
+    }
+
+    #[test]
+    fn parses_every_registration_of_a_bundle_id() {
+        let registrations = parse_pluginkit(MATCH_VERBOSE);
+
+        assert_eq!(
+            elections(&registrations),
+            vec![
+                PluginElection::Use,
+                PluginElection::Superseded,
+                PluginElection::Development
+            ]
+        );
+        assert_eq!(
+            registrations[0],
+            PluginRegistration {
+                election: PluginElection::Use,
+                bundle_id: FILEPROVIDER.to_string(),
+                version: Some("234.4.5091".to_string()),
+                uuid: Some("7F5E1C2A-3B4D-4E6F-8A9B-0C1D2E3F4A5B".to_string()),
+                timestamp: Some("2025-06-02 09:14:31 +0000".to_string()),
+                path: Some(PathBuf::from(
+                    "/Applications/Dropbox.app/Contents/PlugIns/FileProvider.appex"
+                )),
+            }
+        );
+        assert_eq!(
+            registrations[1].path.as_deref(),
+            Some(Path::new(
+                "/Users/me/Downloads/Dropbox.app/Contents/PlugIns/FileProvider.appex"
+            ))
+        );
+        assert_eq!(registrations[2].version.as_deref(), Some("235.0.1"));
+    }
+
+    #[test]
+    fn parses_every_election_marker() {
+        let output = "+    a.use(1)\n-    a.ignore(1)\n=    a.superseded(1)\n\
+                      !    a.development(1)\n?    a.unknown(1)\n     a.default(1)\n";
+
+        let registrations = parse_pluginkit(output);
+
+        assert_eq!(
+            elections(&registrations),
+            vec![
+                PluginElection::Use,
+                PluginElection::Ignore,
+                PluginElection::Superseded,
+                PluginElection::Development,
+                PluginElection::Unknown,
+                PluginElection::Default,
+            ]
+        );
+        assert_eq!(registrations[5].bundle_id, "a.default");
+        assert!(registrations.iter().all(|r| r.uuid.is_none()));
+    }
+
+    #[test]
+    fn registrations_without_a_path_have_none() {
+        let output = "-    com.getdropbox.dropbox.garcon(234.4.5091)\t\
+                      0A1B2C3D-0000-4000-8000-000000000001\t2025-06-02 09:14:31 +0000\t\n\
+                      +    com.getdropbox.dropbox.garcon\n";
+
+        let registrations = parse_pluginkit(output);
+
+        assert_eq!(registrations.len(), 2);
+        assert_eq!(registrations[0].election, PluginElection::Ignore);
+        assert!(registrations[0].uuid.is_some());
+        assert_eq!(registrations[0].path, None);
+        assert_eq!(registrations[1].version, None);
+        assert_eq!(registrations[1].path, None);
+    }
+
+    #[test]
+    fn parses_very_verbose_output() {
+        let registrations = parse_pluginkit(MATCH_VERY_VERBOSE);
+
+        assert_eq!(
+            elections(&registrations),
+            vec![PluginElection::Ignore, PluginElection::Unknown]
+        );
+        assert_eq!(
+            registrations[0].path.as_deref(),
+            Some(Path::new(
+                "/Applications/Dropbox.app/Contents/PlugIns/FileProvider.appex"
+            ))
+        );
+        assert_eq!(
+            registrations[0].timestamp.as_deref(),
+            Some("2025-06-02 09:14:31 +0000")
+        );
+        assert_eq!(
+            registrations[1].uuid.as_deref(),
+            Some("1A2B3C4D-5E6F-4A8B-9C0D-1E2F3A4B5C6D")
+        );
+        assert_eq!(registrations[1].path, None);
+    }
+
+    #[test]
+    fn no_registrations_in_empty_output() {
+        assert!(parse_pluginkit("").is_empty());
+        assert!(parse_pluginkit("\n  \n").is_empty());
+    }
+
+    #[test]
+    fn a_failing_pluginkit_is_not_read_as_no_registrations() {
+        let sys = crate::backend::FakeBackend::new().with_extension(FILEPROVIDER, true);
+        sys.script(
+            "pluginkit_match",
+            Err(format!(
+                "`pluginkit -m -A -D -v -i {}` failed with exit status 1: pkd: Connection \
+                 invalid",
+                FILEPROVIDER
+            )),
+        );
+
+        let error = get_registrations(&sys, FILEPROVIDER).unwrap_err();
+
+        assert!(
+            error.to_string().contains("Connection invalid"),
+            "{}",
+            error
+        );
+        assert_eq!(get_registrations(&sys, FILEPROVIDER).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn finds_extensions_anywhere_in_dropbox_app() {
+        let mac = TestMac::new()
+            .with_bundled_extension("PlugIns/FileProvider.appex", FILEPROVIDER)
+            .with_bundled_extension(
+                "PlugIns/FileProvider.appex/Contents/PlugIns/Thumbnails.appex",
+                "com.getdropbox.dropbox.thumbnails",
+            )
+            .with_bundled_extension(
+                "Library/LoginItems/Helper.app/Contents/PlugIns/Share.appex",
+                "com.getdropbox.dropbox.share",
+            )
+            // Not walked, and not an extension of the app even if it looks like one.
+            .with_bundled_extension(
+                "Frameworks/Sparkle.framework/Updater.appex",
+                "org.sparkle-project.updater",
+            );
+        fs::create_dir_all(mac.bundled_extension("PlugIns/Broken.appex/Contents")).unwrap();
+        let ex_info = mac.bundled_extension("PlugIns/Garcon.appex/Contents/Info.plist");
+        fs::create_dir_all(ex_info.parent().unwrap()).unwrap();
+        fs::write(
+            &ex_info,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+	<key>CFBundleIdentifier</key>
+	<string>com.getdropbox.dropbox.garcon</string>
+	<key>EXAppExtensionAttributes</key>
+	<dict>
+		<key>EXExtensionPointIdentifier</key>
+		<string>com.apple.FinderSync</string>
+	</dict>
+</dict>
+</plist>
+"#,
+        )
+        .unwrap();
+
+        let app = mac.layout.find_dropbox_app().unwrap();
+        let found = find_bundled_extensions(&app).unwrap();
+
+        let summary: Vec<(&str, Option<&str>)> = found
+            .iter()
+            .map(|e| (e.bundle_id.as_str(), e.extension_point.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "com.getdropbox.dropbox.fileprovider",
+                    Some("com.apple.fileprovider-nonui")
+                ),
+                (
+                    "com.getdropbox.dropbox.garcon",
+                    Some("com.apple.FinderSync")
+                ),
+                (
+                    "com.getdropbox.dropbox.share",
+                    Some("com.apple.fileprovider-nonui")
+                ),
+                (
+                    "com.getdropbox.dropbox.thumbnails",
+                    Some("com.apple.fileprovider-nonui")
+                ),
+            ]
+        );
+        assert_eq!(
+            found[0].path,
+            mac.bundled_extension("PlugIns/FileProvider.appex")
+        );
+    }
+
+    #[test]
+    fn bundle_ids_are_the_known_ones_then_those_dropbox_app_ships() {
+        let known: Vec<String> = KNOWN_EXTENSIONS
+            .iter()
+            .map(|e| e.bundle_id.to_string())
+            .collect();
+
+        // Without Dropbox.app, only the extensions droponoff knows by name.
+        let empty = tempfile::tempdir().unwrap();
+        let layout = Layout::new(empty.path(), "/Users/me");
+        assert!(installed_extensions(&layout).unwrap().is_empty());
+        assert_eq!(dropbox_bundle_ids(&layout).unwrap(), known);
+
+        let mac = TestMac::new()
+            .with_bundled_extension("PlugIns/Share.appex", "com.getdropbox.dropbox.share")
+            .with_bundled_extension("PlugIns/FileProvider.appex", FILEPROVIDER);
+        let mut expected = known;
+        expected.push("com.getdropbox.dropbox.share".to_string());
+        assert_eq!(dropbox_bundle_ids(&mac.layout).unwrap(), expected);
+    }
 }
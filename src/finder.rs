@@ -1,12 +1,6 @@
-use anyhow::{Context, Result};
-use duct::cmd;
+use crate::backend::SystemBackend;
+use anyhow::Result;
 
-pub fn restart_finder() -> Result<()> {
-    cmd!("killall", "Finder")
-        .stdout_null()
-        .stderr_null()
-        .unchecked() // Finder may not be running
-        .run()
-        .context("Failed to restart Finder")?;
-    Ok(())
+pub fn restart_finder(sys: &dyn SystemBackend) -> Result<()> {
+    sys.restart_finder()
 }
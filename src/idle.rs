@@ -0,0 +1,369 @@
+//! Waiting for Dropbox to stop syncing before it is turned off, judged by the CPU and
+//! network use of its processes and by writes to its scratch_files directories.
+
+use crate::backend::SystemBackend;
+use crate::config::{format_duration, Timings};
+use crate::discovery::Layout;
+use crate::processes;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+pub struct IdleOptions {
+    /// Give up if Dropbox has not been quiet for `window` within this long.
+    pub timeout: Duration,
+    /// How long every signal must stay quiet.
+    pub window: Duration,
+    pub poll_interval: Duration,
+    /// CPU use, as a percentage of one core, above which a process counts as busy.
+    pub max_cpu_percent: f64,
+    /// Network traffic per poll interval above which Dropbox counts as busy.
+    pub max_network_bytes: u64,
+}
+
+impl Default for IdleOptions {
+    fn default() -> Self {
+        let timings = Timings::default();
+        Self {
+            timeout: timings.idle_timeout,
+            window: timings.idle_window,
+            poll_interval: Duration::from_secs(2),
+            max_cpu_percent: 5.0,
+            max_network_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Number, total size and newest modification time of the files in every
+/// `root-mount/*/scratch_files` directory.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct ScratchSignature {
+    files: usize,
+    bytes: u64,
+    newest: Option<SystemTime>,
+}
+
+struct Sample {
+    cpu: HashMap<u32, Duration>,
+    network: HashMap<u32, u64>,
+    scratch: ScratchSignature,
+}
+
+/// Parse `ps -o pid=,time=` output. CPU times look like `[[dd-]hh:]mm:ss.cc`.
+fn parse_cpu_times(output: &str) -> HashMap<u32, Duration> {
+    let mut times = HashMap::new();
+    for line in output.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(pid), Some(time)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let Ok(pid) = pid.parse::<u32>() else {
+            continue;
+        };
+        if let Some(time) = parse_cpu_time(time) {
+            times.insert(pid, time);
+        }
+    }
+    times
+}
+
+fn parse_cpu_time(time: &str) -> Option<Duration> {
+    let (days, rest) = match time.split_once('-') {
+        Some((days, rest)) => (days.parse::<u64>().ok()?, rest),
+        None => (0, time),
+    };
+
+    let mut seconds = 0.0;
+    for part in rest.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(Duration::from_secs_f64(seconds + (days * 86_400) as f64))
+}
+
+/// Parse `nettop -x -J bytes_in,bytes_out` CSV output into total bytes per PID.
+fn parse_network_bytes(output: &str) -> HashMap<u32, u64> {
+    let mut bytes = HashMap::new();
+    for line in output.lines() {
+        let mut fields = line.split(',');
+        let Some(name) = fields.next() else {
+            continue;
+        };
+        let Some(pid) = name.rsplit('.').next().and_then(|p| p.parse::<u32>().ok()) else {
+            continue;
+        };
+        let total: u64 = fields.filter_map(|f| f.trim().parse::<u64>().ok()).sum();
+        *bytes.entry(pid).or_insert(0) += total;
+    }
+    bytes
+}
+
+fn scratch_signature(layout: &Layout) -> ScratchSignature {
+    let mut signature = ScratchSignature::default();
+    let Ok(entries) = fs::read_dir(layout.root_mount()) else {
+        return signature;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(children) = fs::read_dir(entry.path().join("scratch_files")) else {
+            continue;
+        };
+        for child in children.flatten() {
+            let Ok(metadata) = child.metadata() else {
+                continue;
+            };
+            signature.files += 1;
+            signature.bytes += metadata.len();
+            if let Ok(modified) = metadata.modified() {
+                signature.newest = signature.newest.max(Some(modified));
+            }
+        }
+    }
+    signature
+}
+
+fn sample(sys: &dyn SystemBackend, layout: &Layout) -> Result<Sample> {
+    let pids: Vec<u32> = processes::list_dropbox_processes(sys, layout)?
+        .iter()
+        .map(|p| p.pid)
+        .collect();
+
+    let cpu = parse_cpu_times(&sys.ps_cpu_times(&pids)?);
+    let network = if pids.is_empty() {
+        HashMap::new()
+    } else {
+        parse_network_bytes(&sys.nettop_bytes()?)
+            .into_iter()
+            .filter(|(pid, _)| pids.contains(pid))
+            .collect()
+    };
+
+    Ok(Sample {
+        cpu,
+        network,
+        scratch: scratch_signature(layout),
+    })
+}
+
+/// Everything that was busy between two samples, or an empty list if all was quiet.
+fn activity(
+    previous: &Sample,
+    current: &Sample,
+    elapsed: Duration,
+    options: &IdleOptions,
+) -> Vec<String> {
+    let mut busy = Vec::new();
+
+    for (pid, cpu) in &current.cpu {
+        let Some(before) = previous.cpu.get(pid) else {
+            continue;
+        };
+        let percent =
+            cpu.saturating_sub(*before).as_secs_f64() / elapsed.as_secs_f64().max(0.001) * 100.0;
+        if percent > options.max_cpu_percent {
+            busy.push(format!("PID {} used {:.1}% CPU", pid, percent));
+        }
+    }
+
+    let network: u64 = current
+        .network
+        .iter()
+        .map(|(pid, bytes)| {
+            bytes.saturating_sub(previous.network.get(pid).copied().unwrap_or(*bytes))
+        })
+        .sum();
+    if network > options.max_network_bytes {
+        busy.push(format!("{} bytes of network traffic", network));
+    }
+
+    if current.scratch != previous.scratch {
+        busy.push(format!(
+            "scratch_files changed ({} → {} files, {} → {} bytes)",
+            previous.scratch.files,
+            current.scratch.files,
+            previous.scratch.bytes,
+            current.scratch.bytes
+        ));
+    }
+
+    busy
+}
+
+/// Block until Dropbox has been quiet for `options.window`, or fail with a report of
+/// what kept it busy once `options.timeout` has passed.
+pub fn wait_for_idle(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    options: &IdleOptions,
+) -> Result<()> {
+    let clock = sys.clock();
+    let start = clock.now();
+    let mut quiet_since = start;
+    let mut previous = sample(sys, layout)?;
+    let mut previous_at = start;
+    let mut last_activity: Vec<String> = Vec::new();
+
+    loop {
+        clock.sleep(options.poll_interval);
+
+        let current = sample(sys, layout)?;
+        let now = clock.now();
+        let busy = activity(&previous, &current, now - previous_at, options);
+
+        if !busy.is_empty() {
+            info!("  Busy: {}", busy.join("; "));
+            quiet_since = now;
+            last_activity = busy;
+        }
+
+        if now - quiet_since >= options.window {
+            info!("  Quiet for {}", format_duration(options.window));
+            return Ok(());
+        }
+
+        if now - start > options.timeout {
+            anyhow::bail!(
+                "Dropbox did not stay idle for idle-window = {} within idle-timeout = {}. \
+                 Last activity {} ago: {}",
+                format_duration(options.window),
+                format_duration(options.timeout),
+                format_duration(now - quiet_since),
+                if last_activity.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    last_activity.join("; ")
+                }
+            );
+        }
+
+        previous = current;
+        previous_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn options() -> IdleOptions {
+        IdleOptions {
+            timeout: Duration::from_secs(20),
+            window: Duration::from_secs(6),
+            poll_interval: Duration::from_secs(2),
+            ..IdleOptions::default()
+        }
+    }
+
+    #[test]
+    fn parses_ps_cpu_times() {
+        // `ps -o pid=,time= -p 812,815,816,817`
+        let output = "  812   0:03.51\n  815  12:01.20\n  816 1:02:03.00\n  817 2-01:00:00.50\n";
+
+        let times = parse_cpu_times(output);
+
+        assert_eq!(times.len(), 4);
+        assert_eq!(times[&812], Duration::from_millis(3_510));
+        assert_eq!(times[&815], Duration::from_millis(721_200));
+        assert_eq!(times[&816], Duration::from_secs(3_723));
+        assert_eq!(times[&817], Duration::from_millis(176_400_500));
+    }
+
+    #[test]
+    fn skips_unparseable_ps_lines() {
+        let output = "  PID      TIME\n  812   0:01.00\n  815\nabc 0:01.00\n  816 n/a\n";
+
+        let times = parse_cpu_times(output);
+
+        assert_eq!(times.len(), 1);
+        assert_eq!(times[&812], Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parses_nettop_bytes_per_pid() {
+        // `nettop -P -L 1 -x -J bytes_in,bytes_out`
+        let output = indoc::indoc! {"
+            ,bytes_in,bytes_out,
+            launchd.1,0,0,
+            Dropbox.812,1048576,524288,
+            DropboxFileProvi.815,2048,1024,
+            com.apple.WebKi.901,300,12,
+        "};
+
+        let bytes = parse_network_bytes(output);
+
+        assert_eq!(bytes.len(), 4);
+        assert_eq!(bytes[&812], 1_572_864);
+        assert_eq!(bytes[&815], 3_072);
+        assert_eq!(bytes[&901], 312);
+        assert_eq!(bytes[&1], 0);
+    }
+
+    #[test]
+    fn sums_repeated_nettop_rows_for_a_pid() {
+        let output = "Dropbox.812,100,50,\nDropbox.812,10,5,\n";
+
+        assert_eq!(parse_network_bytes(output)[&812], 165);
+    }
+
+    #[test]
+    fn proceeds_once_dropbox_has_been_quiet_for_the_window() {
+        let mac = TestMac::new().with_scratch_file("chunk", 10);
+        let sys = running_dropbox();
+        // Only changes count, not what was used before the wait.
+        sys.set_cpu_time(100, Duration::from_secs(90));
+        sys.set_network_bytes(100, 5 * 1024 * 1024);
+
+        wait_for_idle(&sys, &mac.layout, &options()).unwrap();
+
+        assert_eq!(sys.fake_clock().elapsed(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn gives_up_reporting_the_last_activity_while_dropbox_stays_busy() {
+        let mac = TestMac::new();
+        let sys = running_dropbox();
+        // A second of CPU and a MiB of traffic every two-second poll.
+        for i in 1..=20u64 {
+            sys.at(Duration::from_secs(2 * i), move |sys| {
+                sys.set_cpu_time(100, Duration::from_secs(i));
+                sys.set_network_bytes(101, i * 1024 * 1024);
+            });
+        }
+
+        let error = wait_for_idle(&sys, &mac.layout, &options())
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            error.starts_with(
+                "Dropbox did not stay idle for idle-window = 6s within idle-timeout = 20s. \
+                 Last activity 0s ago: "
+            ),
+            "{}",
+            error
+        );
+        assert!(error.contains("PID 100 used 50.0% CPU"), "{}", error);
+        assert!(
+            error.contains("1048576 bytes of network traffic"),
+            "{}",
+            error
+        );
+        assert_eq!(sys.fake_clock().elapsed(), Duration::from_secs(22));
+    }
+
+    #[test]
+    fn a_scratch_files_change_restarts_the_window() {
+        let mac = TestMac::new().with_scratch_file("chunk", 10);
+        let sys = running_dropbox();
+        let scratch = mac.scratch_dir();
+        sys.at(Duration::from_secs(4), move |_| {
+            fs::write(scratch.join("another-chunk"), [0u8; 20]).unwrap();
+        });
+
+        wait_for_idle(&sys, &mac.layout, &options()).unwrap();
+
+        assert_eq!(sys.fake_clock().elapsed(), Duration::from_secs(10));
+    }
+}
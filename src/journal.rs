@@ -0,0 +1,544 @@
+use crate::backend::Signal;
+use crate::discovery::Layout;
+use crate::extensions::ExtensionState;
+use crate::launchagent::LaunchdJob;
+use crate::status::Status;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const JOURNAL_VERSION: u32 = 2;
+const JOURNAL_FILE_NAME: &str = "journal.json";
+
+/// A change made to the machine. `off` records these so that `on` can undo exactly
+/// that, and both commands use them to roll back after a failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Action {
+    QuitDropbox,
+    LaunchDropbox,
+    UnloadLaunchAgent {
+        job: LaunchdJob,
+    },
+    LoadLaunchAgent {
+        job: LaunchdJob,
+    },
+    RenameLaunchAgent {
+        job: LaunchdJob,
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// `launchctl disable` / `launchctl enable` on the job.
+    DisableLaunchAgentService {
+        job: LaunchdJob,
+    },
+    EnableLaunchAgentService {
+        job: LaunchdJob,
+    },
+    /// The `immutable` strategy's placeholder at the plist path.
+    CreateLaunchAgentPlaceholder {
+        job: LaunchdJob,
+    },
+    RemoveLaunchAgentPlaceholder {
+        job: LaunchdJob,
+    },
+    /// One of two conflicting plists was moved out of the way.
+    BackUpLaunchAgent {
+        job: LaunchdJob,
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// `path` is set when only that registration of the bundle ID was elected.
+    DisableExtension {
+        bundle_id: String,
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    EnableExtension {
+        bundle_id: String,
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    RestartFinder,
+    KillProcess {
+        pid: u32,
+        name: String,
+        #[serde(default)]
+        signal: Signal,
+    },
+}
+
+impl Action {
+    pub fn describe(&self) -> String {
+        match self {
+            Action::QuitDropbox => "asked Dropbox to quit".to_string(),
+            Action::LaunchDropbox => "launched Dropbox".to_string(),
+            Action::UnloadLaunchAgent { job } => format!("unloaded {}", job.label),
+            Action::LoadLaunchAgent { job } => format!("loaded {}", job.label),
+            Action::RenameLaunchAgent { from, to, .. } => {
+                format!("renamed {} → {}", from.display(), to.display())
+            }
+            Action::DisableLaunchAgentService { job } => {
+                format!("disabled {} in launchd's overrides", job.label)
+            }
+            Action::EnableLaunchAgentService { job } => {
+                format!("enabled {} in launchd's overrides", job.label)
+            }
+            Action::CreateLaunchAgentPlaceholder { job } => {
+                format!("locked a placeholder at {}", job.plist.display())
+            }
+            Action::RemoveLaunchAgentPlaceholder { job } => {
+                format!("removed the placeholder at {}", job.plist.display())
+            }
+            Action::BackUpLaunchAgent { from, to, .. } => {
+                format!("moved conflicting {} → {}", from.display(), to.display())
+            }
+            Action::DisableExtension { bundle_id, path } => match path {
+                Some(path) => format!("disabled extension {} at {}", bundle_id, path.display()),
+                None => format!("disabled extension {}", bundle_id),
+            },
+            Action::EnableExtension { bundle_id, path } => match path {
+                Some(path) => format!("enabled extension {} at {}", bundle_id, path.display()),
+                None => format!("enabled extension {}", bundle_id),
+            },
+            Action::RestartFinder => "restarted Finder".to_string(),
+            Action::KillProcess { pid, name, signal } => {
+                format!("sent SIG{} to PID {} ({})", signal.as_str(), pid, name)
+            }
+        }
+    }
+}
+
+/// What `off` did to one job, and so what `on` has to undo.
+#[derive(Debug, Clone)]
+pub struct LaunchAgentChanges {
+    pub job: LaunchdJob,
+    /// The plist was renamed to `.plist.disabled`.
+    pub renamed: bool,
+    pub unloaded: bool,
+    pub disabled_service: bool,
+    pub created_placeholder: bool,
+}
+
+impl LaunchAgentChanges {
+    fn new(job: LaunchdJob) -> Self {
+        Self {
+            job,
+            renamed: false,
+            unloaded: false,
+            disabled_service: false,
+            created_placeholder: false,
+        }
+    }
+
+    /// Everything `on` could undo, for when there is no journal to say what `off` did.
+    pub fn everything(job: LaunchdJob) -> Self {
+        Self {
+            job,
+            renamed: true,
+            unloaded: true,
+            disabled_service: true,
+            created_placeholder: true,
+        }
+    }
+}
+
+/// What `off` found and did, persisted under `~/Library/Application Support/droponoff`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub version: u32,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Status before `off` changed anything.
+    pub before: Status,
+    /// Every change `off` made, in order.
+    pub actions: Vec<Action>,
+    /// Status once `off` completed, or `None` if it never did.
+    pub after: Option<Status>,
+}
+
+impl Journal {
+    pub fn new(before: Status) -> Self {
+        Self {
+            version: JOURNAL_VERSION,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            before,
+            actions: Vec::new(),
+            after: None,
+        }
+    }
+
+    pub fn path(layout: &Layout) -> PathBuf {
+        layout.app_support_dir().join(JOURNAL_FILE_NAME)
+    }
+
+    pub fn load(layout: &Layout) -> Result<Option<Self>> {
+        let path = Self::path(layout);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents =
+            fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let journal: Journal = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse journal {}", path.display()))?;
+        if journal.version != JOURNAL_VERSION {
+            anyhow::bail!(
+                "Journal {} has unsupported version {} (expected {}); it was written by \
+                 another version of droponoff. Remove it and `on` restores every component",
+                path.display(),
+                journal.version,
+                JOURNAL_VERSION
+            );
+        }
+        Ok(Some(journal))
+    }
+
+    pub fn save(&self, layout: &Layout) -> Result<()> {
+        let path = Self::path(layout);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated journal.
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Drop every action after the first `len` and persist.
+    pub fn truncate(&mut self, layout: &Layout, len: usize) -> Result<()> {
+        self.actions.truncate(len);
+        self.save(layout)
+    }
+
+    pub fn remove(layout: &Layout) -> Result<()> {
+        let path = Self::path(layout);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Append `action` and persist immediately, so an interrupted `off` still leaves
+    /// an accurate record.
+    pub fn record(&mut self, layout: &Layout, action: Action) -> Result<()> {
+        self.actions.push(action);
+        self.save(layout)
+    }
+
+    /// Each extension `off` disabled, with the path of the registration it elected if
+    /// there were duplicates.
+    pub fn disabled_extensions(&self) -> Vec<(&str, Option<&Path>)> {
+        self.actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::DisableExtension { bundle_id, path } => {
+                    Some((bundle_id.as_str(), path.as_deref()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// What `off` did to each LaunchAgent and LaunchDaemon, in the order they were first
+    /// changed.
+    pub fn launch_agent_changes(&self) -> Vec<LaunchAgentChanges> {
+        let mut changes: Vec<LaunchAgentChanges> = Vec::new();
+        for action in &self.actions {
+            let job = match action {
+                Action::UnloadLaunchAgent { job }
+                | Action::RenameLaunchAgent { job, .. }
+                | Action::DisableLaunchAgentService { job }
+                | Action::CreateLaunchAgentPlaceholder { job }
+                | Action::BackUpLaunchAgent { job, .. } => job,
+                _ => continue,
+            };
+            let index = match changes.iter().position(|c| c.job.plist == job.plist) {
+                Some(index) => index,
+                None => {
+                    changes.push(LaunchAgentChanges::new(job.clone()));
+                    changes.len() - 1
+                }
+            };
+            let entry = &mut changes[index];
+            match action {
+                Action::UnloadLaunchAgent { .. } => entry.unloaded = true,
+                Action::RenameLaunchAgent { from, .. } => entry.renamed |= *from == job.plist,
+                // Keeping the `.disabled` copy of a conflict disables the job without a
+                // rename.
+                Action::BackUpLaunchAgent { from, .. } => entry.renamed |= *from == job.plist,
+                Action::DisableLaunchAgentService { .. } => entry.disabled_service = true,
+                Action::CreateLaunchAgentPlaceholder { .. } => entry.created_placeholder = true,
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    /// Whether `off` stopped Dropbox processes that `on` should start again.
+    pub fn stopped_dropbox(&self) -> bool {
+        !self.before.processes.is_empty()
+            && self
+                .actions
+                .iter()
+                .any(|a| matches!(a, Action::QuitDropbox | Action::KillProcess { .. }))
+    }
+
+    /// Differences between the state `off` left behind and `current`.
+    pub fn drift(&self, current: &Status) -> Vec<String> {
+        let Some(after) = &self.after else {
+            return vec!["`off` did not complete when the journal was written".to_string()];
+        };
+
+        let mut drift = Vec::new();
+
+        if after.dropbox_app_path != current.dropbox_app_path {
+            drift.push(format!(
+                "Dropbox.app moved from {:?} to {:?}",
+                after.dropbox_app_path, current.dropbox_app_path
+            ));
+        }
+
+        if !current.processes.is_empty() {
+            drift.push(format!(
+                "{} Dropbox process(es) started since `off`",
+                current.processes.len()
+            ));
+        }
+
+        for agent in &current.launch_agents {
+            let label = &agent.job.label;
+            let Some(previous) = after
+                .launch_agents
+                .iter()
+                .find(|a| a.job.plist == agent.job.plist)
+            else {
+                drift.push(format!("{} appeared since `off`", label));
+                continue;
+            };
+            if previous.state != agent.state {
+                drift.push(format!(
+                    "{} was {} after `off` but is now {}",
+                    label,
+                    previous.state.as_str(),
+                    agent.state.as_str()
+                ));
+            }
+            if !previous.runtime.loaded && agent.runtime.loaded {
+                drift.push(format!("{} was loaded into launchd since `off`", label));
+            }
+            if previous.launchd_override != agent.launchd_override {
+                drift.push(format!(
+                    "{} override was {} after `off` but is now {}",
+                    label,
+                    previous.launchd_override.as_str(),
+                    agent.launchd_override.as_str()
+                ));
+            }
+        }
+
+        for (bundle_id, state) in &current.extensions {
+            let Some((_, previous)) = after.extensions.iter().find(|(id, _)| id == bundle_id)
+            else {
+                continue;
+            };
+            let describe = |s: &ExtensionState| {
+                if !s.found {
+                    "not found"
+                } else if s.enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            };
+            if describe(previous) != describe(state) {
+                drift.push(format!(
+                    "extension {} was {} after `off` but is now {}",
+                    bundle_id,
+                    describe(previous),
+                    describe(state)
+                ));
+            }
+        }
+
+        drift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::launchagent::{self, JobKind};
+    use crate::status::get_status;
+    use crate::testutil::*;
+
+    fn agent_job(mac: &TestMac) -> LaunchdJob {
+        launchagent::discover_jobs(&mac.layout).unwrap().remove(0)
+    }
+
+    fn other_job(mac: &TestMac) -> LaunchdJob {
+        LaunchdJob {
+            label: "com.dropbox.other".to_string(),
+            plist: mac
+                .layout
+                .launch_agents_dir()
+                .join("com.dropbox.other.plist"),
+            kind: JobKind::UserAgent,
+            program: None,
+        }
+    }
+
+    #[test]
+    fn every_recorded_action_is_saved_immediately() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let mut journal = Journal::new(get_status(&sys, &mac.layout).unwrap());
+
+        journal.record(&mac.layout, Action::QuitDropbox).unwrap();
+        journal
+            .record(
+                &mac.layout,
+                Action::UnloadLaunchAgent {
+                    job: agent_job(&mac),
+                },
+            )
+            .unwrap();
+
+        let loaded = Journal::load(&mac.layout).unwrap().unwrap();
+        assert_eq!(loaded.actions, journal.actions);
+        assert_eq!(loaded.before.processes.len(), 2);
+        assert!(loaded.after.is_none());
+        assert!(!Journal::path(&mac.layout)
+            .with_extension("json.tmp")
+            .exists());
+
+        journal.truncate(&mac.layout, 1).unwrap();
+        let loaded = Journal::load(&mac.layout).unwrap().unwrap();
+        assert_eq!(loaded.actions, [Action::QuitDropbox]);
+
+        Journal::remove(&mac.layout).unwrap();
+        assert!(Journal::load(&mac.layout).unwrap().is_none());
+    }
+
+    #[test]
+    fn a_journal_from_another_version_is_refused() {
+        let mac = TestMac::new();
+        let mut journal = Journal::new(get_status(&FakeBackend::new(), &mac.layout).unwrap());
+        journal.version = JOURNAL_VERSION + 1;
+        journal.save(&mac.layout).unwrap();
+
+        let error = Journal::load(&mac.layout).unwrap_err();
+
+        assert!(
+            error.to_string().contains("unsupported version"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn launch_agent_changes_are_merged_per_job_in_order() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        let other = other_job(&mac);
+        let mut journal = Journal::new(get_status(&FakeBackend::new(), &mac.layout).unwrap());
+        journal.actions = vec![
+            Action::DisableLaunchAgentService { job: other.clone() },
+            Action::UnloadLaunchAgent { job: job.clone() },
+            Action::RenameLaunchAgent {
+                job: job.clone(),
+                from: job.plist.clone(),
+                to: job.disabled_path(),
+            },
+            // Moving the stale `.disabled` copy aside does not disable the job.
+            Action::BackUpLaunchAgent {
+                job: other.clone(),
+                from: other.disabled_path(),
+                to: other.disabled_path().with_extension("backup"),
+            },
+        ];
+
+        let changes = journal.launch_agent_changes();
+
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].job, other);
+        assert!(changes[0].disabled_service);
+        assert!(!changes[0].renamed && !changes[0].unloaded);
+        assert_eq!(changes[1].job, job);
+        assert!(changes[1].unloaded && changes[1].renamed);
+        assert!(!changes[1].disabled_service && !changes[1].created_placeholder);
+    }
+
+    #[test]
+    fn dropbox_was_stopped_only_if_it_ran_and_off_stopped_it() {
+        let mac = TestMac::new();
+        let running = get_status(&running_dropbox(), &mac.layout).unwrap();
+        let stopped = get_status(&FakeBackend::new(), &mac.layout).unwrap();
+        let kill = Action::KillProcess {
+            pid: 101,
+            name: FILEPROVIDER.to_string(),
+            signal: Signal::Kill,
+        };
+
+        for (before, actions, expected) in [
+            (&running, vec![Action::QuitDropbox], true),
+            (&running, vec![kill.clone()], true),
+            (&running, vec![Action::RestartFinder], false),
+            (&stopped, vec![Action::QuitDropbox, kill.clone()], false),
+        ] {
+            let mut journal = Journal::new(before.clone());
+            journal.actions = actions;
+
+            assert_eq!(journal.stopped_dropbox(), expected, "{:?}", journal.actions);
+        }
+    }
+
+    #[test]
+    fn drift_lists_what_changed_since_off() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let mut journal = Journal::new(get_status(&sys, &mac.layout).unwrap());
+        assert_eq!(
+            journal.drift(&get_status(&sys, &mac.layout).unwrap()),
+            ["`off` did not complete when the journal was written"]
+        );
+
+        let sys = FakeBackend::new()
+            .with_extension(FILEPROVIDER_EXTENSION, false)
+            .with_extension(GARCON_EXTENSION, false);
+        journal.after = Some(get_status(&sys, &mac.layout).unwrap());
+        assert_eq!(
+            journal.drift(&get_status(&sys, &mac.layout).unwrap()),
+            Vec::<String>::new()
+        );
+
+        let sys = running_dropbox();
+        let drift = journal.drift(&get_status(&sys, &mac.layout).unwrap());
+
+        assert_eq!(
+            drift,
+            [
+                "2 Dropbox process(es) started since `off`".to_string(),
+                format!("{} was loaded into launchd since `off`", AGENT),
+                format!(
+                    "extension {} was disabled after `off` but is now enabled",
+                    FILEPROVIDER_EXTENSION
+                ),
+                format!(
+                    "extension {} was disabled after `off` but is now enabled",
+                    GARCON_EXTENSION
+                ),
+            ]
+        );
+    }
+}
@@ -1,104 +1,456 @@
-use crate::discovery;
+use crate::backend::SystemBackend;
+use crate::discovery::Layout;
+use crate::state::Target;
 use anyhow::{Context, Result};
-use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CString;
 use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::info;
 
-fn get_user_id() -> Result<String> {
-    let output = cmd!("id", "-u")
-        .stdout_capture()
-        .run()
-        .context("Failed to get user ID")?;
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+/// What the `immutable` strategy leaves at the plist path. Not a valid plist, so launchd
+/// ignores it.
+const PLACEHOLDER_CONTENTS: &str = "droponoff placeholder: Dropbox's LaunchAgent is disabled. \
+                                    Run `droponoff on` to restore it.\n";
+
+const DISABLED_SUFFIX: &str = ".disabled";
+
+/// How `off` keeps the LaunchAgents from starting Dropbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LaunchAgentStrategy {
+    /// Rename the plist to `.plist.disabled`
+    #[default]
+    Rename,
+    /// Leave the plist in place and disable the service in launchd's persistent
+    /// override database (`launchctl disable`)
+    LaunchctlDisable,
+    /// Rename the plist and put a user-immutable placeholder in its place, so the
+    /// Dropbox updater cannot recreate it
+    Immutable,
+}
+
+impl LaunchAgentStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LaunchAgentStrategy::Rename => "rename",
+            LaunchAgentStrategy::LaunchctlDisable => "launchctl-disable",
+            LaunchAgentStrategy::Immutable => "immutable",
+        }
+    }
+}
+
+/// Which directory a job's plist lives in, which decides its launchd domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// `~/Library/LaunchAgents`
+    UserAgent,
+    /// `/Library/LaunchAgents`: loaded into every user's GUI session.
+    GlobalAgent,
+    /// `/Library/LaunchDaemons`: the system domain.
+    Daemon,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::UserAgent => "user-agent",
+            JobKind::GlobalAgent => "global-agent",
+            JobKind::Daemon => "daemon",
+        }
+    }
+}
+
+/// A Dropbox LaunchAgent or LaunchDaemon found on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaunchdJob {
+    pub label: String,
+    /// Where launchd looks for the plist. While disabled it is renamed to
+    /// [`LaunchdJob::disabled_path`].
+    pub plist: PathBuf,
+    pub kind: JobKind,
+    /// `Program`, or the first of `ProgramArguments`.
+    pub program: Option<String>,
 }
 
-pub fn unload_launch_agent() -> Result<()> {
-    let uid = get_user_id()?;
+impl LaunchdJob {
+    pub fn disabled_path(&self) -> PathBuf {
+        let mut path = self.plist.clone().into_os_string();
+        path.push(DISABLED_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    pub fn domain_target(&self, sys: &dyn SystemBackend) -> Result<String> {
+        Ok(match self.kind {
+            JobKind::UserAgent | JobKind::GlobalAgent => format!("gui/{}", sys.user_id()?),
+            JobKind::Daemon => "system".to_string(),
+        })
+    }
 
-    let service_target = format!("gui/{}/com.dropbox.DropboxMacUpdate.agent", uid);
+    pub fn service_target(&self, sys: &dyn SystemBackend) -> Result<String> {
+        Ok(format!("{}/{}", self.domain_target(sys)?, self.label))
+    }
+
+    /// Why `strategy` cannot be applied to this job by the current user, if it cannot.
+    /// Plists outside the home directory usually belong to root.
+    pub fn unchangeable_reason(
+        &self,
+        sys: &dyn SystemBackend,
+        strategy: LaunchAgentStrategy,
+    ) -> Result<Option<String>> {
+        let is_root = sys.user_id()? == "0";
+        let reason = match strategy {
+            LaunchAgentStrategy::LaunchctlDisable if self.kind == JobKind::Daemon && !is_root => {
+                Some("disabling a LaunchDaemon requires root".to_string())
+            }
+            LaunchAgentStrategy::LaunchctlDisable => None,
+            LaunchAgentStrategy::Rename | LaunchAgentStrategy::Immutable => {
+                let dir = self.plist.parent().unwrap_or(Path::new("/"));
+                (!is_writable(dir)).then(|| format!("{} is not writable", dir.display()))
+            }
+        };
+        Ok(reason)
+    }
+}
 
-    cmd!("launchctl", "bootout", &service_target)
-        .stdout_null()
-        .stderr_null()
-        .unchecked()
-        .run()
-        .context("Failed to unload LaunchAgent")?;
+fn is_writable(dir: &Path) -> bool {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid NUL-terminated string.
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
 
-    Ok(())
+/// The keys of a launchd plist that identify the job.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JobPlist {
+    label: String,
+    program: Option<String>,
+    program_arguments: Option<Vec<String>>,
 }
 
-pub fn load_launch_agent() -> Result<()> {
-    let path = discovery::get_launch_agent_path()?;
-    let uid = get_user_id()?;
+/// Every Dropbox job in the LaunchAgents and LaunchDaemons directories, enabled or
+/// renamed to `.plist.disabled`, sorted by label. A job counts as Dropbox's if its
+/// `Label` or program mentions Dropbox. Files that are not plists (including
+/// droponoff's placeholders) are skipped.
+pub fn discover_jobs(layout: &Layout) -> Result<Vec<LaunchdJob>> {
+    let dirs = [
+        (layout.launch_agents_dir(), JobKind::UserAgent),
+        (layout.global_launch_agents_dir(), JobKind::GlobalAgent),
+        (layout.launch_daemons_dir(), JobKind::Daemon),
+    ];
+
+    // Keyed by the enabled path, so a job found under both names is listed once.
+    let mut jobs = BTreeMap::new();
+    for (dir, kind) in dirs {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => anyhow::bail!("Failed to read {}: {}", dir.display(), e),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let plist = match name.strip_suffix(DISABLED_SUFFIX) {
+                Some(enabled_name) => dir.join(enabled_name),
+                None => path.clone(),
+            };
+            if !plist.to_string_lossy().ends_with(".plist") || jobs.contains_key(&plist) {
+                continue;
+            }
+            let Ok(parsed) = plist::from_file::<_, JobPlist>(&path) else {
+                continue;
+            };
+
+            let program = parsed
+                .program
+                .or_else(|| parsed.program_arguments.and_then(|a| a.into_iter().next()));
+            let mentions_dropbox = |s: &str| s.to_lowercase().contains("dropbox");
+            if !mentions_dropbox(&parsed.label) && !program.as_deref().is_some_and(mentions_dropbox)
+            {
+                continue;
+            }
+
+            jobs.insert(
+                plist.clone(),
+                LaunchdJob {
+                    label: parsed.label,
+                    plist,
+                    kind,
+                    program,
+                },
+            );
+        }
+    }
+
+    let mut jobs: Vec<LaunchdJob> = jobs.into_values().collect();
+    jobs.sort_by(|a, b| (a.kind, &a.label).cmp(&(b.kind, &b.label)));
+    Ok(jobs)
+}
+
+/// What launchd knows about a job at run time, as opposed to the plist files on disk.
+/// The two disagree when a job was loaded before its plist was renamed, or unloaded
+/// while its plist stayed in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobRuntime {
+    pub loaded: bool,
+    /// The job's running process, if any.
+    pub pid: Option<u32>,
+    /// How the last run ended: its exit status, or the negated signal number if it was
+    /// killed. `None` if it never exited.
+    pub last_exit_status: Option<i32>,
+    /// How many times launchd started the job since it was loaded.
+    pub runs: Option<u32>,
+    /// The program launchd runs.
+    pub program: Option<String>,
+}
+
+/// Parse `launchctl print <service_target>` output, whose top-level entries look like
+/// `\tpid = 123` (nested blocks such as `arguments = {` are skipped). Returns `None`
+/// if the output has no `state` entry: Apple documents the format as unstable, so
+/// that means it is no longer one this understands.
+fn parse_print(output: &str) -> Option<JobRuntime> {
+    let mut runtime = JobRuntime {
+        loaded: true,
+        ..JobRuntime::default()
+    };
+    let mut has_state = false;
+    let mut depth = 0usize;
+    for line in output.lines().map(str::trim) {
+        if line == "}" {
+            depth = depth.saturating_sub(1);
+            continue;
+        }
+        if depth == 1 {
+            if let Some((key, value)) = line.split_once(" = ") {
+                let number = || value.split(':').next().unwrap_or("").trim().parse().ok();
+                match key {
+                    "state" => has_state = true,
+                    "pid" => runtime.pid = value.parse().ok(),
+                    "runs" => runtime.runs = value.parse().ok(),
+                    "program" => runtime.program = Some(value.to_string()),
+                    // e.g. `78: EX_CONFIG`, or `(never exited)`
+                    "last exit code" => runtime.last_exit_status = number(),
+                    // e.g. `Terminated: 15`
+                    "last terminating signal" => {
+                        runtime.last_exit_status = value
+                            .rsplit(':')
+                            .next()
+                            .and_then(|n| n.trim().parse::<i32>().ok())
+                            .map(|signal| -signal)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if line.ends_with('{') {
+            depth += 1;
+        }
+    }
+    has_state.then_some(runtime)
+}
+
+/// Find `label` in `launchctl list` output, whose rows are `<pid or -> <status>
+/// <label>`, returning its PID and last exit status.
+fn parse_list(output: &str, label: &str) -> Option<(Option<u32>, Option<i32>)> {
+    output.lines().find_map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        match columns.as_slice() {
+            [pid, status, row_label] if *row_label == label => {
+                Some((pid.parse().ok(), status.parse().ok()))
+            }
+            _ => None,
+        }
+    })
+}
+
+pub fn get_job_runtime(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<JobRuntime> {
+    let Some(output) = sys.launchctl_print(&job.service_target(sys)?)? else {
+        return Ok(JobRuntime::default());
+    };
+    if let Some(runtime) = parse_print(&output) {
+        return Ok(runtime);
+    }
+
+    // `launchctl list` only covers the caller's own domain, but its format is stable.
+    let (pid, last_exit_status) = match job.kind {
+        JobKind::Daemon => (None, None),
+        JobKind::UserAgent | JobKind::GlobalAgent => {
+            parse_list(&sys.launchctl_list()?, &job.label).unwrap_or((None, None))
+        }
+    };
+    Ok(JobRuntime {
+        loaded: true,
+        pid,
+        last_exit_status,
+        ..JobRuntime::default()
+    })
+}
+
+/// Returns whether the job was unloaded (as opposed to not being loaded).
+pub fn unload_launch_agent(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    if !get_job_runtime(sys, job)?.loaded {
+        info!("  {} not loaded", job.label);
+        return Ok(false);
+    }
+
+    let service_target = job.service_target(sys)?;
+    sys.launchctl_bootout(&service_target)?;
+    if get_job_runtime(sys, job)?.loaded {
+        anyhow::bail!(
+            "{} is still loaded after `launchctl bootout {}` succeeded",
+            job.label,
+            service_target
+        );
+    }
+    info!("  launchctl bootout {}", service_target);
+    Ok(true)
+}
+
+/// Returns whether the job was loaded (as opposed to already being loaded).
+pub fn load_launch_agent(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    if !job.plist.exists() {
+        anyhow::bail!("{} plist not found at {:?}", job.label, job.plist);
+    }
+    if get_job_runtime(sys, job)?.loaded {
+        info!("  {} already loaded", job.label);
+        return Ok(false);
+    }
 
-    if !path.exists() {
-        anyhow::bail!("LaunchAgent plist not found at {:?}", path);
+    let domain_target = job.domain_target(sys)?;
+    sys.launchctl_bootstrap(&domain_target, &job.plist)?;
+    if !get_job_runtime(sys, job)?.loaded {
+        anyhow::bail!(
+            "{} is not loaded after `launchctl bootstrap {} {}` succeeded",
+            job.label,
+            domain_target,
+            job.plist.display()
+        );
     }
+    info!(
+        "  launchctl bootstrap {} {}",
+        domain_target,
+        job.plist.display()
+    );
+    Ok(true)
+}
 
-    let domain_target = format!("gui/{}", uid);
+/// Whether the plist path holds the real plist rather than nothing or a placeholder.
+fn live_plist_exists(job: &LaunchdJob) -> bool {
+    job.plist.exists() && !is_placeholder(&job.plist)
+}
 
-    cmd!("launchctl", "bootstrap", &domain_target, &path)
-        .stdout_null()
-        .stderr_null()
-        .unchecked()
-        .run()
-        .context("Failed to load LaunchAgent")?;
+/// Whether the `immutable` strategy's placeholder is at the plist path.
+pub fn has_placeholder(job: &LaunchdJob) -> bool {
+    is_placeholder(&job.plist)
+}
 
-    Ok(())
+fn is_placeholder(path: &Path) -> bool {
+    fs::read(path).is_ok_and(|contents| contents == PLACEHOLDER_CONTENTS.as_bytes())
 }
 
-pub fn disable_launch_agent() -> Result<()> {
-    let enabled_path = discovery::get_launch_agent_path()?;
-    let disabled_path = discovery::get_launch_agent_disabled_path()?;
+/// Returns whether the plist was renamed (as opposed to already being disabled).
+pub fn disable_launch_agent(job: &LaunchdJob) -> Result<bool> {
+    let enabled_path = &job.plist;
+    let disabled_path = job.disabled_path();
+
+    if live_plist_exists(job) && disabled_path.exists() {
+        anyhow::bail!(
+            "Both {:?} and {:?} exist; resolve the conflict first",
+            enabled_path,
+            disabled_path
+        );
+    }
 
     if disabled_path.exists() {
-        info!("  LaunchAgent already disabled");
-        return Ok(());
+        info!("  {} already disabled", job.label);
+        return Ok(false);
     }
 
-    if !enabled_path.exists() {
-        anyhow::bail!("LaunchAgent plist not found at {:?}", enabled_path);
+    if !live_plist_exists(job) {
+        anyhow::bail!("{} plist not found at {:?}", job.label, enabled_path);
     }
 
-    fs::rename(&enabled_path, &disabled_path).context("Failed to rename LaunchAgent plist")?;
+    fs::rename(enabled_path, &disabled_path)
+        .with_context(|| format!("Failed to rename {:?}", enabled_path))?;
     info!("  Renamed {:?} → {:?}", enabled_path, disabled_path);
-    Ok(())
+    Ok(true)
 }
 
-pub fn enable_launch_agent() -> Result<()> {
-    let enabled_path = discovery::get_launch_agent_path()?;
-    let disabled_path = discovery::get_launch_agent_disabled_path()?;
+/// Returns whether the plist was renamed (as opposed to already being enabled).
+pub fn enable_launch_agent(job: &LaunchdJob) -> Result<bool> {
+    let enabled_path = &job.plist;
+    let disabled_path = job.disabled_path();
+
+    if is_placeholder(enabled_path) {
+        anyhow::bail!(
+            "{:?} is droponoff's placeholder; remove it first",
+            enabled_path
+        );
+    }
+
+    if enabled_path.exists() && disabled_path.exists() {
+        anyhow::bail!(
+            "Both {:?} and {:?} exist; resolve the conflict first",
+            enabled_path,
+            disabled_path
+        );
+    }
 
     if enabled_path.exists() {
-        info!("  LaunchAgent already enabled");
-        return Ok(());
+        info!("  {} already enabled", job.label);
+        return Ok(false);
     }
 
     if !disabled_path.exists() {
         anyhow::bail!(
-            "LaunchAgent plist not found at {:?} or {:?}",
+            "{} plist not found at {:?} or {:?}",
+            job.label,
             enabled_path,
             disabled_path
         );
     }
 
-    fs::rename(&disabled_path, &enabled_path).context("Failed to rename LaunchAgent plist")?;
+    fs::rename(&disabled_path, enabled_path)
+        .with_context(|| format!("Failed to rename {:?}", disabled_path))?;
     info!("  Renamed {:?} → {:?}", disabled_path, enabled_path);
-    Ok(())
+    Ok(true)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LaunchAgentState {
     Enabled,
     Disabled,
     Missing,
+    /// Both the plist and its `.disabled` copy exist, typically because the Dropbox
+    /// updater recreated the plist after `off`. launchd loads the plist.
+    Conflict,
 }
 
-pub fn get_launch_agent_state() -> Result<LaunchAgentState> {
-    let enabled_path = discovery::get_launch_agent_path()?;
-    let disabled_path = discovery::get_launch_agent_disabled_path()?;
+impl LaunchAgentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LaunchAgentState::Enabled => "enabled",
+            LaunchAgentState::Disabled => "disabled",
+            LaunchAgentState::Missing => "missing",
+            LaunchAgentState::Conflict => "conflict",
+        }
+    }
+}
 
-    if enabled_path.exists() {
+pub fn get_launch_agent_state(job: &LaunchdJob) -> Result<LaunchAgentState> {
+    let disabled_path = job.disabled_path();
+
+    let enabled = live_plist_exists(job);
+
+    if enabled && disabled_path.exists() {
+        Ok(LaunchAgentState::Conflict)
+    } else if enabled {
         Ok(LaunchAgentState::Enabled)
     } else if disabled_path.exists() {
         Ok(LaunchAgentState::Disabled)
@@ -106,3 +458,613 @@ pub fn get_launch_agent_state() -> Result<LaunchAgentState> {
         Ok(LaunchAgentState::Missing)
     }
 }
+
+/// A job's entry in launchd's persistent override database, which launchd consults
+/// before loading the plist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchdOverride {
+    /// No entry: launchd loads the plist if it exists.
+    #[default]
+    Unset,
+    Enabled,
+    Disabled,
+}
+
+impl LaunchdOverride {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LaunchdOverride::Unset => "unset",
+            LaunchdOverride::Enabled => "enabled",
+            LaunchdOverride::Disabled => "disabled",
+        }
+    }
+}
+
+/// Find `label` in `launchctl print-disabled` output, whose entries look like
+/// `"<label>" => disabled` (or `=> true` before macOS 10.15).
+fn parse_override(output: &str, label: &str) -> LaunchdOverride {
+    let quoted_label = format!("\"{}\"", label);
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once("=>"))
+        .find(|(key, _)| key.trim() == quoted_label)
+        .map(|(_, value)| match value.trim() {
+            "disabled" | "true" => LaunchdOverride::Disabled,
+            _ => LaunchdOverride::Enabled,
+        })
+        .unwrap_or(LaunchdOverride::Unset)
+}
+
+pub fn get_launch_agent_override(
+    sys: &dyn SystemBackend,
+    job: &LaunchdJob,
+) -> Result<LaunchdOverride> {
+    let output = sys.launchctl_print_disabled(&job.domain_target(sys)?)?;
+    Ok(parse_override(&output, &job.label))
+}
+
+/// Returns whether the override was changed (as opposed to already being disabled).
+pub fn disable_service(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    if get_launch_agent_override(sys, job)? == LaunchdOverride::Disabled {
+        info!("  {} already disabled in launchd", job.label);
+        return Ok(false);
+    }
+    let service_target = job.service_target(sys)?;
+    sys.launchctl_disable(&service_target)?;
+    info!("  launchctl disable {}", service_target);
+    Ok(true)
+}
+
+/// Returns whether the override was changed (as opposed to not being disabled).
+pub fn enable_service(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    if get_launch_agent_override(sys, job)? != LaunchdOverride::Disabled {
+        info!("  {} not disabled in launchd", job.label);
+        return Ok(false);
+    }
+    let service_target = job.service_target(sys)?;
+    sys.launchctl_enable(&service_target)?;
+    info!("  launchctl enable {}", service_target);
+    Ok(true)
+}
+
+/// Put a user-immutable placeholder at the plist path, which must be free. Returns
+/// whether one was created (as opposed to already being there).
+pub fn create_placeholder(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    let path = &job.plist;
+    if is_placeholder(path) {
+        info!("  Placeholder already in place at {:?}", path);
+        return Ok(false);
+    }
+    if path.exists() {
+        anyhow::bail!("Cannot create a placeholder: {:?} exists", path);
+    }
+
+    fs::write(path, PLACEHOLDER_CONTENTS).with_context(|| format!("Failed to write {:?}", path))?;
+    sys.set_immutable(path, true)?;
+    info!("  Locked placeholder at {:?}", path);
+    Ok(true)
+}
+
+/// Returns whether a placeholder was removed (as opposed to there being none).
+pub fn remove_placeholder(sys: &dyn SystemBackend, job: &LaunchdJob) -> Result<bool> {
+    let path = &job.plist;
+    if !is_placeholder(path) {
+        return Ok(false);
+    }
+
+    sys.set_immutable(path, false)?;
+    fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))?;
+    info!("  Removed placeholder at {:?}", path);
+    Ok(true)
+}
+
+/// How a [`LaunchAgentState::Conflict`] is resolved: `keep` stays where it is and
+/// `discard` is moved to `backup`.
+#[derive(Debug, Clone)]
+pub struct ConflictResolution {
+    pub keep: PathBuf,
+    pub discard: PathBuf,
+    pub backup: PathBuf,
+    pub reason: String,
+}
+
+/// Decide how to resolve a conflict, or `None` if there is none. Identical copies keep
+/// the one already in place for `target`, so no rename is needed afterwards. Otherwise
+/// the most recently modified copy wins, and the live plist wins a tie since that is
+/// what launchd would load.
+pub fn plan_conflict_resolution(
+    layout: &Layout,
+    job: &LaunchdJob,
+    target: Target,
+) -> Result<Option<ConflictResolution>> {
+    if get_launch_agent_state(job)? != LaunchAgentState::Conflict {
+        return Ok(None);
+    }
+
+    let enabled_path = job.plist.clone();
+    let disabled_path = job.disabled_path();
+    let read = |path: &Path| -> Result<(Vec<u8>, SystemTime)> {
+        let contents = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let modified = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("Failed to read the modification time of {:?}", path))?;
+        Ok((contents, modified))
+    };
+    let (enabled_contents, enabled_modified) = read(&enabled_path)?;
+    let (disabled_contents, disabled_modified) = read(&disabled_path)?;
+
+    let (keep_enabled, reason) = if enabled_contents == disabled_contents {
+        (target == Target::On, "the copies are identical".to_string())
+    } else if disabled_modified > enabled_modified {
+        (false, "the .disabled copy is newer".to_string())
+    } else if enabled_modified > disabled_modified {
+        (true, "the live plist is newer".to_string())
+    } else {
+        (
+            true,
+            "the copies differ but were modified at the same time; launchd loads the live \
+             plist"
+                .to_string(),
+        )
+    };
+    let (keep, discard) = if keep_enabled {
+        (enabled_path, disabled_path)
+    } else {
+        (disabled_path, enabled_path)
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file_name = discard
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let backup = layout
+        .app_support_dir()
+        .join("backups")
+        .join(format!("{}.{}", file_name, timestamp));
+
+    Ok(Some(ConflictResolution {
+        keep,
+        discard,
+        backup,
+        reason,
+    }))
+}
+
+/// Resolve a conflict as [`plan_conflict_resolution`] describes, returning what was
+/// done, or `None` if there was no conflict.
+pub fn resolve_conflict(
+    layout: &Layout,
+    job: &LaunchdJob,
+    target: Target,
+) -> Result<Option<ConflictResolution>> {
+    let Some(resolution) = plan_conflict_resolution(layout, job, target)? else {
+        return Ok(None);
+    };
+
+    let backup_dir = resolution.backup.parent().unwrap_or(Path::new("/"));
+    fs::create_dir_all(backup_dir)
+        .with_context(|| format!("Failed to create {}", backup_dir.display()))?;
+    fs::rename(&resolution.discard, &resolution.backup).with_context(|| {
+        format!(
+            "Failed to move {:?} to {:?}",
+            resolution.discard, resolution.backup
+        )
+    })?;
+    info!(
+        "  Kept {:?} ({}); moved {:?} → {:?}",
+        resolution.keep, resolution.reason, resolution.discard, resolution.backup
+    );
+    Ok(Some(resolution))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::testutil::*;
+
+    fn agent_job(mac: &TestMac) -> LaunchdJob {
+        let jobs = discover_jobs(&mac.layout).unwrap();
+        assert_eq!(jobs.len(), 1);
+        jobs.into_iter().next().unwrap()
+    }
+
+    /// A launchd plist with `label` and `keys`, as XML or in the binary format.
+    fn write_job_plist(path: &Path, label: &str, keys: &[(&str, plist::Value)], binary: bool) {
+        let mut dict = plist::Dictionary::new();
+        dict.insert("Label".to_string(), label.into());
+        for (key, value) in keys {
+            dict.insert(key.to_string(), value.clone());
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let value = plist::Value::Dictionary(dict);
+        if binary {
+            value.to_file_binary(path).unwrap();
+        } else {
+            value.to_file_xml(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn discovers_dropbox_jobs_in_every_launchd_directory() {
+        let mac = TestMac::new().with_agent();
+        let layout = &mac.layout;
+        let helper = "/Applications/Dropbox.app/Contents/MacOS/DropboxHelper";
+        // Found by its program, not its label, and stored in the binary format.
+        write_job_plist(
+            &layout
+                .global_launch_agents_dir()
+                .join("com.example.sync.plist"),
+            "com.example.sync",
+            &[(
+                "ProgramArguments",
+                plist::Value::Array(vec![helper.into(), "--background".into()]),
+            )],
+            true,
+        );
+        write_job_plist(
+            &layout
+                .launch_daemons_dir()
+                .join("com.getdropbox.dropbox.helperd.plist.disabled"),
+            "com.getdropbox.dropbox.helperd",
+            &[("Program", "/Library/DropboxHelperTools/dbfseventsd".into())],
+            false,
+        );
+        // Not Dropbox's, not a plist, and not parseable.
+        write_job_plist(
+            &layout.launch_daemons_dir().join("com.example.backup.plist"),
+            "com.example.backup",
+            &[("Program", "/usr/local/bin/backup".into())],
+            true,
+        );
+        fs::write(layout.launch_agents_dir().join("Dropbox.txt"), "notes").unwrap();
+        fs::write(
+            layout.launch_agents_dir().join("com.dropbox.broken.plist"),
+            "not a plist",
+        )
+        .unwrap();
+
+        let jobs = discover_jobs(layout).unwrap();
+
+        let found: Vec<(JobKind, &str, Option<&str>, PathBuf)> = jobs
+            .iter()
+            .map(|j| {
+                (
+                    j.kind,
+                    j.label.as_str(),
+                    j.program.as_deref(),
+                    j.plist.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (JobKind::UserAgent, AGENT, Some(UPDATER), mac.agent_plist()),
+                (
+                    JobKind::GlobalAgent,
+                    "com.example.sync",
+                    Some(helper),
+                    layout
+                        .global_launch_agents_dir()
+                        .join("com.example.sync.plist")
+                ),
+                (
+                    JobKind::Daemon,
+                    "com.getdropbox.dropbox.helperd",
+                    Some("/Library/DropboxHelperTools/dbfseventsd"),
+                    layout
+                        .launch_daemons_dir()
+                        .join("com.getdropbox.dropbox.helperd.plist")
+                ),
+            ]
+        );
+        assert_eq!(
+            get_launch_agent_state(&jobs[2]).unwrap(),
+            LaunchAgentState::Disabled
+        );
+    }
+
+    #[test]
+    fn disabling_and_enabling_renames_the_plist_beneath_the_root() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        assert_eq!(job.label, AGENT);
+        assert_eq!(job.plist, mac.agent_plist());
+        assert_eq!(job.program.as_deref(), Some(UPDATER));
+
+        assert!(disable_launch_agent(&job).unwrap());
+        assert!(!job.plist.exists());
+        assert_eq!(
+            fs::read_to_string(job.disabled_path()).unwrap(),
+            agent_plist_contents()
+        );
+        assert_eq!(
+            get_launch_agent_state(&job).unwrap(),
+            LaunchAgentState::Disabled
+        );
+        // The renamed plist is still discovered as the same job.
+        assert_eq!(agent_job(&mac).plist, job.plist);
+        assert!(!disable_launch_agent(&job).unwrap());
+
+        assert!(enable_launch_agent(&job).unwrap());
+        assert!(!job.disabled_path().exists());
+        assert_eq!(
+            get_launch_agent_state(&job).unwrap(),
+            LaunchAgentState::Enabled
+        );
+        assert!(!enable_launch_agent(&job).unwrap());
+    }
+
+    /// `launchctl print gui/501/com.dropbox.DropboxMacUpdate.agent` while the updater
+    /// runs.
+    const PRINT_RUNNING: &str = indoc::indoc! {"
+        gui/501/com.dropbox.DropboxMacUpdate.agent = {
+        \tactive count = 1
+        \tpath = /Users/me/Library/LaunchAgents/com.dropbox.DropboxMacUpdate.agent.plist
+        \ttype = LaunchAgent
+        \tstate = running
+
+        \tprogram = /Library/DropboxHelperTools/DropboxMacUpdate
+        \targuments = {
+        \t\t/Library/DropboxHelperTools/DropboxMacUpdate
+        \t\t-check
+        \t}
+
+        \tdefault environment = {
+        \t\tPATH => /usr/bin:/bin:/usr/sbin:/sbin
+        \t}
+
+        \tdomain = gui/501 [100005]
+        \tasid = 100005
+        \tminimum runtime = 10
+        \texit timeout = 5
+        \truns = 4
+        \tpid = 2817
+        \timmediate reason = interval
+        \tforks = 0
+        \texecs = 1
+        \tinitialized = 1
+        \ttrampolined = 1
+        \tstarted suspended = 0
+        \tproxy started suspended = 0
+        \tlast exit code = 0
+
+        \tspawn type = daemon (3)
+        \tjetsam priority = 40
+        }
+    "};
+
+    /// The same job loaded but waiting for its next interval, after being killed.
+    const PRINT_WAITING: &str = indoc::indoc! {"
+        gui/501/com.dropbox.DropboxMacUpdate.agent = {
+        \tactive count = 0
+        \tpath = /Users/me/Library/LaunchAgents/com.dropbox.DropboxMacUpdate.agent.plist
+        \tstate = not running
+
+        \tprogram = /Library/DropboxHelperTools/DropboxMacUpdate
+        \targuments = {
+        \t\tpid = 1
+        \t}
+
+        \truns = 5
+        \tlast terminating signal = Terminated: 15
+        }
+    "};
+
+    /// `launchctl list` with the updater running, another agent loaded but not
+    /// running, and one that last exited with an error.
+    const LIST: &str = indoc::indoc! {"
+        PID\tStatus\tLabel
+        2817\t0\tcom.dropbox.DropboxMacUpdate.agent
+        -\t0\tcom.dropbox.dropboxmacupdate.xpcservice
+        -\t78\tcom.getdropbox.dropbox.garcon
+        391\t-9\tcom.apple.Finder
+    "};
+
+    #[test]
+    fn parses_a_running_job_from_launchctl_print() {
+        assert_eq!(
+            parse_print(PRINT_RUNNING),
+            Some(JobRuntime {
+                loaded: true,
+                pid: Some(2817),
+                last_exit_status: Some(0),
+                runs: Some(4),
+                program: Some(UPDATER.to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_a_loaded_job_without_a_process_from_launchctl_print() {
+        // The `pid = 1` nested in `arguments` is not the job's.
+        assert_eq!(
+            parse_print(PRINT_WAITING),
+            Some(JobRuntime {
+                loaded: true,
+                pid: None,
+                last_exit_status: Some(-15),
+                runs: Some(5),
+                program: Some(UPDATER.to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn launchctl_print_output_without_a_state_is_not_understood() {
+        assert_eq!(parse_print(""), None);
+        assert_eq!(
+            parse_print("gui/501/com.dropbox.DropboxMacUpdate.agent = {\n\tpid = 2817\n}\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn finds_jobs_in_launchctl_list() {
+        assert_eq!(parse_list(LIST, AGENT), Some((Some(2817), Some(0))));
+        assert_eq!(
+            parse_list(LIST, "com.dropbox.dropboxmacupdate.xpcservice"),
+            Some((None, Some(0)))
+        );
+        assert_eq!(
+            parse_list(LIST, "com.getdropbox.dropbox.garcon"),
+            Some((None, Some(78)))
+        );
+        assert_eq!(parse_list(LIST, "com.dropbox.missing"), None);
+        // Labels are matched exactly, not by prefix.
+        assert_eq!(parse_list(LIST, "com.dropbox.DropboxMacUpdate"), None);
+    }
+
+    #[test]
+    fn job_runtime_falls_back_to_launchctl_list() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        let sys = FakeBackend::new();
+
+        assert_eq!(get_job_runtime(&sys, &job).unwrap(), JobRuntime::default());
+
+        sys.script(
+            "launchctl_print",
+            Ok("a format from the future".to_string()),
+        );
+        sys.script("launchctl_list", Ok(LIST.to_string()));
+        assert_eq!(
+            get_job_runtime(&sys, &job).unwrap(),
+            JobRuntime {
+                loaded: true,
+                pid: Some(2817),
+                last_exit_status: Some(0),
+                ..JobRuntime::default()
+            }
+        );
+    }
+
+    fn set_modified(path: &Path, seconds: u64) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    /// The agent with a `.disabled` copy whose contents differ, modified at the given
+    /// times.
+    fn conflicting_agent(live_modified: u64, disabled_modified: u64) -> (TestMac, LaunchdJob) {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        fs::write(
+            job.disabled_path(),
+            agent_plist_contents().replace("</dict>", "<key>RunAtLoad</key><true/></dict>"),
+        )
+        .unwrap();
+        set_modified(&job.plist, live_modified);
+        set_modified(&job.disabled_path(), disabled_modified);
+        (mac, job)
+    }
+
+    #[test]
+    fn conflict_resolution_keeps_the_newer_copy() {
+        let (mac, job) = conflicting_agent(1_000, 2_000);
+        let resolution = plan_conflict_resolution(&mac.layout, &job, Target::Off)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolution.keep, job.disabled_path());
+        assert_eq!(resolution.discard, job.plist);
+        assert_eq!(resolution.reason, "the .disabled copy is newer");
+        assert!(resolution
+            .backup
+            .starts_with(mac.layout.app_support_dir().join("backups")));
+
+        let (mac, job) = conflicting_agent(2_000, 1_000);
+        let resolution = plan_conflict_resolution(&mac.layout, &job, Target::Off)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolution.keep, job.plist);
+        assert_eq!(resolution.reason, "the live plist is newer");
+
+        let (mac, job) = conflicting_agent(1_000, 1_000);
+        let resolution = plan_conflict_resolution(&mac.layout, &job, Target::On)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolution.keep, job.plist);
+    }
+
+    #[test]
+    fn conflict_resolution_keeps_identical_copies_where_the_target_wants_them() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        fs::copy(&job.plist, job.disabled_path()).unwrap();
+        set_modified(&job.disabled_path(), 2_000);
+
+        for (target, keep) in [
+            (Target::Off, job.disabled_path()),
+            (Target::On, job.plist.clone()),
+        ] {
+            let resolution = plan_conflict_resolution(&mac.layout, &job, target)
+                .unwrap()
+                .unwrap();
+            assert_eq!(resolution.keep, keep);
+            assert_eq!(resolution.reason, "the copies are identical");
+        }
+    }
+
+    #[test]
+    fn conflict_resolution_needs_both_copies() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        assert!(plan_conflict_resolution(&mac.layout, &job, Target::Off)
+            .unwrap()
+            .is_none());
+
+        disable_launch_agent(&job).unwrap();
+        assert!(plan_conflict_resolution(&mac.layout, &job, Target::On)
+            .unwrap()
+            .is_none());
+
+        fs::remove_file(job.disabled_path()).unwrap();
+        assert!(plan_conflict_resolution(&mac.layout, &job, Target::On)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn resolving_a_conflict_backs_up_the_discarded_copy() {
+        let (mac, job) = conflicting_agent(1_000, 2_000);
+        let discarded = fs::read(&job.plist).unwrap();
+
+        let resolution = resolve_conflict(&mac.layout, &job, Target::Off)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            get_launch_agent_state(&job).unwrap(),
+            LaunchAgentState::Disabled
+        );
+        assert_eq!(fs::read(&resolution.backup).unwrap(), discarded);
+        assert!(resolve_conflict(&mac.layout, &job, Target::Off)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn renaming_refuses_when_both_copies_exist() {
+        let mac = TestMac::new().with_agent();
+        let job = agent_job(&mac);
+        fs::copy(&job.plist, job.disabled_path()).unwrap();
+
+        assert_eq!(
+            get_launch_agent_state(&job).unwrap(),
+            LaunchAgentState::Conflict
+        );
+        assert!(disable_launch_agent(&job).is_err());
+        assert!(enable_launch_agent(&job).is_err());
+        assert!(job.plist.exists() && job.disabled_path().exists());
+    }
+}
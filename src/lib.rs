@@ -0,0 +1,21 @@
+pub mod backend;
+pub mod commands;
+pub mod config;
+pub mod discovery;
+pub mod extensions;
+pub mod finder;
+pub mod idle;
+pub mod journal;
+pub mod launchagent;
+pub mod lock;
+pub mod logging;
+pub mod plan;
+pub mod processes;
+pub mod reconcile;
+pub mod repair;
+pub mod state;
+pub mod status;
+#[cfg(test)]
+mod testutil;
+pub mod transaction;
+pub mod verify;
@@ -0,0 +1,172 @@
+//! An advisory lock held by every command that changes the machine, so that two runs
+//! (say a manual `on` and a scheduled `off`) cannot interleave their renames and
+//! `pluginkit` calls. The lock is an `flock` on a file in the Application Support
+//! directory; the kernel releases it when the holder exits, however it exits.
+
+use crate::discovery::Layout;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const LOCK_FILE_NAME: &str = "droponoff.lock";
+
+/// Who holds the lock, written into the lock file for the error shown to other runs.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    command: String,
+}
+
+impl Holder {
+    fn describe(holder: &Option<Holder>) -> String {
+        match holder {
+            Some(holder) => format!("PID {} (`{}`)", holder.pid, holder.command),
+            None => "another process".to_string(),
+        }
+    }
+}
+
+/// Held until dropped.
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    pub fn path(layout: &Layout) -> PathBuf {
+        layout.app_support_dir().join(LOCK_FILE_NAME)
+    }
+
+    /// Take the lock for `command`. If another run holds it, fail naming that run, or
+    /// with `wait` block until it is released.
+    pub fn acquire(layout: &Layout, command: &str, wait: bool) -> Result<Self> {
+        let path = Self::path(layout);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        // Never truncated on open: the current holder's details must survive until we
+        // have the lock ourselves.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        if !flock(&file, libc::LOCK_EX | libc::LOCK_NB)
+            .with_context(|| format!("Failed to lock {}", path.display()))?
+        {
+            let holder = Holder::describe(&read_holder(&mut file));
+            if !wait {
+                anyhow::bail!(
+                    "Another droponoff run is in progress: {} holds {}. Wait for it to \
+                     finish, or pass --wait-lock to wait automatically",
+                    holder,
+                    path.display()
+                );
+            }
+            info!("Waiting for {} to release {}", holder, path.display());
+            flock(&file, libc::LOCK_EX)
+                .with_context(|| format!("Failed to lock {}", path.display()))?;
+        }
+
+        let holder = Holder {
+            pid: std::process::id(),
+            command: command.to_string(),
+        };
+        let contents = serde_json::to_vec(&holder)?;
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| file.write_all(&contents))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// `false` if the lock is held elsewhere and `LOCK_NB` was given.
+fn flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        // SAFETY: the descriptor is valid for as long as `file` is borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            _ => return Err(error),
+        }
+    }
+}
+
+/// `None` if the holder has not written its details yet.
+fn read_holder(file: &mut File) -> Option<Holder> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestMac;
+    use std::time::Duration;
+
+    fn holder(layout: &Layout) -> Holder {
+        let contents = fs::read_to_string(RunLock::path(layout)).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn a_second_run_fails_naming_the_holder() {
+        let mac = TestMac::new();
+        let _lock = RunLock::acquire(&mac.layout, "off", false).unwrap();
+
+        let error = RunLock::acquire(&mac.layout, "on", false)
+            .err()
+            .expect("lock is held");
+
+        let message = error.to_string();
+        assert!(
+            message.contains(&format!("PID {} (`off`)", std::process::id())),
+            "{}",
+            message
+        );
+        assert!(message.contains("--wait-lock"), "{}", message);
+        // Opening the file to find the holder must not have erased it.
+        assert_eq!(holder(&mac.layout).command, "off");
+        assert_eq!(holder(&mac.layout).pid, std::process::id());
+    }
+
+    #[test]
+    fn the_lock_is_released_when_dropped() {
+        let mac = TestMac::new();
+        drop(RunLock::acquire(&mac.layout, "off", false).unwrap());
+
+        let _lock = RunLock::acquire(&mac.layout, "on", false).unwrap();
+
+        assert_eq!(holder(&mac.layout).command, "on");
+    }
+
+    #[test]
+    fn waiting_takes_the_lock_once_it_is_released() {
+        let mac = TestMac::new();
+        let lock = RunLock::acquire(&mac.layout, "off", false).unwrap();
+
+        let layout = mac.layout.clone();
+        let waiter =
+            std::thread::spawn(move || RunLock::acquire(&layout, "on", true).map(|_| ()).is_ok());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        drop(lock);
+
+        assert!(waiter.join().unwrap());
+        assert_eq!(holder(&mac.layout).command, "on");
+    }
+}
@@ -77,8 +77,11 @@ fn add_emoji_prefix(message: &str, level: &Level) -> String {
     }
 }
 
+/// Log to standard error, keeping standard output for machine-readable output such as
+/// `status --format json` and `--report-json`.
 pub fn init_logging() {
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_target(false)
         .with_level(false)
         .without_time()
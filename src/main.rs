@@ -1,23 +1,45 @@
-#[cfg(not(target_os = "macos"))]
-compile_error!("droponoff only works on macOS");
-
-mod discovery;
-mod extensions;
-mod finder;
-mod launchagent;
-mod logging;
-mod processes;
-mod status;
-
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use droponoff::backend::MacOsBackend;
+use droponoff::commands::{
+    cmd_explain, cmd_nuke_scratch, cmd_off, cmd_on, cmd_repair, cmd_set, cmd_status, OffOptions,
+    OnOptions, SetOptions,
+};
+use droponoff::config::{resolve_launch_agent_strategy, resolve_timings, TimingOverrides};
+use droponoff::discovery::Layout;
+use droponoff::idle::IdleOptions;
+use droponoff::launchagent::LaunchAgentStrategy;
+use droponoff::lock::RunLock;
+use droponoff::logging;
+use droponoff::processes::TerminationPolicy;
+use droponoff::reconcile::DesiredState;
+use droponoff::state::Target;
+use droponoff::status::StatusFormat;
 use indoc::indoc;
-use tracing::{error, info, warn};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::error;
 
 #[derive(Parser)]
 #[command(name = "droponoff")]
 #[command(about = "A reversible kill switch for Dropbox on macOS")]
 struct Cli {
+    /// Resolve all filesystem paths beneath this directory instead of `/` (e.g. a
+    /// mounted disk image or a Time Machine restore)
+    #[arg(long, global = true, env = "DROPONOFF_ROOT", value_name = "DIR")]
+    root: Option<PathBuf>,
+
+    /// Read settings from this file instead of
+    /// ~/Library/Application Support/droponoff/config.toml
+    #[arg(long, global = true, env = "DROPONOFF_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// If another droponoff run is changing things, wait for it to finish instead of
+    /// failing
+    #[arg(long, global = true)]
+    wait_lock: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,11 +47,117 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Restore Dropbox to normal operation
-    On,
-    /// Disable Dropbox completely (DOES NOT WAIT FOR SYNCHRONIZATION TO FINISH).
-    Off,
-    /// Show current Dropbox state (read-only)
-    Status,
+    On {
+        /// On failure, leave completed changes in place instead of undoing them
+        #[arg(long)]
+        no_rollback: bool,
+        /// Print the ordered plan without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the verification report as JSON on standard output, whether or not it
+        /// passes
+        #[arg(long)]
+        report_json: bool,
+        #[command(flatten)]
+        timing: TimingOverrides,
+    },
+    /// Disable Dropbox completely (DOES NOT WAIT FOR SYNCHRONIZATION TO FINISH unless
+    /// --wait-for-idle is given).
+    Off {
+        /// On failure, leave completed changes in place instead of undoing them
+        #[arg(long)]
+        no_rollback: bool,
+        /// Print the ordered plan without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the verification report as JSON on standard output, whether or not it
+        /// passes
+        #[arg(long)]
+        report_json: bool,
+        /// Before changing anything, wait up to DURATION for Dropbox's CPU, network and
+        /// scratch_files activity to go quiet, and abort if it does not [default: 10m,
+        /// or `idle-timeout` in the config file's [timing] table]
+        #[arg(long, value_name = "DURATION", num_args = 0..=1, require_equals = true, value_parser = humantime::parse_duration)]
+        wait_for_idle: Option<Option<Duration>>,
+        /// How long activity must stay quiet for --wait-for-idle, e.g. `1m` [default: 30s]
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        idle_window: Option<Duration>,
+        /// Skip the quit request and the SIGTERM grace period, and SIGKILL every Dropbox
+        /// process right away. Anything not yet written to disk is lost
+        #[arg(long)]
+        force: bool,
+        /// How to keep the LaunchAgent from starting Dropbox [default: rename, or
+        /// `strategy` in the config file's [launch-agent] table]
+        #[arg(long, value_enum, value_name = "STRATEGY")]
+        launch_agent_strategy: Option<LaunchAgentStrategy>,
+        #[command(flatten)]
+        timing: TimingOverrides,
+    },
+    #[command(
+        about = "Bring individual components to a state, changing only those not in it yet",
+        after_help = indoc! {r#"
+            Components:
+              processes=running|stopped
+              agent=enabled|disabled        (every Dropbox LaunchAgent and LaunchDaemon)
+              extensions=enabled|disabled
+
+            Example:
+              droponoff set processes=running agent=disabled
+        "#}
+    )]
+    Set {
+        /// Components and the state to bring each to, e.g. `agent=disabled`
+        #[arg(value_name = "COMPONENT=STATE")]
+        assignments: Vec<String>,
+        /// Read the desired state from this TOML file; COMPONENT=STATE arguments win
+        /// over it
+        #[arg(long, value_name = "FILE")]
+        file: Option<PathBuf>,
+        /// On failure, leave completed changes in place instead of undoing them
+        #[arg(long)]
+        no_rollback: bool,
+        /// Print the ordered plan without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the verification report as JSON on standard output, whether or not it
+        /// passes
+        #[arg(long)]
+        report_json: bool,
+        /// How to keep the LaunchAgents from starting Dropbox with agent=disabled
+        /// [default: rename, or `strategy` in the config file's [launch-agent] table]
+        #[arg(long, value_enum, value_name = "STRATEGY")]
+        launch_agent_strategy: Option<LaunchAgentStrategy>,
+        #[command(flatten)]
+        timing: TimingOverrides,
+    },
+    #[command(
+        about = "Show current Dropbox state (read-only)",
+        after_help = indoc! {r#"
+            Exit codes with --check:
+              0  Dropbox is in the requested state
+              1  An error occurred
+              2  Dropbox is fully in the opposite state
+              3  Dropbox is partially on
+              4  Dropbox is not installed
+        "#}
+    )]
+    Status {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = StatusFormat::Pretty)]
+        format: StatusFormat,
+        /// Exit with a distinct code unless Dropbox is in this state
+        #[arg(long, value_enum, value_name = "STATE")]
+        check: Option<Target>,
+    },
+    /// Show which processes are treated as part of Dropbox, and why (read-only)
+    Explain,
+    /// Register the extensions the installed Dropbox.app ships with pluginkit again, and
+    /// remove registrations from deleted or duplicate copies of Dropbox.app
+    Repair {
+        /// Print what would be registered and removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
     #[command(
         about = "DANGEROUS: Delete scratch_files contents after ensuring Dropbox is stopped (READ FULL HELP).",
         long_about = indoc! {r#"
@@ -44,196 +172,265 @@ enum Commands {
             recommended, this command is risky and is not in any way supported by Dropbox or the author of this tool.
         "#}
     )]
-    NukeScratch,
-}
-
-fn main() -> Result<()> {
-    logging::init_logging();
-
-    let cli = Cli::parse();
-
-    let result = match cli.command {
-        Commands::Off => cmd_off(),
-        Commands::On => cmd_on(),
-        Commands::Status => cmd_status(),
-        Commands::NukeScratch => cmd_nuke_scratch(),
-    };
-
-    if let Err(ref e) = result {
-        error!("{}", e);
-    }
-
-    result
+    NukeScratch {
+        /// List every file that would be deleted, and the total size, without deleting
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
-fn verify_with_retry<F>(check_fn: F, max_attempts: u32, delay_ms: u64) -> Result<()>
-where
-    F: Fn(&status::Status) -> bool,
-{
-    for attempt in 1..=max_attempts {
-        let status = status::get_status()?;
-
-        if check_fn(&status) {
-            return Ok(());
+impl Commands {
+    fn is_mutating(&self) -> bool {
+        match self {
+            Commands::On { dry_run, .. }
+            | Commands::Off { dry_run, .. }
+            | Commands::Set { dry_run, .. }
+            | Commands::Repair { dry_run }
+            | Commands::NukeScratch { dry_run } => !dry_run,
+            Commands::Status { .. } | Commands::Explain => false,
         }
+    }
 
-        if attempt < max_attempts {
-            std::thread::sleep(std::time::Duration::from_millis(delay_ms));
+    /// Whether the command runs launchctl, pluginkit or kill, which act on the running
+    /// machine rather than on files beneath `--root`.
+    fn changes_live_system(&self) -> bool {
+        match self {
+            Commands::On { dry_run, .. }
+            | Commands::Off { dry_run, .. }
+            | Commands::Set { dry_run, .. }
+            | Commands::Repair { dry_run } => !dry_run,
+            Commands::NukeScratch { .. } | Commands::Status { .. } | Commands::Explain => false,
         }
     }
-
-    anyhow::bail!("Verification failed after {} attempts", max_attempts)
 }
 
-fn cmd_off() -> Result<()> {
-    info!("Disabling Dropbox...\n");
-
-    info!("→ Requesting Dropbox to quit...");
-    if let Err(e) = processes::quit_dropbox_gracefully() {
-        warn!("  Note: {}", e);
+/// `--root` only redirects filesystem paths. A rooted `off` would rename plists inside
+/// the image but boot out, disable and kill jobs on this machine, and its lock would not
+/// exclude an unrooted run.
+fn check_root(cli: &Cli) -> Result<()> {
+    if let Some(root) = &cli.root {
+        if cli.command.changes_live_system() {
+            anyhow::bail!(
+                "--root {} only works with status, explain, nuke-scratch and --dry-run: \
+                 this command would run launchctl, pluginkit and kill on this machine",
+                root.display()
+            );
+        }
     }
-
-    info!("→ Disabling LaunchAgent...");
-    launchagent::unload_launch_agent().ok(); // Ignore if not loaded
-    launchagent::disable_launch_agent()?;
-
-    info!("→ Disabling Dropbox extensions...");
-    extensions::disable_all_extensions()?;
-
-    // Refresh finder in the hopes it will relinquish any active
-    // file provider dependencies.
-    info!("→ Restarting Finder...");
-    finder::restart_finder()?;
-
-    info!("→ Waiting for non-FileProvider processes to stop...");
-    processes::wait_for_non_fileprovider_processes_to_die(10)?;
-
-    // Note: We have found no other way to gracefully terminate FileProvider processes
-    // using command-line tools. SIGTERM should be reasonably safe unless the provider
-    // already has other bugs - it's at the very least as graceful as an actual normal
-    // crash or similar event. There used to be a fileproviderctl command to
-    // remove "domains" - but the command disappeared in Sonoma.
-    info!("→ Terminating DropboxFileProvider processes...");
-    processes::kill_fileprovider_processes()?;
-
-    info!("→ Waiting for all processes to stop...");
-    processes::wait_for_processes_to_die(10)?;
-
-    info!("→ Checking status...");
-    verify_with_retry(
-        |status| {
-            let mut verified = true;
-
-            if !status.processes.is_empty() {
-                warn!("  Still running: {} process(es)", status.processes.len());
-                verified = false;
-            }
-
-            if status.launch_agent_state != status::LaunchAgentState::Disabled {
-                warn!("  LaunchAgent state: {:?}", status.launch_agent_state);
-                verified = false;
-            }
-
-            let enabled_exts: Vec<_> = status
-                .extensions
-                .iter()
-                .filter(|(_, s)| s.enabled)
-                .map(|(name, _)| name.as_str())
-                .collect();
-            if !enabled_exts.is_empty() {
-                warn!("  Extensions still enabled: {}", enabled_exts.join(", "));
-                verified = false;
-            }
-
-            verified
-        },
-        5,
-        500,
-    )?;
-
-    info!("");
-    info!("✓ Dropbox is now OFF");
     Ok(())
 }
 
-fn cmd_on() -> Result<()> {
-    info!("Enabling Dropbox...\n");
-
-    info!("→ Restoring LaunchAgent...");
-    launchagent::enable_launch_agent()?;
-    launchagent::load_launch_agent()?;
-
-    info!("→ Enabling Dropbox extensions...");
-    extensions::enable_all_extensions()?;
+fn main() -> ExitCode {
+    logging::init_logging();
 
-    info!("→ Launching Dropbox...");
-    processes::launch_dropbox()?;
+    let cli = Cli::parse();
 
-    info!("→ Waiting for Dropbox to start...");
-    processes::wait_for_dropbox_to_start(10)?;
+    match run(cli) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    info!("→ Checking status...");
-    verify_with_retry(
-        |status| {
-            let mut verified = true;
+fn run(cli: Cli) -> Result<u8> {
+    if !cfg!(target_os = "macos") {
+        anyhow::bail!("droponoff only works on macOS");
+    }
 
-            if status.processes.is_empty() {
-                warn!("  No processes running yet");
-                verified = false;
-            }
+    check_root(&cli)?;
+    let sys = MacOsBackend::new();
+    let layout = Layout::detect(cli.root.as_deref())?;
 
-            if status.launch_agent_state != status::LaunchAgentState::Enabled {
-                warn!("  LaunchAgent state: {:?}", status.launch_agent_state);
-                verified = false;
-            }
+    // Held until `run` returns by every command that changes anything.
+    let _lock = if cli.command.is_mutating() {
+        let command = std::env::args().collect::<Vec<_>>().join(" ");
+        Some(RunLock::acquire(&layout, &command, cli.wait_lock)?)
+    } else {
+        None
+    };
 
-            let disabled_exts: Vec<_> = status
-                .extensions
-                .iter()
-                .filter(|(bundle_id, s)| {
-                    // Exclude garcon from the check (seems to have to do with
-                    // old school non-file system provider operation)
-                    *bundle_id != "com.getdropbox.dropbox.garcon" && !s.enabled
-                })
-                .map(|(name, _)| name.as_str())
-                .collect();
-            if !disabled_exts.is_empty() {
-                warn!("  Extensions still disabled: {}", disabled_exts.join(", "));
-                verified = false;
+    match cli.command {
+        Commands::Off {
+            no_rollback,
+            dry_run,
+            report_json,
+            wait_for_idle,
+            idle_window,
+            force,
+            launch_agent_strategy,
+            timing,
+        } => {
+            let timing = TimingOverrides {
+                idle_timeout: wait_for_idle.flatten(),
+                idle_window,
+                ..timing
+            };
+            let timings = resolve_timings(&layout, cli.config.as_deref(), &timing)?;
+            let options = OffOptions {
+                rollback: !no_rollback,
+                dry_run,
+                wait_for_idle: wait_for_idle.map(|_| IdleOptions {
+                    timeout: timings.idle_timeout,
+                    window: timings.idle_window,
+                    ..IdleOptions::default()
+                }),
+                termination: if force {
+                    TerminationPolicy::Force
+                } else {
+                    TerminationPolicy::Escalate
+                },
+                launch_agent_strategy: resolve_launch_agent_strategy(
+                    &layout,
+                    cli.config.as_deref(),
+                    launch_agent_strategy,
+                )?,
+                timings,
+                report_json,
+            };
+            cmd_off(&sys, &layout, &options).map(|_| 0)
+        }
+        Commands::On {
+            no_rollback,
+            dry_run,
+            report_json,
+            timing,
+        } => {
+            let options = OnOptions {
+                rollback: !no_rollback,
+                dry_run,
+                timings: resolve_timings(&layout, cli.config.as_deref(), &timing)?,
+                report_json,
+            };
+            cmd_on(&sys, &layout, &options).map(|_| 0)
+        }
+        Commands::Set {
+            assignments,
+            file,
+            no_rollback,
+            dry_run,
+            report_json,
+            launch_agent_strategy,
+            timing,
+        } => {
+            let from_file = match &file {
+                Some(path) => DesiredState::load(path)?,
+                None => DesiredState::default(),
+            };
+            let desired = from_file.merge(DesiredState::parse_assignments(&assignments)?);
+            if desired.is_empty() {
+                anyhow::bail!(
+                    "Nothing to set; give COMPONENT=STATE arguments or --file (see `droponoff set --help`)"
+                );
             }
-
-            verified
-        },
-        5,
-        500,
-    )?;
-
-    info!("");
-    info!("✓ Dropbox is now ON");
-    Ok(())
+            let options = SetOptions {
+                desired,
+                rollback: !no_rollback,
+                dry_run,
+                launch_agent_strategy: resolve_launch_agent_strategy(
+                    &layout,
+                    cli.config.as_deref(),
+                    launch_agent_strategy,
+                )?,
+                timings: resolve_timings(&layout, cli.config.as_deref(), &timing)?,
+                report_json,
+            };
+            cmd_set(&sys, &layout, &options).map(|_| 0)
+        }
+        Commands::Status { format, check } => cmd_status(&sys, &layout, format, check),
+        Commands::Explain => cmd_explain(&sys, &layout).map(|_| 0),
+        Commands::Repair { dry_run } => cmd_repair(&sys, &layout, dry_run).map(|_| 0),
+        Commands::NukeScratch { dry_run } => cmd_nuke_scratch(&sys, &layout, dry_run).map(|_| 0),
+    }
 }
 
-fn cmd_status() -> Result<()> {
-    let status = status::get_status()?;
-    status::print_status(&status);
-    Ok(())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn cmd_nuke_scratch() -> Result<()> {
-    info!("Deleting scratch_files contents...\n");
+    fn check(args: &[&str]) -> Result<()> {
+        check_root(&Cli::try_parse_from(args).unwrap())
+    }
 
-    info!("→ Checking Dropbox status...");
-    let status = status::get_status()?;
-    if !status.processes.is_empty() {
-        anyhow::bail!(
-            "Dropbox appears to be running. Run `droponoff status` to check and `droponoff off` to turn it off."
+    #[test]
+    fn wait_for_idle_takes_an_optional_duration() {
+        let wait_for_idle = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Off {
+                wait_for_idle,
+                idle_window,
+                ..
+            } => (wait_for_idle, idle_window),
+            _ => unreachable!(),
+        };
+
+        assert_eq!(wait_for_idle(&["droponoff", "off"]), (None, None));
+        assert_eq!(
+            wait_for_idle(&["droponoff", "off", "--wait-for-idle"]),
+            (Some(None), None)
+        );
+        assert_eq!(
+            wait_for_idle(&[
+                "droponoff",
+                "off",
+                "--wait-for-idle=15m",
+                "--idle-window",
+                "1m 30s"
+            ]),
+            (
+                Some(Some(Duration::from_secs(900))),
+                Some(Duration::from_secs(90))
+            )
         );
+        assert!(Cli::try_parse_from(["droponoff", "off", "--wait-for-idle=600"]).is_err());
     }
 
-    info!("→ Cleaning scratch_files directories...");
-    status::clean_scratch_files()?;
+    #[test]
+    fn report_json_is_accepted_by_every_verifying_command() {
+        for args in [
+            &["droponoff", "off", "--report-json"][..],
+            &["droponoff", "on", "--report-json"],
+            &["droponoff", "set", "--report-json", "agent=disabled"],
+        ] {
+            Cli::try_parse_from(args).unwrap();
+        }
+        assert!(Cli::try_parse_from(["droponoff", "status", "--report-json"]).is_err());
+    }
 
-    info!("");
-    info!("✓ scratch_files contents deleted");
-    Ok(())
+    #[test]
+    fn root_is_refused_for_commands_that_change_the_live_system() {
+        for command in ["off", "on", "repair"] {
+            let error = check(&["droponoff", "--root", "/Volumes/Restore", command]).unwrap_err();
+            assert!(error.to_string().contains("launchctl"), "{}", error);
+        }
+        assert!(check(&[
+            "droponoff",
+            "--root",
+            "/Volumes/Restore",
+            "set",
+            "agent=disabled"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn root_is_allowed_for_filesystem_only_commands_and_dry_runs() {
+        for args in [
+            &["droponoff", "--root", "/r", "status"][..],
+            &["droponoff", "--root", "/r", "explain"],
+            &["droponoff", "--root", "/r", "nuke-scratch"],
+            &["droponoff", "--root", "/r", "off", "--dry-run"],
+            &[
+                "droponoff",
+                "--root",
+                "/r",
+                "set",
+                "--dry-run",
+                "agent=disabled",
+            ],
+            &["droponoff", "off"],
+        ] {
+            check(args).unwrap();
+        }
+    }
 }
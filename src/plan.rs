@@ -0,0 +1,978 @@
+//! Ordered descriptions of what `off`, `on` and `set` would do, computed from the current
+//! state without changing anything. Used by `--dry-run`.
+
+use crate::backend::{Election, SystemBackend};
+use crate::commands::{OffOptions, OnOptions, SetOptions};
+use crate::config::{format_duration, Timings};
+use crate::discovery::Layout;
+use crate::extensions::{ExtensionPolicy, ExtensionState, PluginElection};
+use crate::journal::{Journal, LaunchAgentChanges};
+use crate::launchagent::{LaunchAgentStrategy, LaunchdJob};
+use crate::processes::TerminationPolicy;
+use crate::reconcile::{self, ComponentState, ProcessesState};
+use crate::state::Target;
+use crate::status::{self, LaunchAgentState, LaunchAgentStatus, LaunchdOverride};
+use crate::{extensions, launchagent, processes};
+use anyhow::Result;
+use std::path::Path;
+use tracing::info;
+
+pub struct PlanStep {
+    pub description: String,
+    /// The equivalent shell command, when there is one.
+    pub command: Option<String>,
+}
+
+pub struct Plan {
+    pub title: String,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    fn new(title: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            steps: Vec::new(),
+        }
+    }
+
+    fn step(&mut self, description: impl Into<String>, command: Option<String>) {
+        self.steps.push(PlanStep {
+            description: description.into(),
+            command,
+        });
+    }
+
+    pub fn print(&self) {
+        info!("Dry run: {} (nothing will be changed)\n", self.title);
+        if self.steps.is_empty() {
+            info!("  Nothing to do");
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            info!("  {}. {}", i + 1, step.description);
+            if let Some(command) = &step.command {
+                info!("       $ {}", command);
+            }
+        }
+    }
+}
+
+fn quote(path: &Path) -> String {
+    let s = path.display().to_string();
+    if s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-+".contains(c))
+    {
+        s
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+fn quit_command() -> String {
+    r#"osascript -e 'tell application "Dropbox" to quit'"#.to_string()
+}
+
+/// Elect by UUID when only the registration at `path` is meant.
+fn elect_command(state: &ExtensionState, path: Option<&Path>, election: Election) -> String {
+    match path
+        .and_then(|path| state.registration(Some(path)))
+        .and_then(|r| r.uuid.as_deref())
+    {
+        Some(uuid) => format!("pluginkit -e {} -u {}", election.as_str(), uuid),
+        None => format!("pluginkit -e {} -i {}", election.as_str(), state.bundle_id),
+    }
+}
+
+/// Marks extensions whose failure to change only produces a warning.
+fn optional_prefix(policy: ExtensionPolicy) -> &'static str {
+    match policy {
+        ExtensionPolicy::Optional => "optional ",
+        ExtensionPolicy::Required | ExtensionPolicy::Ignored => "",
+    }
+}
+
+fn describe_extension(bundle_id: &str, path: Option<&Path>) -> String {
+    match path {
+        Some(path) => format!("{} at {}", bundle_id, path.display()),
+        None => bundle_id.to_string(),
+    }
+}
+
+fn conflict_failure(job: &LaunchdJob) -> String {
+    format!(
+        "Fail: both {} and {} exist; resolve the conflict first",
+        job.plist.display(),
+        job.disabled_path().display()
+    )
+}
+
+fn rename_command(from: &Path, to: &Path) -> String {
+    format!("mv {} {}", quote(from), quote(to))
+}
+
+/// Add the step that resolves a job's conflict, if there is one, and return the state
+/// that leaves behind. The plist files are read again rather than taken from the status,
+/// since the updater may have changed them since.
+fn resolve_conflict_step(
+    plan: &mut Plan,
+    layout: &Layout,
+    job: &LaunchdJob,
+    target: Target,
+) -> Result<LaunchAgentState> {
+    let Some(resolution) = launchagent::plan_conflict_resolution(layout, job, target)? else {
+        return launchagent::get_launch_agent_state(job);
+    };
+    plan.step(
+        format!(
+            "Resolve {} conflict: keep {} ({}), back up the other copy",
+            job.label,
+            resolution.keep.display(),
+            resolution.reason
+        ),
+        Some(format!(
+            "mkdir -p {} && {}",
+            quote(resolution.backup.parent().unwrap_or(Path::new("/"))),
+            rename_command(&resolution.discard, &resolution.backup)
+        )),
+    );
+    Ok(if resolution.keep == job.plist {
+        LaunchAgentState::Enabled
+    } else {
+        LaunchAgentState::Disabled
+    })
+}
+
+/// Add the steps `off` takes for one job.
+fn disable_job_steps(
+    plan: &mut Plan,
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    agent: &LaunchAgentStatus,
+    strategy: LaunchAgentStrategy,
+) -> Result<()> {
+    let job = &agent.job;
+    if let Some(reason) = job.unchangeable_reason(sys, strategy)? {
+        plan.step(format!("Skip {}: {}", job.label, reason), None);
+        return Ok(());
+    }
+
+    let state = resolve_conflict_step(plan, layout, job, Target::Off)?;
+    if agent.runtime.loaded {
+        plan.step(
+            format!("Unload {}", job.label),
+            Some(format!("launchctl bootout {}", job.service_target(sys)?)),
+        );
+    } else {
+        plan.step(format!("{} not loaded, skip unloading", job.label), None);
+    }
+
+    match state {
+        _ if strategy == LaunchAgentStrategy::LaunchctlDisable => {
+            if agent.launchd_override == LaunchdOverride::Disabled {
+                plan.step(
+                    format!("{} already disabled in launchd, skip", job.label),
+                    None,
+                );
+            } else {
+                plan.step(
+                    format!(
+                        "Disable {} in launchd's overrides (the plist stays in place)",
+                        job.label
+                    ),
+                    Some(format!("launchctl disable {}", job.service_target(sys)?)),
+                );
+            }
+        }
+        LaunchAgentState::Enabled => plan.step(
+            format!("Disable the {} plist", job.label),
+            Some(rename_command(&job.plist, &job.disabled_path())),
+        ),
+        LaunchAgentState::Disabled => {
+            plan.step(format!("{} already disabled, skip", job.label), None)
+        }
+        LaunchAgentState::Missing => plan.step(
+            format!(
+                "Fail: {} plist not found at {}",
+                job.label,
+                job.plist.display()
+            ),
+            None,
+        ),
+        // The updater recreated a copy after the conflict was checked.
+        LaunchAgentState::Conflict => plan.step(conflict_failure(job), None),
+    }
+    if strategy == LaunchAgentStrategy::Immutable {
+        if launchagent::has_placeholder(job) {
+            plan.step(
+                format!("{} placeholder already in place, skip", job.label),
+                None,
+            );
+        } else {
+            plan.step(
+                format!(
+                    "Write a placeholder to {} and make it immutable",
+                    job.plist.display()
+                ),
+                Some(format!("chflags uchg {}", quote(&job.plist))),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Add the steps `on` takes to undo `changes`.
+fn restore_job_steps(
+    plan: &mut Plan,
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    status: &status::Status,
+    changes: &LaunchAgentChanges,
+) -> Result<()> {
+    let job = &changes.job;
+    let current = status
+        .launch_agents
+        .iter()
+        .find(|a| a.job.plist == job.plist);
+    let launchd_override = current.map(|a| a.launchd_override).unwrap_or_default();
+    let loaded = current.is_some_and(|a| a.runtime.loaded);
+
+    if changes.created_placeholder && launchagent::has_placeholder(job) {
+        let path = quote(&job.plist);
+        plan.step(
+            format!("Remove the {} placeholder", job.label),
+            Some(format!("chflags nouchg {} && rm {}", path, path)),
+        );
+    }
+    let state = resolve_conflict_step(plan, layout, job, Target::On)?;
+    if changes.renamed {
+        match state {
+            LaunchAgentState::Disabled => plan.step(
+                format!("Enable the {} plist", job.label),
+                Some(rename_command(&job.disabled_path(), &job.plist)),
+            ),
+            LaunchAgentState::Enabled => {
+                plan.step(format!("{} already enabled, skip", job.label), None)
+            }
+            LaunchAgentState::Missing => plan.step(
+                format!(
+                    "Fail: {} plist not found at {}",
+                    job.label,
+                    job.disabled_path().display()
+                ),
+                None,
+            ),
+            LaunchAgentState::Conflict => plan.step(conflict_failure(job), None),
+        }
+    }
+    if changes.disabled_service && launchd_override == LaunchdOverride::Disabled {
+        plan.step(
+            format!("Enable {} in launchd's overrides", job.label),
+            Some(format!("launchctl enable {}", job.service_target(sys)?)),
+        );
+    }
+    if changes.unloaded && loaded {
+        plan.step(format!("{} already loaded, skip", job.label), None);
+    } else if changes.unloaded {
+        plan.step(
+            format!("Load {}", job.label),
+            Some(format!(
+                "launchctl bootstrap {} {}",
+                job.domain_target(sys)?,
+                quote(&job.plist)
+            )),
+        );
+    }
+    Ok(())
+}
+
+fn pids(processes: &[processes::DropboxProcess]) -> String {
+    processes
+        .iter()
+        .map(|p| format!("{} ({})", p.pid, p.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn quit_step(plan: &mut Plan, lists: &processes::DropboxProcessLists) {
+    if lists.all.is_empty() {
+        plan.step(
+            "Ask Dropbox to quit (no Dropbox processes are running)",
+            Some(quit_command()),
+        );
+    } else {
+        plan.step(
+            format!("Ask Dropbox to quit; running: {}", pids(&lists.all)),
+            Some(quit_command()),
+        );
+    }
+}
+
+/// Add the steps that stop every process in `lists` once Dropbox was asked to quit.
+fn stop_steps(
+    plan: &mut Plan,
+    lists: &processes::DropboxProcessLists,
+    termination: TerminationPolicy,
+    timings: &Timings,
+) {
+    let force = termination == TerminationPolicy::Force;
+
+    if force {
+        // Nothing is waited for; everything still running gets SIGKILL below.
+    } else if lists.non_fileprovider.is_empty() {
+        plan.step(
+            format!(
+                "Wait up to {} for non-FileProvider processes to stop (none running)",
+                format_duration(timings.quit_timeout)
+            ),
+            None,
+        );
+    } else {
+        plan.step(
+            format!(
+                "Wait up to {} for non-FileProvider processes to stop, and fail if they \
+                 do not: {}",
+                format_duration(timings.quit_timeout),
+                pids(&lists.non_fileprovider)
+            ),
+            None,
+        );
+    }
+
+    // Without --force, the non-FileProvider processes are gone by now or `off` has
+    // failed.
+    let targets = if force {
+        &lists.all
+    } else {
+        &lists.fileprovider
+    };
+    if targets.is_empty() {
+        plan.step("Signal remaining Dropbox processes (none running)", None);
+    }
+    for process in targets {
+        if force {
+            plan.step(
+                format!("Send SIGKILL to PID {} ({})", process.pid, process.name),
+                Some(format!("kill -KILL {}", process.pid)),
+            );
+        } else {
+            plan.step(
+                format!(
+                    "Send SIGTERM to PID {} ({}), then SIGKILL if still running after {}",
+                    process.pid,
+                    process.name,
+                    format_duration(timings.term_grace)
+                ),
+                Some(format!("kill -TERM {}", process.pid)),
+            );
+        }
+    }
+
+    plan.step(
+        format!(
+            "Wait up to {} for all Dropbox processes to stop",
+            format_duration(timings.stop_timeout)
+        ),
+        None,
+    );
+}
+
+fn verify_step(state: &str, timings: &Timings) -> String {
+    format!(
+        "Verify that Dropbox is {} ({} attempts, {} apart)",
+        state,
+        timings.verify_attempts,
+        format_duration(timings.verify_interval)
+    )
+}
+
+pub fn plan_off(sys: &dyn SystemBackend, layout: &Layout, options: &OffOptions) -> Result<Plan> {
+    let mut plan = Plan::new("droponoff off");
+    let status = status::get_status(sys, layout)?;
+    let lists = processes::list_all_dropbox_processes(sys, layout)?;
+
+    if let Some(idle) = &options.wait_for_idle {
+        plan.step(
+            format!(
+                "Wait up to {} for Dropbox CPU, network and scratch_files activity to stay \
+                 quiet for {}; abort otherwise",
+                format_duration(idle.timeout),
+                format_duration(idle.window)
+            ),
+            None,
+        );
+    }
+
+    let force = options.termination == TerminationPolicy::Force;
+
+    if force {
+        plan.step("Skip asking Dropbox to quit (--force)", None);
+    } else {
+        quit_step(&mut plan, &lists);
+    }
+
+    if status.launch_agents.is_empty() {
+        plan.step("No Dropbox LaunchAgents or LaunchDaemons found, skip", None);
+    }
+    for agent in &status.launch_agents {
+        disable_job_steps(&mut plan, sys, layout, agent, options.launch_agent_strategy)?;
+    }
+
+    for (bundle_id, state) in &status.extensions {
+        let policy = extensions::policy(bundle_id, Target::Off);
+        if policy == ExtensionPolicy::Ignored {
+            plan.step(
+                format!("{} is ignored when turning Dropbox off, skip", bundle_id),
+                None,
+            );
+        } else if !state.found {
+            plan.step(format!("{} not found, skip", bundle_id), None);
+        } else if !state.enabled {
+            plan.step(format!("{} already disabled, skip", bundle_id), None);
+        } else {
+            let path = state.target_path();
+            plan.step(
+                format!(
+                    "Disable {}extension {}",
+                    optional_prefix(policy),
+                    describe_extension(bundle_id, path)
+                ),
+                Some(elect_command(state, path, Election::Ignore)),
+            );
+        }
+    }
+
+    plan.step("Restart Finder", Some("killall Finder".to_string()));
+
+    stop_steps(&mut plan, &lists, options.termination, &options.timings);
+    plan.step(verify_step("off", &options.timings), None);
+    plan.step(
+        format!("Write journal to {}", Journal::path(layout).display()),
+        None,
+    );
+
+    Ok(plan)
+}
+
+pub fn plan_on(sys: &dyn SystemBackend, layout: &Layout, options: &OnOptions) -> Result<Plan> {
+    let mut plan = Plan::new("droponoff on");
+    let status = status::get_status(sys, layout)?;
+
+    let journal = Journal::load(layout)?;
+    let (agent_changes, extensions, launch) = match &journal {
+        Some(journal) => {
+            plan.step(
+                format!(
+                    "Undo the changes recorded in {}",
+                    Journal::path(layout).display()
+                ),
+                None,
+            );
+            for difference in journal.drift(&status) {
+                plan.step(
+                    format!("Warn about drift since `off`: {}", difference),
+                    None,
+                );
+            }
+            (
+                journal.launch_agent_changes(),
+                journal
+                    .disabled_extensions()
+                    .into_iter()
+                    .map(|(id, path)| (id.to_string(), path.map(Path::to_path_buf)))
+                    .collect::<Vec<_>>(),
+                journal.stopped_dropbox(),
+            )
+        }
+        None => {
+            plan.step(
+                format!(
+                    "No journal at {}, restore every component",
+                    Journal::path(layout).display()
+                ),
+                None,
+            );
+            let mut changes = Vec::new();
+            for agent in &status.launch_agents {
+                let job = &agent.job;
+                let reason = match job.unchangeable_reason(sys, LaunchAgentStrategy::Rename)? {
+                    Some(reason) => Some(reason),
+                    None => job.unchangeable_reason(sys, LaunchAgentStrategy::LaunchctlDisable)?,
+                };
+                match reason {
+                    Some(reason) => plan.step(format!("Skip {}: {}", job.label, reason), None),
+                    None => changes.push(LaunchAgentChanges::everything(job.clone())),
+                }
+            }
+            let extensions = extensions::dropbox_bundle_ids(layout)?
+                .into_iter()
+                .map(|bundle_id| (bundle_id, None))
+                .collect();
+            (changes, extensions, true)
+        }
+    };
+
+    for changes in &agent_changes {
+        restore_job_steps(&mut plan, sys, layout, &status, changes)?;
+    }
+    if journal.is_some() {
+        for agent in &status.launch_agents {
+            if !agent_changes.iter().any(|c| c.job.plist == agent.job.plist) {
+                resolve_conflict_step(&mut plan, layout, &agent.job, Target::On)?;
+            }
+        }
+    }
+
+    for (bundle_id, path) in &extensions {
+        let policy = extensions::policy(bundle_id, Target::On);
+        if policy == ExtensionPolicy::Ignored {
+            plan.step(
+                format!("{} is ignored when turning Dropbox on, skip", bundle_id),
+                None,
+            );
+            continue;
+        }
+        let Some((_, state)) = status.extensions.iter().find(|(id, _)| id == bundle_id) else {
+            plan.step(format!("{} not found, skip", bundle_id), None);
+            continue;
+        };
+        let path = path.as_deref().or(state.target_path());
+        let name = describe_extension(bundle_id, path);
+        match state.registration(path) {
+            None => plan.step(format!("{} not found, skip", name), None),
+            Some(r) if r.election == PluginElection::Use => {
+                plan.step(format!("{} already enabled, skip", name), None)
+            }
+            Some(_) => plan.step(
+                format!("Enable {}extension {}", optional_prefix(policy), name),
+                Some(elect_command(state, path, Election::Use)),
+            ),
+        }
+    }
+
+    if launch {
+        plan.step("Launch Dropbox", Some("open -a Dropbox".to_string()));
+        plan.step(
+            format!(
+                "Wait up to {} for Dropbox to start",
+                format_duration(options.timings.start_timeout)
+            ),
+            None,
+        );
+    }
+
+    plan.step(verify_step("on", &options.timings), None);
+    if journal.is_some() {
+        plan.step("Remove the journal", None);
+    }
+
+    Ok(plan)
+}
+
+pub fn plan_set(sys: &dyn SystemBackend, layout: &Layout, options: &SetOptions) -> Result<Plan> {
+    let desired = &options.desired;
+    let mut plan = Plan::new(&format!("droponoff set {}", desired.describe()));
+    let status = status::get_status(sys, layout)?;
+    let lists = processes::list_all_dropbox_processes(sys, layout)?;
+
+    let reconciliation =
+        reconcile::reconcile(sys, layout, &status, desired, options.launch_agent_strategy)?;
+    for (component, reason) in &reconciliation.skipped {
+        plan.step(format!("Skip {}: {}", component, reason), None);
+    }
+    for goal in &reconciliation.goals {
+        let (actual, met) = goal.expectation.check(&status);
+        if met {
+            plan.step(
+                format!("{}: {}, skip", goal.expectation.component(), actual),
+                None,
+            );
+        }
+    }
+    if reconciliation.pending(&status).is_empty() {
+        plan.step("Every component is already in the requested state", None);
+        return Ok(plan);
+    }
+
+    for job in reconciliation.pending_jobs(&status) {
+        let Some(agent) = status
+            .launch_agents
+            .iter()
+            .find(|a| a.job.plist == job.plist)
+        else {
+            continue;
+        };
+        match desired.agent {
+            Some(ComponentState::Disabled) => {
+                disable_job_steps(&mut plan, sys, layout, agent, options.launch_agent_strategy)?
+            }
+            Some(ComponentState::Enabled) => restore_job_steps(
+                &mut plan,
+                sys,
+                layout,
+                &status,
+                &LaunchAgentChanges::everything(job.clone()),
+            )?,
+            None => {}
+        }
+    }
+
+    let pending_extensions = reconciliation.pending_extensions(&status);
+    for bundle_id in &pending_extensions {
+        let Some((_, state)) = status.extensions.iter().find(|(id, _)| id == bundle_id) else {
+            continue;
+        };
+        let path = state.target_path();
+        let name = describe_extension(bundle_id, path);
+        match desired.extensions {
+            Some(ComponentState::Disabled) => plan.step(
+                format!(
+                    "Disable {}extension {}",
+                    optional_prefix(extensions::policy(bundle_id, Target::Off)),
+                    name
+                ),
+                Some(elect_command(state, path, Election::Ignore)),
+            ),
+            Some(ComponentState::Enabled) => plan.step(
+                format!(
+                    "Enable {}extension {}",
+                    optional_prefix(extensions::policy(bundle_id, Target::On)),
+                    name
+                ),
+                Some(elect_command(state, path, Election::Use)),
+            ),
+            None => {}
+        }
+    }
+    if desired.extensions == Some(ComponentState::Disabled) && !pending_extensions.is_empty() {
+        plan.step("Restart Finder", Some("killall Finder".to_string()));
+    }
+
+    match desired.processes {
+        Some(ProcessesState::Stopped) if !lists.all.is_empty() => {
+            quit_step(&mut plan, &lists);
+            stop_steps(
+                &mut plan,
+                &lists,
+                TerminationPolicy::Escalate,
+                &options.timings,
+            );
+        }
+        Some(ProcessesState::Running) if lists.all.is_empty() => {
+            plan.step("Launch Dropbox", Some("open -a Dropbox".to_string()));
+            plan.step(
+                format!(
+                    "Wait up to {} for Dropbox to start",
+                    format_duration(options.timings.start_timeout)
+                ),
+                None,
+            );
+        }
+        _ => {}
+    }
+
+    plan.step(
+        verify_step("in the requested state", &options.timings),
+        None,
+    );
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    fn descriptions(plan: &Plan) -> Vec<&str> {
+        plan.steps.iter().map(|s| s.description.as_str()).collect()
+    }
+
+    #[test]
+    fn a_conflict_gone_since_the_status_was_taken_is_planned_from_the_disk() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        fs::copy(&job.plist, job.disabled_path()).unwrap();
+        let snapshot = status::get_status(&sys, &mac.layout).unwrap();
+        let agent = &snapshot.launch_agents[0];
+        assert_eq!(agent.state, LaunchAgentState::Conflict);
+        // The updater removes its copy before the plan is made.
+        fs::remove_file(job.disabled_path()).unwrap();
+
+        let mut plan = Plan::new("off");
+        disable_job_steps(
+            &mut plan,
+            &sys,
+            &mac.layout,
+            agent,
+            LaunchAgentStrategy::Rename,
+        )
+        .unwrap();
+
+        let steps = descriptions(&plan);
+        assert!(
+            steps.contains(&format!("Disable the {} plist", AGENT).as_str()),
+            "{:?}",
+            steps
+        );
+        assert!(
+            !steps.iter().any(|s| s.starts_with("Resolve")),
+            "{:?}",
+            steps
+        );
+    }
+
+    #[test]
+    fn a_conflict_still_present_is_resolved_first() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        fs::copy(&job.plist, job.disabled_path()).unwrap();
+        let snapshot = status::get_status(&sys, &mac.layout).unwrap();
+
+        let mut plan = Plan::new("off");
+        disable_job_steps(
+            &mut plan,
+            &sys,
+            &mac.layout,
+            &snapshot.launch_agents[0],
+            LaunchAgentStrategy::Rename,
+        )
+        .unwrap();
+
+        let steps = descriptions(&plan);
+        assert!(steps[0].starts_with(&format!("Resolve {} conflict: keep", AGENT)));
+        assert!(steps.contains(&format!("{} already disabled, skip", AGENT).as_str()));
+    }
+
+    /// Each step as `description` or `description $ command`.
+    fn steps(plan: &Plan) -> Vec<String> {
+        plan.steps
+            .iter()
+            .map(|s| match &s.command {
+                Some(command) => format!("{} $ {}", s.description, command),
+                None => s.description.clone(),
+            })
+            .collect()
+    }
+
+    fn off_with(strategy: LaunchAgentStrategy) -> OffOptions {
+        OffOptions {
+            launch_agent_strategy: strategy,
+            ..off_options()
+        }
+    }
+
+    #[test]
+    fn off_plans_each_launch_agent_strategy() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let plist = quote(&mac.agent_plist());
+        let disabled = quote(&mac.agent_plist().with_extension("plist.disabled"));
+        let agent_steps = |strategy| -> Vec<String> {
+            let plan = plan_off(&sys, &mac.layout, &off_with(strategy)).unwrap();
+            steps(&plan)
+                .into_iter()
+                .filter(|s| s.contains(AGENT) || s.contains("placeholder"))
+                .collect()
+        };
+
+        assert_eq!(
+            agent_steps(LaunchAgentStrategy::Rename),
+            [
+                format!("Unload {0} $ launchctl bootout gui/501/{0}", AGENT),
+                format!("Disable the {} plist $ mv {} {}", AGENT, plist, disabled),
+            ]
+        );
+        assert_eq!(
+            agent_steps(LaunchAgentStrategy::LaunchctlDisable),
+            [
+                format!("Unload {0} $ launchctl bootout gui/501/{0}", AGENT),
+                format!(
+                    "Disable {0} in launchd's overrides (the plist stays in place) $ \
+                     launchctl disable gui/501/{0}",
+                    AGENT
+                ),
+            ]
+        );
+        assert_eq!(
+            agent_steps(LaunchAgentStrategy::Immutable),
+            [
+                format!("Unload {0} $ launchctl bootout gui/501/{0}", AGENT),
+                format!("Disable the {} plist $ mv {} {}", AGENT, plist, disabled),
+                format!(
+                    "Write a placeholder to {} and make it immutable $ chflags uchg {}",
+                    mac.agent_plist().display(),
+                    plist
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn on_plans_to_undo_each_launch_agent_strategy() {
+        let plan_after_off = |strategy| -> (TestMac, Vec<String>) {
+            let mac = TestMac::new().with_agent();
+            let sys = running_dropbox();
+            crate::commands::cmd_off(&sys, &mac.layout, &off_with(strategy)).unwrap();
+            let plan = plan_on(&sys, &mac.layout, &on_options()).unwrap();
+            let agent_steps = steps(&plan)
+                .into_iter()
+                .filter(|s| s.contains(AGENT) || s.contains("placeholder"))
+                .collect();
+            (mac, agent_steps)
+        };
+
+        let (mac, agent_steps) = plan_after_off(LaunchAgentStrategy::LaunchctlDisable);
+        assert_eq!(
+            agent_steps,
+            [
+                format!(
+                    "Enable {0} in launchd's overrides $ launchctl enable gui/501/{0}",
+                    AGENT
+                ),
+                format!(
+                    "Load {} $ launchctl bootstrap gui/501 {}",
+                    AGENT,
+                    quote(&mac.agent_plist())
+                ),
+            ]
+        );
+
+        let (mac, agent_steps) = plan_after_off(LaunchAgentStrategy::Immutable);
+        let plist = quote(&mac.agent_plist());
+        let disabled = quote(&mac.agent_plist().with_extension("plist.disabled"));
+        assert_eq!(
+            agent_steps,
+            [
+                format!(
+                    "Remove the {} placeholder $ chflags nouchg {1} && rm {1}",
+                    AGENT, plist
+                ),
+                format!("Enable the {} plist $ mv {} {}", AGENT, disabled, plist),
+                format!("Load {} $ launchctl bootstrap gui/501 {}", AGENT, plist),
+            ]
+        );
+    }
+
+    #[test]
+    fn off_plans_every_step_in_order() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let plist = quote(&mac.agent_plist());
+        let disabled = quote(&mac.agent_plist().with_extension("plist.disabled"));
+
+        let plan = plan_off(&sys, &mac.layout, &off_options()).unwrap();
+
+        assert_eq!(
+            steps(&plan),
+            [
+                format!(
+                    "Ask Dropbox to quit; running: 100 ({}), 101 ({}) $ {}",
+                    DROPBOX,
+                    FILEPROVIDER,
+                    quit_command()
+                ),
+                format!("Unload {0} $ launchctl bootout gui/501/{0}", AGENT),
+                format!("Disable the {} plist $ mv {} {}", AGENT, plist, disabled),
+                format!(
+                    "Disable extension {0} $ pluginkit -e ignore -i {0}",
+                    FILEPROVIDER_EXTENSION
+                ),
+                "com.getdropbox.dropbox.TransferExtension not found, skip".to_string(),
+                format!(
+                    "Disable extension {0} $ pluginkit -e ignore -i {0}",
+                    GARCON_EXTENSION
+                ),
+                "Restart Finder $ killall Finder".to_string(),
+                format!(
+                    "Wait up to 10s for non-FileProvider processes to stop, and fail if they \
+                     do not: 100 ({})",
+                    DROPBOX
+                ),
+                format!(
+                    "Send SIGTERM to PID 101 ({}), then SIGKILL if still running after 5s $ \
+                     kill -TERM 101",
+                    FILEPROVIDER
+                ),
+                "Wait up to 10s for all Dropbox processes to stop".to_string(),
+                "Verify that Dropbox is off (5 attempts, 500ms apart)".to_string(),
+                format!("Write journal to {}", Journal::path(&mac.layout).display()),
+            ]
+        );
+        assert_eq!(sys.calls().iter().filter(|c| c.mutates()).count(), 0);
+    }
+
+    #[test]
+    fn off_with_force_plans_to_kill_every_process_without_asking() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let options = OffOptions {
+            termination: TerminationPolicy::Force,
+            ..off_options()
+        };
+
+        let plan = plan_off(&sys, &mac.layout, &options).unwrap();
+
+        let steps = steps(&plan);
+        assert_eq!(steps[0], "Skip asking Dropbox to quit (--force)");
+        let finder = steps
+            .iter()
+            .position(|s| s.starts_with("Restart Finder"))
+            .unwrap();
+        assert_eq!(
+            steps[finder + 1..finder + 4],
+            [
+                format!("Send SIGKILL to PID 100 ({}) $ kill -KILL 100", DROPBOX),
+                format!(
+                    "Send SIGKILL to PID 101 ({}) $ kill -KILL 101",
+                    FILEPROVIDER
+                ),
+                "Wait up to 10s for all Dropbox processes to stop".to_string(),
+            ]
+        );
+        assert!(!steps.iter().any(|s| s.contains("SIGTERM")), "{:?}", steps);
+        assert_eq!(sys.calls().iter().filter(|c| c.mutates()).count(), 0);
+    }
+
+    #[test]
+    fn on_plans_to_undo_the_journal_in_order() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        crate::commands::cmd_off(&sys, &mac.layout, &off_options()).unwrap();
+        let plist = quote(&mac.agent_plist());
+        let disabled = quote(&mac.agent_plist().with_extension("plist.disabled"));
+        let changes_before_plan = sys.calls().iter().filter(|c| c.mutates()).count();
+
+        let plan = plan_on(&sys, &mac.layout, &on_options()).unwrap();
+
+        assert_eq!(
+            steps(&plan),
+            [
+                format!(
+                    "Undo the changes recorded in {}",
+                    Journal::path(&mac.layout).display()
+                ),
+                format!("Enable the {} plist $ mv {} {}", AGENT, disabled, plist),
+                format!("Load {} $ launchctl bootstrap gui/501 {}", AGENT, plist),
+                format!(
+                    "Enable extension {0} $ pluginkit -e use -i {0}",
+                    FILEPROVIDER_EXTENSION
+                ),
+                format!(
+                    "Enable optional extension {0} $ pluginkit -e use -i {0}",
+                    GARCON_EXTENSION
+                ),
+                "Launch Dropbox $ open -a Dropbox".to_string(),
+                "Wait up to 10s for Dropbox to start".to_string(),
+                "Verify that Dropbox is on (5 attempts, 500ms apart)".to_string(),
+                "Remove the journal".to_string(),
+            ]
+        );
+        assert_eq!(
+            sys.calls().iter().filter(|c| c.mutates()).count(),
+            changes_before_plan
+        );
+    }
+}
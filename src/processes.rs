@@ -1,71 +1,194 @@
+use crate::backend::{ProcessInfo, Signal, SignalOutcome, SystemBackend};
+use crate::config::{format_duration, Timings};
+use crate::discovery::{Layout, DROPBOX_TEAM_ID};
 use anyhow::{Context, Result};
-use duct::cmd;
-use std::thread;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DropboxProcess {
     pub pid: u32,
+    /// 0 in journals written before the parent was recorded.
+    #[serde(default)]
+    pub ppid: u32,
     pub name: String,
+    /// Together with `start_time`, tells this process apart from a later one that
+    /// reuses its PID.
+    #[serde(default)]
+    pub executable: Option<PathBuf>,
+    #[serde(default)]
+    pub start_time: Option<SystemTime>,
 }
 
-struct DropboxProcessLists {
-    all: Vec<DropboxProcess>,
-    fileprovider: Vec<DropboxProcess>,
-    non_fileprovider: Vec<DropboxProcess>,
-}
-
-fn list_all_dropbox_processes() -> Result<DropboxProcessLists> {
-    let user = std::env::var("USER").context("Could not get USER environment variable")?;
-
-    let output = cmd!("pgrep", "-l", "-u", &user, "-f", "Dropbox")
-        .stdout_capture()
-        .stderr_capture()
-        .unchecked()
-        .run()?;
-
-    // pgrep exit codes:
-    // 0: One or more processes matched
-    // 1: No processes matched
-    // 2: Syntax error in the command line
-    // 3: Fatal error (e.g., out of memory)
-    if !output.status.success() {
-        let exit_code = output.status.code().unwrap_or(-1);
-        if exit_code == 1 {
-            // No processes found, which is fine
-            return Ok(DropboxProcessLists {
-                all: Vec::new(),
-                fileprovider: Vec::new(),
-                non_fileprovider: Vec::new(),
-            });
-        } else {
-            anyhow::bail!("pgrep failed with exit code {}", exit_code);
+impl DropboxProcess {
+    /// Check that `pid` still belongs to this process, returning what differs if not.
+    pub fn verify_identity(&self, sys: &dyn SystemBackend) -> Result<(), String> {
+        let current = match sys.process_info(self.pid) {
+            Ok(Some(current)) => current,
+            Ok(None) => return Err("the process has exited".to_string()),
+            Err(e) => return Err(format!("could not look up the process: {}", e)),
+        };
+
+        if self.start_time.is_none() || current.start_time.is_none() {
+            return Err("start time unknown, so a reused PID cannot be ruled out".to_string());
+        }
+        if current.start_time != self.start_time {
+            return Err(format!(
+                "PID reused: started at {} instead of {}",
+                format_timestamp(current.start_time),
+                format_timestamp(self.start_time)
+            ));
+        }
+        if current.executable != self.executable {
+            return Err(format!(
+                "PID reused: now runs {} instead of {}",
+                display_path(&current.executable),
+                display_path(&self.executable)
+            ));
         }
+        Ok(())
     }
+}
+
+fn format_timestamp(time: Option<SystemTime>) -> String {
+    match time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => format!("{:.6}", since_epoch.as_secs_f64()),
+        None => "unknown".to_string(),
+    }
+}
+
+fn display_path(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => "an unknown executable".to_string(),
+    }
+}
+
+pub struct DropboxProcessLists {
+    pub all: Vec<DropboxProcess>,
+    pub fileprovider: Vec<DropboxProcess>,
+    pub non_fileprovider: Vec<DropboxProcess>,
+}
+
+/// Why a process that mentions Dropbox was or was not treated as part of Dropbox.
+#[derive(Debug, Clone)]
+pub struct Classification {
+    pub process: ProcessInfo,
+    pub team_id: Option<String>,
+    pub is_dropbox: bool,
+    pub reason: String,
+}
+
+impl Classification {
+    fn is_fileprovider(&self) -> bool {
+        self.process
+            .executable
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .is_some_and(|name| name.to_string_lossy().contains("DropboxFileProvider"))
+    }
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+/// Classify every process of the current user whose executable or command line
+/// mentions Dropbox. A process belongs to Dropbox only if its executable lives inside
+/// the discovered Dropbox.app and is signed by Dropbox's team ID; an editor or shell
+/// that merely has a file in the Dropbox folder open does not.
+pub fn classify_processes(sys: &dyn SystemBackend, layout: &Layout) -> Result<Vec<Classification>> {
+    let app = layout
+        .find_dropbox_app()
+        .ok()
+        .map(|path| layout.unresolve(path));
+    let uid: u32 = sys.user_id()?.parse().context("Failed to parse user ID")?;
+
+    let mut classifications = Vec::new();
+    for process in sys.list_processes()? {
+        let mentions_dropbox = process.command_line().contains("Dropbox")
+            || process
+                .executable
+                .as_ref()
+                .is_some_and(|exe| exe.to_string_lossy().contains("Dropbox"));
+        if process.uid != uid || !mentions_dropbox {
+            continue;
+        }
+
+        let mut team_id = None;
+        let (is_dropbox, reason) = match (&process.executable, &app) {
+            (None, _) => (
+                false,
+                "executable unknown (the process may have exited)".to_string(),
+            ),
+            (Some(_), None) => (false, "Dropbox.app not found".to_string()),
+            (Some(exe), Some(app)) if !exe.starts_with(app) => {
+                (false, format!("executable is outside {}", app.display()))
+            }
+            (Some(exe), Some(app)) => {
+                team_id = sys.code_signing_team_id(exe)?;
+                match &team_id {
+                    Some(id) if id == DROPBOX_TEAM_ID => (
+                        true,
+                        format!(
+                            "executable is inside {} and signed by team {}",
+                            app.display(),
+                            id
+                        ),
+                    ),
+                    Some(id) => (
+                        false,
+                        format!(
+                            "executable is signed by team {}, not Dropbox's {}",
+                            id, DROPBOX_TEAM_ID
+                        ),
+                    ),
+                    None => (
+                        false,
+                        format!(
+                            "executable is not signed by Dropbox's team {}",
+                            DROPBOX_TEAM_ID
+                        ),
+                    ),
+                }
+            }
+        };
+
+        classifications.push(Classification {
+            process,
+            team_id,
+            is_dropbox,
+            reason,
+        });
+    }
+
+    Ok(classifications)
+}
+
+pub fn list_all_dropbox_processes(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+) -> Result<DropboxProcessLists> {
     let mut all = Vec::new();
     let mut fileprovider = Vec::new();
     let mut non_fileprovider = Vec::new();
 
-    for line in stdout.lines() {
-        let parts: Vec<&str> = line.splitn(2, ' ').collect();
-        if parts.len() >= 2 {
-            if let Ok(pid) = parts[0].parse::<u32>() {
-                let name = parts[1].to_string();
-                let process = DropboxProcess {
-                    pid,
-                    name: name.clone(),
-                };
-
-                all.push(process.clone());
-
-                if name.contains("DropboxFileProvider") {
-                    fileprovider.push(process);
-                } else {
-                    non_fileprovider.push(process);
-                }
-            }
+    for classification in classify_processes(sys, layout)? {
+        if !classification.is_dropbox {
+            continue;
+        }
+        let process = DropboxProcess {
+            pid: classification.process.pid,
+            ppid: classification.process.ppid,
+            name: classification.process.command_line(),
+            executable: classification.process.executable.clone(),
+            start_time: classification.process.start_time,
+        };
+
+        all.push(process.clone());
+
+        if classification.is_fileprovider() {
+            fileprovider.push(process);
+        } else {
+            non_fileprovider.push(process);
         }
     }
 
@@ -76,35 +199,48 @@ fn list_all_dropbox_processes() -> Result<DropboxProcessLists> {
     })
 }
 
-pub fn list_dropbox_processes() -> Result<Vec<DropboxProcess>> {
-    Ok(list_all_dropbox_processes()?.all)
+pub fn list_dropbox_processes(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+) -> Result<Vec<DropboxProcess>> {
+    Ok(list_all_dropbox_processes(sys, layout)?.all)
 }
 
-pub fn quit_dropbox_gracefully() -> Result<()> {
-    let script = r#"tell application "Dropbox" to quit"#;
-    cmd!("osascript", "-e", script)
-        .stdout_null()
-        .stderr_null()
-        .unchecked()
-        .run()
-        .context("Failed to send quit request to Dropbox")?;
-    Ok(())
+pub fn quit_dropbox_gracefully(sys: &dyn SystemBackend) -> Result<()> {
+    sys.quit_dropbox()
 }
 
-pub fn wait_for_processes_to_die(timeout_secs: u64) -> Result<()> {
-    wait_for_processes_to_die_impl(timeout_secs, false)
+/// Wait up to `timings.stop_timeout` for every Dropbox process to exit.
+pub fn wait_for_processes_to_die(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timings: &Timings,
+) -> Result<()> {
+    wait_for_processes_to_die_impl(sys, layout, timings.stop_timeout, "stop-timeout", false)
 }
 
-pub fn wait_for_non_fileprovider_processes_to_die(timeout_secs: u64) -> Result<()> {
-    wait_for_processes_to_die_impl(timeout_secs, true)
+/// Wait up to `timings.quit_timeout` for every process except DropboxFileProvider to exit.
+pub fn wait_for_non_fileprovider_processes_to_die(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timings: &Timings,
+) -> Result<()> {
+    wait_for_processes_to_die_impl(sys, layout, timings.quit_timeout, "quit-timeout", true)
 }
 
-fn wait_for_processes_to_die_impl(timeout_secs: u64, exclude_fileprovider: bool) -> Result<()> {
-    let start = std::time::Instant::now();
-    let timeout = Duration::from_secs(timeout_secs);
+fn wait_for_processes_to_die_impl(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timeout: Duration,
+    setting: &str,
+    exclude_fileprovider: bool,
+) -> Result<()> {
+    let clock = sys.clock();
+    let start = clock.now();
 
+    // The list is re-read after every watch to catch processes that started meanwhile.
     loop {
-        let process_lists = list_all_dropbox_processes()?;
+        let process_lists = list_all_dropbox_processes(sys, layout)?;
         let processes = if exclude_fileprovider {
             &process_lists.non_fileprovider
         } else {
@@ -115,62 +251,305 @@ fn wait_for_processes_to_die_impl(timeout_secs: u64, exclude_fileprovider: bool)
             return Ok(());
         }
 
-        if start.elapsed() > timeout {
+        let elapsed = clock.now() - start;
+        if elapsed >= timeout {
             let process_type = if exclude_fileprovider {
                 "non-FileProvider Dropbox"
             } else {
                 "Dropbox"
             };
             anyhow::bail!(
-                "Timeout waiting for {} processes to stop. {} still running: {:?}",
+                "Timed out after {} ({} = {}) waiting for {} processes to stop. {} still running: {:?}",
+                format_duration(elapsed),
+                setting,
+                format_duration(timeout),
                 process_type,
                 processes.len(),
                 processes.iter().map(|p| &p.name).collect::<Vec<_>>()
             );
         }
 
-        thread::sleep(Duration::from_millis(100));
+        watch_exits(sys, processes, timeout - elapsed)?;
+    }
+}
+
+/// Block until `processes` have exited or `timeout` has passed, logging each exit in
+/// the order it happened. Returns the processes that are still running.
+fn watch_exits(
+    sys: &dyn SystemBackend,
+    processes: &[DropboxProcess],
+    timeout: Duration,
+) -> Result<Vec<DropboxProcess>> {
+    let pids: Vec<u32> = processes.iter().map(|p| p.pid).collect();
+    let exits = sys.wait_for_exits(&pids, timeout)?;
+
+    for exit in &exits {
+        if let Some(process) = processes.iter().find(|p| p.pid == exit.pid) {
+            info!(
+                "  PID {} ({}) exited after {}",
+                process.pid,
+                process.name,
+                format_duration(exit.elapsed)
+            );
+        }
     }
+
+    Ok(processes
+        .iter()
+        .filter(|p| !exits.iter().any(|exit| exit.pid == p.pid))
+        .cloned()
+        .collect())
 }
 
-pub fn launch_dropbox() -> Result<()> {
-    cmd!("open", "-a", "Dropbox")
-        .stdout_null()
-        .stderr_null()
-        .run()
-        .context("Failed to launch Dropbox")?;
-    Ok(())
+pub fn launch_dropbox(sys: &dyn SystemBackend) -> Result<()> {
+    sys.launch_dropbox()
 }
 
-pub fn wait_for_dropbox_to_start(timeout_secs: u64) -> Result<()> {
-    let start = std::time::Instant::now();
-    let timeout = Duration::from_secs(timeout_secs);
+/// Wait up to `timings.start_timeout` for a Dropbox process to appear.
+pub fn wait_for_dropbox_to_start(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    timings: &Timings,
+) -> Result<()> {
+    let clock = sys.clock();
+    let start = clock.now();
 
     loop {
-        let processes = list_dropbox_processes()?;
+        let processes = list_dropbox_processes(sys, layout)?;
         if !processes.is_empty() {
             return Ok(());
         }
 
-        if start.elapsed() > timeout {
-            anyhow::bail!("Timeout waiting for Dropbox to start");
+        if clock.now() - start > timings.start_timeout {
+            anyhow::bail!(
+                "Timed out after {} (start-timeout = {}, polling every {}) waiting for Dropbox to start",
+                format_duration(clock.now() - start),
+                format_duration(timings.start_timeout),
+                format_duration(timings.poll_interval)
+            );
         }
 
-        thread::sleep(Duration::from_millis(100));
+        clock.sleep(timings.poll_interval);
     }
 }
 
-pub fn kill_fileprovider_processes() -> Result<()> {
-    let process_lists = list_all_dropbox_processes()?;
+/// How `off` gets rid of Dropbox processes that are still running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationPolicy {
+    /// SIGTERM, then SIGKILL for whatever is still running after `term-grace`.
+    Escalate,
+    /// SIGKILL straight away.
+    Force,
+}
 
-    for process in &process_lists.fileprovider {
-        let _ = cmd!("kill", process.pid.to_string())
-            .stdout_null()
-            .stderr_null()
-            .unchecked()
-            .run();
-        // Ignore errors - process may have already exited
+/// The result of sending one signal to one process.
+#[derive(Debug, Clone)]
+pub struct SignalReport {
+    pub process: DropboxProcess,
+    pub signal: Signal,
+    pub outcome: SignalOutcome,
+}
+
+fn send(
+    sys: &dyn SystemBackend,
+    processes: &[DropboxProcess],
+    signal: Signal,
+) -> Vec<SignalReport> {
+    processes
+        .iter()
+        .map(|process| {
+            // Checked right before signalling, so that a PID that was reused since the
+            // process list was read is never signalled.
+            let outcome = match process.verify_identity(sys) {
+                Ok(()) => sys.signal(process.pid, signal),
+                Err(reason) => SignalOutcome::Skipped(reason),
+            };
+            let message = format!(
+                "  SIG{} → PID {} ({}): {}",
+                signal.as_str(),
+                process.pid,
+                process.name,
+                outcome.describe()
+            );
+            if matches!(outcome, SignalOutcome::Skipped(_)) {
+                warn!("{}", message);
+            } else {
+                info!("{}", message);
+            }
+            SignalReport {
+                process: process.clone(),
+                signal,
+                outcome,
+            }
+        })
+        .collect()
+}
+
+/// Terminate every running Dropbox process according to `policy`, reporting the
+/// outcome for each PID and signal. Fails if a process that is still running could
+/// not be signalled.
+pub fn terminate_processes(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    policy: TerminationPolicy,
+    timings: &Timings,
+) -> Result<Vec<SignalReport>> {
+    let running = list_dropbox_processes(sys, layout)?;
+    if running.is_empty() {
+        info!("  No Dropbox processes running");
+        return Ok(Vec::new());
     }
 
-    Ok(())
+    let mut reports = Vec::new();
+    let mut remaining = running;
+    if policy == TerminationPolicy::Escalate {
+        reports.extend(send(sys, &remaining, Signal::Term));
+        remaining = watch_exits(sys, &remaining, timings.term_grace)?;
+
+        if !remaining.is_empty() {
+            warn!(
+                "  {} process(es) still running after {} (term-grace), escalating to SIGKILL",
+                remaining.len(),
+                format_duration(timings.term_grace)
+            );
+        }
+    }
+    let killed = send(sys, &remaining, Signal::Kill);
+
+    // Anything SIGKILL could not reach is still running.
+    let failed: Vec<&SignalReport> = killed
+        .iter()
+        .filter(|r| {
+            matches!(
+                r.outcome,
+                SignalOutcome::PermissionDenied | SignalOutcome::Failed(_)
+            )
+        })
+        .collect();
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "Could not signal {} Dropbox process(es): {}",
+            failed.len(),
+            failed
+                .iter()
+                .map(|r| format!(
+                    "PID {} ({}): SIG{} {}",
+                    r.process.pid,
+                    r.process.name,
+                    r.signal.as_str(),
+                    r.outcome.describe()
+                ))
+                .collect::<Vec<_>>()
+                .join("; ")
+        );
+    }
+
+    reports.extend(killed);
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Call, FakeBackend};
+    use crate::testutil::*;
+
+    #[test]
+    fn only_signed_executables_inside_dropbox_app_are_dropbox() {
+        let mac = TestMac::new();
+        let sys = FakeBackend::new()
+            .with_process(200, "/Applications/Dropbox.app/Contents/MacOS/Impostor")
+            .with_process(201, "/usr/bin/vim /Users/me/Dropbox/notes.txt")
+            .with_process(202, "/usr/bin/tail -f /Users/me/Dropbox/Logs/sync.log")
+            .with_process(203, "/Users/me/Dropbox/bin/Dropbox --sync")
+            .with_process(100, DROPBOX)
+            .with_process(101, FILEPROVIDER)
+            .with_process(300, "/bin/zsh");
+        // The first executable inside Dropbox.app is signed by someone else.
+        sys.script("code_signing_team_id", Ok("ABCDE12345".to_string()));
+
+        let classifications = classify_processes(&sys, &mac.layout).unwrap();
+
+        let verdicts: Vec<(u32, bool, &str)> = classifications
+            .iter()
+            .map(|c| (c.process.pid, c.is_dropbox, c.reason.as_str()))
+            .collect();
+        assert_eq!(
+            verdicts,
+            [
+                (
+                    200,
+                    false,
+                    "executable is signed by team ABCDE12345, not Dropbox's G7HH3F8CAK"
+                ),
+                (
+                    201,
+                    false,
+                    "executable is outside /Applications/Dropbox.app"
+                ),
+                (
+                    202,
+                    false,
+                    "executable is outside /Applications/Dropbox.app"
+                ),
+                (
+                    203,
+                    false,
+                    "executable is outside /Applications/Dropbox.app"
+                ),
+                (
+                    100,
+                    true,
+                    "executable is inside /Applications/Dropbox.app and signed by team \
+                     G7HH3F8CAK"
+                ),
+                (
+                    101,
+                    true,
+                    "executable is inside /Applications/Dropbox.app and signed by team \
+                     G7HH3F8CAK"
+                ),
+            ]
+        );
+        sys.script("code_signing_team_id", Ok("ABCDE12345".to_string()));
+        let lists = list_all_dropbox_processes(&sys, &mac.layout).unwrap();
+        let pids = |processes: &[DropboxProcess]| -> Vec<u32> {
+            processes.iter().map(|p| p.pid).collect()
+        };
+        assert_eq!(pids(&lists.all), [100, 101]);
+        assert_eq!(pids(&lists.fileprovider), [101]);
+        assert_eq!(pids(&lists.non_fileprovider), [100]);
+    }
+
+    #[test]
+    fn a_pid_reused_after_listing_is_not_signalled() {
+        for policy in [TerminationPolicy::Escalate, TerminationPolicy::Force] {
+            let mac = TestMac::new();
+            let sys = running_dropbox();
+            sys.recycle_pid_after_listing(101, "/usr/bin/vim notes.txt");
+
+            let reports =
+                terminate_processes(&sys, &mac.layout, policy, &Timings::default()).unwrap();
+
+            assert!(
+                !sys.calls()
+                    .iter()
+                    .any(|c| matches!(c, Call::Signal(101, _))),
+                "{:?}",
+                policy
+            );
+            assert!(sys.running_pids().contains(&101));
+            assert!(!sys.running_pids().contains(&100));
+            let skipped = reports
+                .iter()
+                .find(|r| r.process.pid == 101)
+                .expect("PID 101 reported");
+            match &skipped.outcome {
+                SignalOutcome::Skipped(reason) => {
+                    assert!(reason.starts_with("PID reused: started at"), "{}", reason)
+                }
+                outcome => panic!("{:?}: {:?}", policy, outcome),
+            }
+        }
+    }
 }
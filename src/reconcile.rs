@@ -0,0 +1,482 @@
+//! `set`: the state each component should be in, and how far the current state is from
+//! it. The same goals decide what `set` changes and what it verifies afterwards.
+
+use crate::backend::SystemBackend;
+use crate::discovery::Layout;
+use crate::extensions::{self, ExtensionPolicy};
+use crate::launchagent::{LaunchAgentStrategy, LaunchdJob};
+use crate::state::Target;
+use crate::status::{LaunchAgentState, Status};
+use crate::verify::Expectation;
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProcessesState {
+    Running,
+    Stopped,
+}
+
+impl ProcessesState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessesState::Running => "running",
+            ProcessesState::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ComponentState {
+    Enabled,
+    Disabled,
+}
+
+impl ComponentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ComponentState::Enabled => "enabled",
+            ComponentState::Disabled => "disabled",
+        }
+    }
+
+    /// The `off`/`on` target whose extension policies apply.
+    pub fn target(&self) -> Target {
+        match self {
+            ComponentState::Enabled => Target::On,
+            ComponentState::Disabled => Target::Off,
+        }
+    }
+}
+
+/// The state `set` brings components to. Components left out are not touched. This is
+/// both the contents of a desired-state file and what `COMPONENT=STATE` arguments
+/// build.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DesiredState {
+    pub processes: Option<ProcessesState>,
+    /// Every Dropbox LaunchAgent and LaunchDaemon.
+    #[serde(alias = "agents")]
+    pub agent: Option<ComponentState>,
+    pub extensions: Option<ComponentState>,
+}
+
+impl DesiredState {
+    /// Parse `COMPONENT=STATE` arguments, e.g. `processes=stopped agent=disabled`.
+    pub fn parse_assignments(assignments: &[String]) -> Result<Self> {
+        let mut table = toml::Table::new();
+        for assignment in assignments {
+            let Some((key, value)) = assignment.split_once('=') else {
+                anyhow::bail!("Expected COMPONENT=STATE, got {:?}", assignment);
+            };
+            let key = key.trim();
+            if table
+                .insert(
+                    key.to_string(),
+                    toml::Value::String(value.trim().to_string()),
+                )
+                .is_some()
+            {
+                anyhow::bail!("{} is given more than once", key);
+            }
+        }
+        toml::Value::Table(table).try_into().map_err(|e| {
+            let message = e.to_string();
+            anyhow::anyhow!(
+                "Invalid desired state: {}",
+                message.trim_end().replace('\n', " ")
+            )
+        })
+    }
+
+    /// Load a desired-state file: a TOML document with the same keys as the arguments.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// The components `other` sets replace those in `self`.
+    pub fn merge(self, other: DesiredState) -> DesiredState {
+        DesiredState {
+            processes: other.processes.or(self.processes),
+            agent: other.agent.or(self.agent),
+            extensions: other.extensions.or(self.extensions),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &DesiredState::default()
+    }
+
+    /// `processes=stopped agent=disabled`, in the order the components are applied.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(agent) = self.agent {
+            parts.push(format!("agent={}", agent.as_str()));
+        }
+        if let Some(extensions) = self.extensions {
+            parts.push(format!("extensions={}", extensions.as_str()));
+        }
+        if let Some(processes) = self.processes {
+            parts.push(format!("processes={}", processes.as_str()));
+        }
+        parts.join(" ")
+    }
+}
+
+/// One thing that must hold for a component to be in its desired state.
+#[derive(Debug, Clone)]
+pub struct Goal {
+    pub expectation: Expectation,
+    /// Whether verification fails when the goal is not met. Optional extensions are
+    /// changed, but only warned about.
+    pub required: bool,
+}
+
+/// What `set` manages for a [`DesiredState`], given the components found.
+#[derive(Debug, Clone, Default)]
+pub struct Reconciliation {
+    pub goals: Vec<Goal>,
+    /// Components the desired state covers that are left alone, and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl Reconciliation {
+    /// The goals `status` does not meet yet, in the order they are applied.
+    pub fn pending(&self, status: &Status) -> Vec<&Goal> {
+        self.goals
+            .iter()
+            .filter(|g| !g.expectation.check(status).1)
+            .collect()
+    }
+
+    /// The jobs with a goal `status` does not meet, each once.
+    pub fn pending_jobs(&self, status: &Status) -> Vec<&LaunchdJob> {
+        let mut jobs: Vec<&LaunchdJob> = Vec::new();
+        for goal in self.pending(status) {
+            if let Expectation::JobEnabled { job, .. } | Expectation::JobLoaded { job, .. } =
+                &goal.expectation
+            {
+                if !jobs.iter().any(|j| j.plist == job.plist) {
+                    jobs.push(job);
+                }
+            }
+        }
+        jobs
+    }
+
+    /// The extensions whose goal `status` does not meet.
+    pub fn pending_extensions(&self, status: &Status) -> Vec<&str> {
+        self.pending(status)
+            .into_iter()
+            .filter_map(|goal| match &goal.expectation {
+                Expectation::ExtensionEnabled { bundle_id, .. } => Some(bundle_id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// What verification checks once the changes are made.
+    pub fn expectations(&self) -> Vec<Expectation> {
+        self.goals
+            .iter()
+            .filter(|g| g.required)
+            .map(|g| g.expectation.clone())
+            .collect()
+    }
+}
+
+/// Why `job` cannot be brought to `state`, if it cannot. Enabling may take a rename as
+/// well as `launchctl enable`.
+pub fn unchangeable_reason(
+    sys: &dyn SystemBackend,
+    job: &LaunchdJob,
+    state: ComponentState,
+    strategy: LaunchAgentStrategy,
+) -> Result<Option<String>> {
+    match state {
+        ComponentState::Disabled => job.unchangeable_reason(sys, strategy),
+        ComponentState::Enabled => {
+            match job.unchangeable_reason(sys, LaunchAgentStrategy::Rename)? {
+                Some(reason) => Ok(Some(reason)),
+                None => job.unchangeable_reason(sys, LaunchAgentStrategy::LaunchctlDisable),
+            }
+        }
+    }
+}
+
+/// The goals for `desired`, ordered LaunchAgents first, then extensions, then processes,
+/// which is the order `set` applies them in.
+pub fn reconcile(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    status: &Status,
+    desired: &DesiredState,
+    strategy: LaunchAgentStrategy,
+) -> Result<Reconciliation> {
+    let mut goals = Vec::new();
+    let mut skipped = Vec::new();
+    let mut goal = |expectation, required| {
+        goals.push(Goal {
+            expectation,
+            required,
+        })
+    };
+
+    if let Some(state) = desired.agent {
+        let enabled = state == ComponentState::Enabled;
+        for agent in &status.launch_agents {
+            let job = &agent.job;
+            let component = format!("launch-agent:{}", job.label);
+            if agent.state == LaunchAgentState::Missing {
+                skipped.push((component, "no plist found".to_string()));
+                continue;
+            }
+            if let Some(reason) = unchangeable_reason(sys, job, state, strategy)? {
+                skipped.push((component, reason));
+                continue;
+            }
+            goal(
+                Expectation::JobEnabled {
+                    job: job.clone(),
+                    enabled,
+                },
+                true,
+            );
+            goal(
+                Expectation::JobLoaded {
+                    job: job.clone(),
+                    loaded: enabled,
+                },
+                true,
+            );
+        }
+    }
+
+    if let Some(state) = desired.extensions {
+        for bundle_id in extensions::dropbox_bundle_ids(layout)? {
+            let policy = extensions::policy(&bundle_id, state.target());
+            if policy == ExtensionPolicy::Ignored {
+                skipped.push((
+                    bundle_id,
+                    format!("ignored when turning Dropbox {}", state.target().as_str()),
+                ));
+                continue;
+            }
+            goal(
+                Expectation::ExtensionEnabled {
+                    bundle_id,
+                    path: None,
+                    enabled: state == ComponentState::Enabled,
+                },
+                policy == ExtensionPolicy::Required,
+            );
+        }
+    }
+
+    if let Some(state) = desired.processes {
+        goal(
+            Expectation::ProcessesRunning(state == ProcessesState::Running),
+            true,
+        );
+    }
+
+    Ok(Reconciliation { goals, skipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status;
+    use crate::testutil::*;
+
+    fn assignments(args: &[&str]) -> Result<DesiredState> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        DesiredState::parse_assignments(&args)
+    }
+
+    fn components(goals: &[&Goal]) -> Vec<String> {
+        goals
+            .iter()
+            .map(|g| format!("{}={}", g.expectation.component(), g.expectation.expected()))
+            .collect()
+    }
+
+    #[test]
+    fn assignments_parse_into_a_desired_state() {
+        let desired = assignments(&["processes=stopped", " agents = disabled "]).unwrap();
+        assert_eq!(
+            desired,
+            DesiredState {
+                processes: Some(ProcessesState::Stopped),
+                agent: Some(ComponentState::Disabled),
+                extensions: None,
+            }
+        );
+        assert_eq!(desired.describe(), "agent=disabled processes=stopped");
+        assert!(assignments(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_assignments_are_rejected() {
+        let error = |args: &[&str]| assignments(args).unwrap_err().to_string();
+
+        assert_eq!(
+            error(&["processes"]),
+            "Expected COMPONENT=STATE, got \"processes\""
+        );
+        assert_eq!(
+            error(&["processes=stopped", "processes=running"]),
+            "processes is given more than once"
+        );
+        let unknown_component = error(&["daemons=disabled"]);
+        assert!(
+            unknown_component.starts_with("Invalid desired state:")
+                && unknown_component.contains("daemons"),
+            "{}",
+            unknown_component
+        );
+        let unknown_state = error(&["extensions=paused"]);
+        assert!(
+            unknown_state.starts_with("Invalid desired state:") && unknown_state.contains("paused"),
+            "{}",
+            unknown_state
+        );
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_desired_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("desired.toml");
+        fs::write(&path, "processes = \"running\"\nagent = \"disabled\"\n").unwrap();
+
+        let file = DesiredState::load(&path).unwrap();
+        let args = assignments(&["processes=stopped", "extensions=enabled"]).unwrap();
+        assert_eq!(
+            file.merge(args),
+            DesiredState {
+                processes: Some(ProcessesState::Stopped),
+                agent: Some(ComponentState::Disabled),
+                extensions: Some(ComponentState::Enabled),
+            }
+        );
+
+        fs::write(&path, "processes = \"paused\"\n").unwrap();
+        let error = DesiredState::load(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Failed to parse"), "{}", error);
+    }
+
+    #[test]
+    fn goals_already_met_are_not_pending() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let status = status::get_status(&sys, &mac.layout).unwrap();
+        let desired =
+            assignments(&["processes=running", "agent=enabled", "extensions=enabled"]).unwrap();
+
+        let reconciliation = reconcile(
+            &sys,
+            &mac.layout,
+            &status,
+            &desired,
+            LaunchAgentStrategy::Rename,
+        )
+        .unwrap();
+
+        assert!(reconciliation.skipped.is_empty());
+        assert!(reconciliation.pending(&status).is_empty());
+        assert!(reconciliation.pending_jobs(&status).is_empty());
+        assert!(reconciliation.pending_extensions(&status).is_empty());
+        // garcon is only optional while Dropbox is on, so it is not verified.
+        let verified: Vec<String> = reconciliation
+            .expectations()
+            .iter()
+            .map(|e| e.component())
+            .collect();
+        assert!(verified.contains(&FILEPROVIDER_EXTENSION.to_string()));
+        assert!(!verified.contains(&GARCON_EXTENSION.to_string()));
+    }
+
+    #[test]
+    fn unmet_goals_are_pending_in_the_order_they_are_applied() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let status = status::get_status(&sys, &mac.layout).unwrap();
+        let desired =
+            assignments(&["processes=stopped", "agent=disabled", "extensions=disabled"]).unwrap();
+
+        let reconciliation = reconcile(
+            &sys,
+            &mac.layout,
+            &status,
+            &desired,
+            LaunchAgentStrategy::Rename,
+        )
+        .unwrap();
+
+        let pending = reconciliation.pending(&status);
+        assert_eq!(
+            components(&pending),
+            [
+                format!("launch-agent:{}=disabled", AGENT),
+                format!("launchd:{}=not loaded", AGENT),
+                format!("{}=disabled", FILEPROVIDER_EXTENSION),
+                format!("{}=disabled", GARCON_EXTENSION),
+                "processes=none running".to_string(),
+            ]
+        );
+        let jobs = reconciliation.pending_jobs(&status);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].label, AGENT);
+        assert_eq!(
+            reconciliation.pending_extensions(&status),
+            [FILEPROVIDER_EXTENSION, GARCON_EXTENSION]
+        );
+        assert!(pending.iter().all(|g| g.required));
+    }
+
+    #[test]
+    fn agents_without_a_plist_are_skipped() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let status = status::get_status(&sys, &mac.layout).unwrap();
+        fs::remove_file(mac.agent_plist()).unwrap();
+        let status = Status {
+            launch_agents: status
+                .launch_agents
+                .into_iter()
+                .map(|mut agent| {
+                    agent.state = LaunchAgentState::Missing;
+                    agent
+                })
+                .collect(),
+            ..status
+        };
+        let desired = assignments(&["agent=disabled"]).unwrap();
+
+        let reconciliation = reconcile(
+            &sys,
+            &mac.layout,
+            &status,
+            &desired,
+            LaunchAgentStrategy::Rename,
+        )
+        .unwrap();
+
+        assert!(reconciliation.goals.is_empty());
+        assert_eq!(
+            reconciliation.skipped,
+            [(
+                format!("launch-agent:{}", AGENT),
+                "no plist found".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! `repair`: bring pluginkit's registrations of the Dropbox extensions in line with
+//! the installed Dropbox.app, after an update or a stale copy of the app left them
+//! missing or duplicated.
+
+use crate::backend::SystemBackend;
+use crate::discovery::Layout;
+use crate::extensions::{self, PluginRegistration};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// A change `repair` makes to pluginkit's registrations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairStep {
+    /// Register an extension Dropbox.app ships that pluginkit has no registration of
+    /// at that path (`pluginkit -a`).
+    Add { bundle_id: String, path: PathBuf },
+    /// Remove a registration whose bundle no longer exists or that duplicates one
+    /// inside the installed Dropbox.app (`pluginkit -r`).
+    Remove {
+        bundle_id: String,
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl RepairStep {
+    pub fn describe(&self) -> String {
+        match self {
+            RepairStep::Add { bundle_id, path } => {
+                format!("register {} from {}", bundle_id, path.display())
+            }
+            RepairStep::Remove {
+                bundle_id,
+                path,
+                reason,
+            } => format!(
+                "remove the registration of {} at {} ({})",
+                bundle_id,
+                path.display(),
+                reason
+            ),
+        }
+    }
+
+    pub fn command(&self) -> String {
+        match self {
+            RepairStep::Add { path, .. } => format!("pluginkit -a {}", path.display()),
+            RepairStep::Remove { path, .. } => format!("pluginkit -r {}", path.display()),
+        }
+    }
+
+    pub fn apply(&self, sys: &dyn SystemBackend) -> Result<()> {
+        match self {
+            RepairStep::Add { path, .. } => sys.pluginkit_add(path),
+            RepairStep::Remove { path, .. } => sys.pluginkit_remove(path),
+        }
+    }
+}
+
+/// Every registration of a Dropbox extension bundle ID, known or shipped in
+/// Dropbox.app.
+pub fn registrations(sys: &dyn SystemBackend, layout: &Layout) -> Result<Vec<PluginRegistration>> {
+    let mut registrations = Vec::new();
+    for bundle_id in extensions::dropbox_bundle_ids(layout)? {
+        registrations.extend(extensions::get_registrations(sys, &bundle_id)?);
+    }
+    Ok(registrations)
+}
+
+/// What `repair` would change, given the current `registrations`. Extensions the
+/// installed Dropbox.app ships are registered first, then stale registrations are
+/// removed.
+pub fn plan_repair(
+    layout: &Layout,
+    registrations: &[PluginRegistration],
+) -> Result<Vec<RepairStep>> {
+    let app = layout.find_dropbox_app()?;
+    let system_app = layout.unresolve(&app);
+    // pluginkit reports paths as they were registered, which is beneath the root for
+    // bundles `repair` registered, so compare them as paths on the target system.
+    let registered_at = |bundle_id: &str, path: &Path| {
+        registrations.iter().any(|r| {
+            r.bundle_id == bundle_id
+                && r.path
+                    .as_deref()
+                    .is_some_and(|p| layout.unresolve(p) == layout.unresolve(path))
+        })
+    };
+
+    let mut steps = Vec::new();
+    let installed = extensions::find_bundled_extensions(&app)?;
+    for extension in &installed {
+        if !registered_at(&extension.bundle_id, &extension.path) {
+            steps.push(RepairStep::Add {
+                bundle_id: extension.bundle_id.clone(),
+                path: extension.path.clone(),
+            });
+        }
+    }
+
+    for registration in registrations {
+        let Some(path) = &registration.path else {
+            continue;
+        };
+        let system_path = layout.unresolve(path);
+        let reason = if !layout.resolve(&system_path).exists() {
+            "its bundle no longer exists".to_string()
+        } else if !system_path.starts_with(&system_app)
+            && installed
+                .iter()
+                .any(|e| e.bundle_id == registration.bundle_id)
+        {
+            format!("duplicates the one in {}", system_app.display())
+        } else {
+            continue;
+        };
+        steps.push(RepairStep::Remove {
+            bundle_id: registration.bundle_id.clone(),
+            path: path.clone(),
+            reason,
+        });
+    }
+
+    Ok(steps)
+}
+
+/// One line per registration that appeared, disappeared or changed election between
+/// `before` and `after`, prefixed with `+`, `-` or `~`.
+pub fn diff_registrations(
+    before: &[PluginRegistration],
+    after: &[PluginRegistration],
+) -> Vec<String> {
+    let same = |a: &PluginRegistration, b: &PluginRegistration| {
+        a.bundle_id == b.bundle_id && a.path == b.path
+    };
+    let describe = |r: &PluginRegistration| {
+        let path = r
+            .path
+            .as_deref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "(no path)".to_string());
+        format!(
+            "{} {} ({})",
+            r.bundle_id,
+            path,
+            r.version.as_deref().unwrap_or("no version")
+        )
+    };
+
+    let mut lines = Vec::new();
+    for old in before {
+        match after.iter().find(|new| same(old, new)) {
+            None => lines.push(format!("- {}", describe(old))),
+            Some(new) if new.election != old.election => lines.push(format!(
+                "~ {}: {} → {}",
+                describe(new),
+                old.election.as_str(),
+                new.election.as_str()
+            )),
+            Some(_) => {}
+        }
+    }
+    for new in after {
+        if !before.iter().any(|old| same(old, new)) {
+            lines.push(format!("+ {}: {}", describe(new), new.election.as_str()));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::extensions::parse_pluginkit;
+    use crate::testutil::*;
+    use std::fs;
+
+    const TRANSFER_EXTENSION: &str = "com.getdropbox.dropbox.TransferExtension";
+    const APP_FILEPROVIDER: &str = "/Applications/Dropbox.app/Contents/PlugIns/FileProvider.appex";
+    const STALE_FILEPROVIDER: &str =
+        "/Users/me/Downloads/Dropbox.app/Contents/PlugIns/FileProvider.appex";
+    const DELETED_TRANSFER: &str = "/Applications/Dropbox.app/Contents/PlugIns/Transfer.appex";
+
+    /// Dropbox.app shipping the fileprovider and garcon, with pluginkit knowing the
+    /// fileprovider from it and from a stale copy in Downloads, a TransferExtension
+    /// whose bundle is gone, and no garcon.
+    fn stale_registrations() -> (TestMac, FakeBackend) {
+        let mac = TestMac::new()
+            .with_bundled_extension("PlugIns/FileProvider.appex", FILEPROVIDER_EXTENSION)
+            .with_bundled_extension("PlugIns/Garcon.appex", GARCON_EXTENSION);
+        fs::create_dir_all(mac.layout.resolve(STALE_FILEPROVIDER)).unwrap();
+        let sys = FakeBackend::new()
+            .with_registration(FILEPROVIDER_EXTENSION, Path::new(APP_FILEPROVIDER), true)
+            .with_registration(FILEPROVIDER_EXTENSION, Path::new(STALE_FILEPROVIDER), false)
+            .with_registration(TRANSFER_EXTENSION, Path::new(DELETED_TRANSFER), true);
+        (mac, sys)
+    }
+
+    #[test]
+    fn plans_to_register_missing_extensions_and_remove_stale_ones() {
+        let (mac, sys) = stale_registrations();
+        let before = registrations(&sys, &mac.layout).unwrap();
+
+        let steps = plan_repair(&mac.layout, &before).unwrap();
+
+        // The fileprovider is registered at its path on the target system, which is
+        // where the bundle beneath the root is.
+        assert_eq!(
+            steps,
+            [
+                RepairStep::Add {
+                    bundle_id: GARCON_EXTENSION.to_string(),
+                    path: mac.bundled_extension("PlugIns/Garcon.appex"),
+                },
+                RepairStep::Remove {
+                    bundle_id: FILEPROVIDER_EXTENSION.to_string(),
+                    path: PathBuf::from(STALE_FILEPROVIDER),
+                    reason: "duplicates the one in /Applications/Dropbox.app".to_string(),
+                },
+                RepairStep::Remove {
+                    bundle_id: TRANSFER_EXTENSION.to_string(),
+                    path: PathBuf::from(DELETED_TRANSFER),
+                    reason: "its bundle no longer exists".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn repaired_registrations_need_no_further_repair() {
+        let (mac, sys) = stale_registrations();
+        let before = registrations(&sys, &mac.layout).unwrap();
+
+        for step in plan_repair(&mac.layout, &before).unwrap() {
+            step.apply(&sys).unwrap();
+        }
+
+        // `repair` registers bundles by their path beneath the root.
+        let garcon = mac.bundled_extension("PlugIns/Garcon.appex");
+        assert!(sys.is_registered(&garcon));
+        assert_eq!(sys.registration_enabled(&garcon), Some(true));
+        assert_eq!(
+            sys.registration_enabled(Path::new(APP_FILEPROVIDER)),
+            Some(true)
+        );
+        assert!(!sys.is_registered(Path::new(STALE_FILEPROVIDER)));
+        assert!(!sys.is_registered(Path::new(DELETED_TRANSFER)));
+
+        let after = registrations(&sys, &mac.layout).unwrap();
+        assert_eq!(plan_repair(&mac.layout, &after).unwrap(), []);
+        assert_eq!(
+            diff_registrations(&before, &after),
+            [
+                format!("- {} {} (1.0)", FILEPROVIDER_EXTENSION, STALE_FILEPROVIDER),
+                format!("- {} {} (1.0)", TRANSFER_EXTENSION, DELETED_TRANSFER),
+                format!("+ {} {} (1.0): use", GARCON_EXTENSION, garcon.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_reports_changed_elections() {
+        let line = |marker: char| {
+            format!(
+                "{}    {}(234.4)\t7F5E1C2A-3B4D-4E6F-8A9B-0C1D2E3F4A5B\t2025-06-02 09:14:31 \
+                 +0000\t{}\n",
+                marker, FILEPROVIDER_EXTENSION, APP_FILEPROVIDER
+            )
+        };
+        let before = parse_pluginkit(&line('+'));
+        let after = parse_pluginkit(&line('-'));
+
+        assert_eq!(diff_registrations(&before, &before), Vec::<String>::new());
+        assert_eq!(
+            diff_registrations(&before, &after),
+            [format!(
+                "~ {} {} (234.4): use → ignore",
+                FILEPROVIDER_EXTENSION, APP_FILEPROVIDER
+            )]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::extensions::{self, ExtensionPolicy};
+use crate::status::{LaunchAgentState, LaunchdOverride, Status};
+
+/// Exit code of `status --check` when Dropbox is in the requested state.
+pub const EXIT_MATCH: u8 = 0;
+/// Exit code of `status --check` when Dropbox is fully in the opposite state.
+pub const EXIT_OPPOSITE: u8 = 2;
+/// Exit code of `status --check` when Dropbox is partially on.
+pub const EXIT_PARTIAL: u8 = 3;
+/// Exit code of `status --check` when Dropbox is not installed.
+pub const EXIT_NOT_INSTALLED: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Target {
+    On,
+    Off,
+}
+
+impl Target {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Target::On => "on",
+            Target::Off => "off",
+        }
+    }
+}
+
+/// How a single component of the machine looks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reason {
+    pub component: String,
+    pub description: String,
+    /// Whether this component looks like Dropbox is on.
+    pub on: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropboxState {
+    On,
+    Off,
+    /// Some components look on and others look off.
+    Partial {
+        reasons: Vec<Reason>,
+    },
+    NotInstalled,
+}
+
+impl DropboxState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropboxState::On => "on",
+            DropboxState::Off => "off",
+            DropboxState::Partial { .. } => "partial",
+            DropboxState::NotInstalled => "not-installed",
+        }
+    }
+
+    /// The command that would bring every component in line, if any is needed.
+    pub fn suggested_command(&self) -> Option<&'static str> {
+        match self {
+            DropboxState::Partial { reasons } => {
+                let on = reasons.iter().filter(|r| r.on).count();
+                if on * 2 > reasons.len() {
+                    Some("droponoff on")
+                } else {
+                    Some("droponoff off")
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn check_exit_code(&self, target: Target) -> u8 {
+        match (self, target) {
+            (DropboxState::On, Target::On) | (DropboxState::Off, Target::Off) => EXIT_MATCH,
+            (DropboxState::On, Target::Off) | (DropboxState::Off, Target::On) => EXIT_OPPOSITE,
+            (DropboxState::Partial { .. }, _) => EXIT_PARTIAL,
+            (DropboxState::NotInstalled, _) => EXIT_NOT_INSTALLED,
+        }
+    }
+}
+
+/// Every component that has an opinion on whether Dropbox is on. Missing LaunchAgents,
+/// extensions that are not registered and extensions whose state the other target's
+/// policy does not require otherwise are left out, since they are consistent with
+/// either state.
+fn reasons(status: &Status) -> Vec<Reason> {
+    let mut reasons = Vec::new();
+
+    reasons.push(Reason {
+        component: "processes".to_string(),
+        description: if status.processes.is_empty() {
+            "no processes running".to_string()
+        } else {
+            format!("{} process(es) running", status.processes.len())
+        },
+        on: !status.processes.is_empty(),
+    });
+
+    for agent in &status.launch_agents {
+        let component = format!("launch-agent:{}", agent.job.label);
+        let label = &agent.job.label;
+        // Disabling the plist does not stop a job launchd already has loaded.
+        if agent.runtime.loaded && !agent.loads() {
+            reasons.push(Reason {
+                component,
+                description: format!("{} still loaded in launchd", label),
+                on: true,
+            });
+            continue;
+        }
+        // launchd does not load a plist whose service is disabled in its overrides.
+        let overridden = agent.launchd_override == LaunchdOverride::Disabled;
+        match agent.state {
+            LaunchAgentState::Enabled | LaunchAgentState::Conflict if overridden => {
+                reasons.push(Reason {
+                    component,
+                    description: format!("{} disabled in launchd's overrides", label),
+                    on: false,
+                })
+            }
+            LaunchAgentState::Enabled | LaunchAgentState::Disabled => reasons.push(Reason {
+                component,
+                description: format!("{} {}", label, agent.state.as_str()),
+                on: agent.state == LaunchAgentState::Enabled,
+            }),
+            LaunchAgentState::Conflict => reasons.push(Reason {
+                component,
+                description: format!("{} plist and .disabled copy both exist", label),
+                on: true,
+            }),
+            LaunchAgentState::Missing => {}
+        }
+    }
+
+    for (bundle_id, state) in &status.extensions {
+        let opposite = if state.enabled {
+            Target::Off
+        } else {
+            Target::On
+        };
+        if !state.found || extensions::policy(bundle_id, opposite) != ExtensionPolicy::Required {
+            continue;
+        }
+        reasons.push(Reason {
+            component: bundle_id.clone(),
+            description: format!(
+                "extension {} {}",
+                bundle_id,
+                if state.enabled { "enabled" } else { "disabled" }
+            ),
+            on: state.enabled,
+        });
+    }
+
+    reasons
+}
+
+pub fn classify(status: &Status) -> DropboxState {
+    let reasons = reasons(status);
+
+    if status.dropbox_app_path.is_none() && reasons.iter().all(|r| !r.on) {
+        return DropboxState::NotInstalled;
+    }
+
+    if reasons.iter().all(|r| r.on) {
+        DropboxState::On
+    } else if reasons.iter().all(|r| !r.on) {
+        DropboxState::Off
+    } else {
+        DropboxState::Partial { reasons }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FakeBackend, SystemBackend};
+    use crate::discovery::Layout;
+    use crate::status::get_status;
+    use crate::testutil::*;
+    use std::fs;
+
+    fn state_of(sys: &FakeBackend, layout: &Layout) -> DropboxState {
+        classify(&get_status(sys, layout).unwrap())
+    }
+
+    /// The updater agent's plist renamed away, as `off` leaves it.
+    fn disabled_agent() -> TestMac {
+        let mac = TestMac::new().with_agent();
+        fs::rename(
+            mac.agent_plist(),
+            mac.agent_plist().with_extension("plist.disabled"),
+        )
+        .unwrap();
+        mac
+    }
+
+    fn components(state: &DropboxState) -> Vec<(&str, bool)> {
+        match state {
+            DropboxState::Partial { reasons } => reasons
+                .iter()
+                .map(|r| (r.component.as_str(), r.on))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn running_dropbox_is_on() {
+        let mac = TestMac::new().with_agent();
+        let state = state_of(&running_dropbox(), &mac.layout);
+
+        assert_eq!(state, DropboxState::On);
+        assert_eq!(state.as_str(), "on");
+        assert_eq!(state.suggested_command(), None);
+        assert_eq!(state.check_exit_code(Target::On), EXIT_MATCH);
+        assert_eq!(state.check_exit_code(Target::Off), EXIT_OPPOSITE);
+    }
+
+    #[test]
+    fn dropbox_turned_off_is_off() {
+        let stopped = || {
+            FakeBackend::new()
+                .with_extension(FILEPROVIDER_EXTENSION, false)
+                .with_extension(GARCON_EXTENSION, false)
+        };
+
+        let mac = disabled_agent();
+        let state = state_of(&stopped(), &mac.layout);
+        assert_eq!(state, DropboxState::Off);
+        assert_eq!(state.suggested_command(), None);
+        assert_eq!(state.check_exit_code(Target::Off), EXIT_MATCH);
+        assert_eq!(state.check_exit_code(Target::On), EXIT_OPPOSITE);
+
+        // launchd does not load a plist whose service is disabled in its overrides.
+        let mac = TestMac::new().with_agent();
+        let sys = stopped();
+        sys.launchctl_disable(&agent_service_target()).unwrap();
+        assert_eq!(state_of(&sys, &mac.layout), DropboxState::Off);
+    }
+
+    #[test]
+    fn mixed_components_are_partial() {
+        // Running, with the fileprovider enabled, but the agent disabled. garcon may
+        // stay disabled while Dropbox is on, so it has no say.
+        let mac = disabled_agent();
+        let sys = FakeBackend::new()
+            .with_process(100, DROPBOX)
+            .with_extension(FILEPROVIDER_EXTENSION, true)
+            .with_extension(GARCON_EXTENSION, false);
+        let state = state_of(&sys, &mac.layout);
+
+        assert_eq!(
+            components(&state),
+            [
+                ("processes", true),
+                (format!("launch-agent:{}", AGENT).as_str(), false),
+                (FILEPROVIDER_EXTENSION, true),
+            ]
+        );
+        assert_eq!(state.as_str(), "partial");
+        assert_eq!(state.suggested_command(), Some("droponoff on"));
+        assert_eq!(state.check_exit_code(Target::On), EXIT_PARTIAL);
+        assert_eq!(state.check_exit_code(Target::Off), EXIT_PARTIAL);
+
+        // Stopped, but the disabled agent is still loaded.
+        let sys = FakeBackend::new()
+            .with_loaded_job(&agent_service_target(), UPDATER, None)
+            .with_extension(FILEPROVIDER_EXTENSION, false)
+            .with_extension(GARCON_EXTENSION, false);
+        let state = state_of(&sys, &mac.layout);
+
+        assert_eq!(
+            components(&state),
+            [
+                ("processes", false),
+                (format!("launch-agent:{}", AGENT).as_str(), true),
+                (FILEPROVIDER_EXTENSION, false),
+            ]
+        );
+        assert_eq!(state.suggested_command(), Some("droponoff off"));
+    }
+
+    #[test]
+    fn a_machine_without_dropbox_is_not_installed() {
+        let empty = tempfile::tempdir().unwrap();
+        let layout = Layout::new(empty.path(), HOME);
+        let state = state_of(&FakeBackend::new(), &layout);
+
+        assert_eq!(state, DropboxState::NotInstalled);
+        assert_eq!(state.as_str(), "not-installed");
+        assert_eq!(state.suggested_command(), None);
+        assert_eq!(state.check_exit_code(Target::On), EXIT_NOT_INSTALLED);
+        assert_eq!(state.check_exit_code(Target::Off), EXIT_NOT_INSTALLED);
+    }
+}
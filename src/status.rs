@@ -1,39 +1,91 @@
-use crate::discovery;
-use crate::extensions::{self, ExtensionState};
-use crate::launchagent;
+use crate::backend::SystemBackend;
+use crate::discovery::Layout;
+use crate::extensions::{self, ExtensionPolicy, ExtensionState, PluginRegistration};
+use crate::launchagent::{self, JobRuntime, LaunchdJob};
 use crate::processes::{self, DropboxProcess};
+use crate::state::{self, DropboxState, Target};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use tracing::info;
 
-pub use crate::launchagent::LaunchAgentState;
+pub use crate::launchagent::{LaunchAgentState, LaunchdOverride};
 
-const DROPBOX_TEAM_ID: &str = "G7HH3F8CAK";
-const GROUP_CONTAINER_SUFFIX: &str = ".com.getdropbox.dropbox.sync";
+/// Version of the `status --format json` document. Bumped whenever a field is removed,
+/// renamed or changes meaning; adding fields does not bump it.
+pub const STATUS_SCHEMA_VERSION: u32 = 2;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatusFormat {
+    /// Human-readable report
+    Pretty,
+    /// A single line of `key=value` pairs, suitable for shell prompts
+    Text,
+    /// A JSON document (see README for the schema)
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Status {
     pub dropbox_app_path: Option<PathBuf>,
     pub processes: Vec<DropboxProcess>,
-    pub launch_agent_state: LaunchAgentState,
+    /// Every Dropbox LaunchAgent and LaunchDaemon found on disk.
+    #[serde(default)]
+    pub launch_agents: Vec<LaunchAgentStatus>,
     pub extensions: Vec<(String, ExtensionState)>,
 }
 
-pub fn get_status() -> Result<Status> {
-    let dropbox_app_path = discovery::find_dropbox_app().ok();
-    let processes = processes::list_dropbox_processes()?;
-    let launch_agent_state = launchagent::get_launch_agent_state()?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LaunchAgentStatus {
+    pub job: LaunchdJob,
+    pub state: LaunchAgentState,
+    #[serde(default)]
+    pub launchd_override: LaunchdOverride,
+    #[serde(default)]
+    pub runtime: JobRuntime,
+}
+
+impl LaunchAgentStatus {
+    /// Whether launchd would load the job at the next login or boot.
+    pub fn loads(&self) -> bool {
+        matches!(
+            self.state,
+            LaunchAgentState::Enabled | LaunchAgentState::Conflict
+        ) && self.launchd_override != LaunchdOverride::Disabled
+    }
+}
+
+pub fn get_status(sys: &dyn SystemBackend, layout: &Layout) -> Result<Status> {
+    let dropbox_app_path = layout.find_dropbox_app().ok();
+    let processes = processes::list_dropbox_processes(sys, layout)?;
+    let mut launch_agents = Vec::new();
+    for job in launchagent::discover_jobs(layout)? {
+        launch_agents.push(LaunchAgentStatus {
+            state: launchagent::get_launch_agent_state(&job)?,
+            launchd_override: launchagent::get_launch_agent_override(sys, &job)?,
+            runtime: launchagent::get_job_runtime(sys, &job)?,
+            job,
+        });
+    }
 
     let mut ext_states = Vec::new();
-    for bundle_id in discovery::DROPBOX_BUNDLE_IDS {
-        let state = extensions::get_extension_state(bundle_id)?;
-        ext_states.push((bundle_id.to_string(), state));
+    let installed = extensions::installed_extensions(layout)?;
+    for bundle_id in extensions::merge_bundle_ids(&installed) {
+        let mut state = extensions::get_extension_state(sys, layout, &bundle_id)?;
+        state.unknown = extensions::known_extension(&bundle_id).is_none();
+        state.extension_point = installed
+            .iter()
+            .find(|e| e.bundle_id == bundle_id)
+            .and_then(|e| e.extension_point.clone());
+        ext_states.push((bundle_id, state));
     }
 
     Ok(Status {
         dropbox_app_path,
         processes,
-        launch_agent_state,
+        launch_agents,
         extensions: ext_states,
     })
 }
@@ -58,12 +110,32 @@ pub fn print_status(status: &Status) {
     }
     info!("");
 
-    let la_state = match status.launch_agent_state {
-        LaunchAgentState::Enabled => "enabled",
-        LaunchAgentState::Disabled => "disabled",
-        LaunchAgentState::Missing => "MISSING",
-    };
-    info!("LaunchAgent: {}", la_state);
+    info!("LaunchAgents and LaunchDaemons:");
+    if status.launch_agents.is_empty() {
+        info!("  (none found)");
+    }
+    for agent in &status.launch_agents {
+        let la_state = match agent.state {
+            LaunchAgentState::Enabled => "enabled",
+            LaunchAgentState::Disabled => "disabled",
+            LaunchAgentState::Missing => "MISSING",
+            LaunchAgentState::Conflict => {
+                "CONFLICT (both the plist and its .disabled copy exist; `off` or `on` resolves it)"
+            }
+        };
+        info!(
+            "  {} ({}): {}",
+            agent.job.label,
+            agent.job.kind.as_str(),
+            la_state
+        );
+        info!("    plist: {}", agent.job.plist.display());
+        if let Some(program) = &agent.job.program {
+            info!("    program: {}", program);
+        }
+        info!("    launchd override: {}", agent.launchd_override.as_str());
+        info!("    launchd: {}", describe_runtime(&agent.runtime));
+    }
     info!("");
 
     info!("Extensions:");
@@ -76,6 +148,278 @@ pub fn print_status(status: &Status) {
             "disabled"
         };
         info!("  {}: {}", bundle_id, status_str);
+        if state.unknown {
+            info!(
+                "    unknown to droponoff; shipped in Dropbox.app as a {} extension",
+                state
+                    .extension_point
+                    .as_deref()
+                    .unwrap_or("(unnamed extension point)")
+            );
+        }
+        let (on, off) = (
+            extensions::policy(bundle_id, Target::On),
+            extensions::policy(bundle_id, Target::Off),
+        );
+        if (on, off) != (ExtensionPolicy::Required, ExtensionPolicy::Required) {
+            info!(
+                "    {} when turning Dropbox on, {} when turning it off",
+                on.as_str(),
+                off.as_str()
+            );
+        }
+        if state.registrations.len() > 1 {
+            info!("    {} registrations:", state.registrations.len());
+        }
+        for registration in &state.registrations {
+            info!("    {}", describe_registration(registration));
+        }
+    }
+    info!("");
+
+    let state = state::classify(status);
+    info!("State: {}", state.as_str().to_uppercase());
+    if let DropboxState::Partial { reasons } = &state {
+        let describe = |on: bool| {
+            reasons
+                .iter()
+                .filter(|r| r.on == on)
+                .map(|r| r.description.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        info!("  Looks on:  {}", describe(true));
+        info!("  Looks off: {}", describe(false));
+        if let Some(command) = state.suggested_command() {
+            info!("  Run `{}` to bring every component in line.", command);
+        }
+    }
+}
+
+fn describe_runtime(runtime: &JobRuntime) -> String {
+    if !runtime.loaded {
+        return "not loaded".to_string();
+    }
+    let mut parts = vec!["loaded".to_string()];
+    match runtime.pid {
+        Some(pid) => parts.push(format!("running as PID {}", pid)),
+        None => parts.push("not running".to_string()),
+    }
+    if let Some(runs) = runtime.runs {
+        parts.push(format!("{} run(s)", runs));
+    }
+    match runtime.last_exit_status {
+        Some(status) if status < 0 => parts.push(format!("last killed by signal {}", -status)),
+        Some(status) => parts.push(format!("last exit status {}", status)),
+        None => {}
+    }
+    parts.join(", ")
+}
+
+fn describe_registration(registration: &PluginRegistration) -> String {
+    let mut parts = vec![registration.election.as_str().to_string()];
+    if let Some(version) = &registration.version {
+        parts.push(format!("version {}", version));
+    }
+    if let Some(uuid) = &registration.uuid {
+        parts.push(format!("UUID {}", uuid));
+    }
+    let path = registration
+        .path
+        .as_deref()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|| "(no path)".to_string());
+    format!("{}: {}", path, parts.join(", "))
+}
+
+#[derive(Serialize)]
+struct StatusDocument<'a> {
+    schema_version: u32,
+    dropbox_app_path: Option<&'a Path>,
+    processes: Vec<ProcessDocument<'a>>,
+    launch_agents: Vec<LaunchAgentDocument<'a>>,
+    extensions: Vec<ExtensionDocument<'a>>,
+    state: StateDocument,
+}
+
+#[derive(Serialize)]
+struct StateDocument {
+    kind: &'static str,
+    reasons: Vec<ReasonDocument>,
+    suggested_command: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ReasonDocument {
+    component: String,
+    description: String,
+    on: bool,
+}
+
+#[derive(Serialize)]
+struct ProcessDocument<'a> {
+    pid: u32,
+    ppid: u32,
+    name: &'a str,
+    executable: Option<&'a Path>,
+    /// Seconds since the Unix epoch.
+    start_time: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct LaunchAgentDocument<'a> {
+    label: &'a str,
+    plist: &'a Path,
+    kind: &'static str,
+    program: Option<&'a str>,
+    state: &'static str,
+    #[serde(rename = "override")]
+    launchd_override: &'static str,
+    runtime: &'a JobRuntime,
+}
+
+#[derive(Serialize)]
+struct ExtensionDocument<'a> {
+    bundle_id: &'a str,
+    found: bool,
+    enabled: bool,
+    known: bool,
+    extension_point: Option<&'a str>,
+    registrations: Vec<RegistrationDocument<'a>>,
+}
+
+#[derive(Serialize)]
+struct RegistrationDocument<'a> {
+    election: &'static str,
+    version: Option<&'a str>,
+    uuid: Option<&'a str>,
+    timestamp: Option<&'a str>,
+    path: Option<&'a Path>,
+}
+
+pub fn status_json(status: &Status) -> String {
+    let state = state::classify(status);
+    let reasons = match &state {
+        DropboxState::Partial { reasons } => reasons
+            .iter()
+            .map(|r| ReasonDocument {
+                component: r.component.clone(),
+                description: r.description.clone(),
+                on: r.on,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let document = StatusDocument {
+        schema_version: STATUS_SCHEMA_VERSION,
+        dropbox_app_path: status.dropbox_app_path.as_deref(),
+        processes: status
+            .processes
+            .iter()
+            .map(|p| ProcessDocument {
+                pid: p.pid,
+                ppid: p.ppid,
+                name: &p.name,
+                executable: p.executable.as_deref(),
+                start_time: p
+                    .start_time
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs_f64()),
+            })
+            .collect(),
+        launch_agents: status
+            .launch_agents
+            .iter()
+            .map(|a| LaunchAgentDocument {
+                label: &a.job.label,
+                plist: &a.job.plist,
+                kind: a.job.kind.as_str(),
+                program: a.job.program.as_deref(),
+                state: a.state.as_str(),
+                launchd_override: a.launchd_override.as_str(),
+                runtime: &a.runtime,
+            })
+            .collect(),
+        extensions: status
+            .extensions
+            .iter()
+            .map(|(bundle_id, state)| ExtensionDocument {
+                bundle_id,
+                found: state.found,
+                enabled: state.enabled,
+                known: !state.unknown,
+                extension_point: state.extension_point.as_deref(),
+                registrations: state
+                    .registrations
+                    .iter()
+                    .map(|r| RegistrationDocument {
+                        election: r.election.as_str(),
+                        version: r.version.as_deref(),
+                        uuid: r.uuid.as_deref(),
+                        timestamp: r.timestamp.as_deref(),
+                        path: r.path.as_deref(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+        state: StateDocument {
+            kind: state.as_str(),
+            reasons,
+            suggested_command: state.suggested_command(),
+        },
+    };
+    serde_json::to_string_pretty(&document).expect("status document is always serializable")
+}
+
+/// A single line without spaces in values or non-ASCII characters, e.g.
+/// `state=on app=found processes=3 agents=on:1,off:0,loaded:1
+/// extensions=on:2,off:0,missing:1,unknown:0`.
+pub fn status_line(status: &Status) -> String {
+    let app = if status.dropbox_app_path.is_some() {
+        "found"
+    } else {
+        "missing"
+    };
+    let on = status
+        .extensions
+        .iter()
+        .filter(|(_, s)| s.found && s.enabled)
+        .count();
+    let off = status
+        .extensions
+        .iter()
+        .filter(|(_, s)| s.found && !s.enabled)
+        .count();
+    let missing = status.extensions.iter().filter(|(_, s)| !s.found).count();
+    let unknown = status.extensions.iter().filter(|(_, s)| s.unknown).count();
+    let agents_on = status.launch_agents.iter().filter(|a| a.loads()).count();
+    let agents_off = status.launch_agents.len() - agents_on;
+    let agents_loaded = status
+        .launch_agents
+        .iter()
+        .filter(|a| a.runtime.loaded)
+        .count();
+
+    format!(
+        "state={} app={} processes={} agents=on:{},off:{},loaded:{} extensions=on:{},off:{},missing:{},unknown:{}",
+        state::classify(status).as_str(),
+        app,
+        status.processes.len(),
+        agents_on,
+        agents_off,
+        agents_loaded,
+        on,
+        off,
+        missing,
+        unknown
+    )
+}
+
+pub fn print_status_as(status: &Status, format: StatusFormat) {
+    match format {
+        StatusFormat::Pretty => print_status(status),
+        StatusFormat::Text => println!("{}", status_line(status)),
+        StatusFormat::Json => println!("{}", status_json(status)),
     }
 }
 
@@ -83,30 +427,33 @@ fn format_gb(bytes: u64) -> String {
     format!("{:.2} GB", bytes as f64 / 1_000_000_000.0)
 }
 
-/// Delete immediate children inside any scratch_files directories under the Dropbox root mount.
-pub fn clean_scratch_files() -> Result<()> {
-    let home = discovery::get_home_dir()?;
-    let data_home = PathBuf::from("/System/Volumes/Data")
-        .join(home.strip_prefix(Path::new("/")).unwrap_or(&home));
-    let base_home = if data_home.exists() { data_home } else { home };
-
-    let container_name = format!("{}{}", DROPBOX_TEAM_ID, GROUP_CONTAINER_SUFFIX);
-    let root_mount = base_home
-        .join("Library/Group Containers")
-        .join(container_name)
-        .join("root-mount");
+/// Files found inside the scratch_files directories under the Dropbox root mount.
+pub struct ScratchFiles {
+    pub root_mount: PathBuf,
+    pub dirs: Vec<PathBuf>,
+    /// Paths and sizes in bytes.
+    pub files: Vec<(PathBuf, u64)>,
+}
+
+impl ScratchFiles {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+}
+
+pub fn find_scratch_files(layout: &Layout) -> Result<ScratchFiles> {
+    let root_mount = layout.root_mount();
 
     if !root_mount.exists() {
         anyhow::bail!("Dropbox root-mount not found at {}", root_mount.display());
     }
 
-    // Collect all files to delete with their sizes
-    let mut files_to_delete: Vec<(PathBuf, u64)> = Vec::new();
+    let mut dirs = Vec::new();
+    let mut files: Vec<(PathBuf, u64)> = Vec::new();
 
-    // Find files immediately inside a directory like this and delete them:
+    // Find files immediately inside a directory like this:
     //
     // System/Volumes/Data/USERNAME/scode/Library/Group Containers/G7HH3F8CAK.com.getdropbox.dropbox.sync/root-mount/UUID/scratch_files
-    let mut found_any = false;
     for entry in fs::read_dir(&root_mount)? {
         let entry = entry?;
         if !entry.file_type()?.is_dir() {
@@ -118,10 +465,7 @@ pub fn clean_scratch_files() -> Result<()> {
             continue;
         }
 
-        found_any = true;
-        info!("  Cleaning {}", scratch_dir.display());
-
-        // Only remove immediate files/symlinks; skip nested
+        // Only consider immediate files/symlinks; skip nested
         // directories as we don't expect them.
         for child in fs::read_dir(&scratch_dir)? {
             let child = child?;
@@ -130,44 +474,132 @@ pub fn clean_scratch_files() -> Result<()> {
 
             if child_type.is_file() || child_type.is_symlink() {
                 let size = child.metadata().map(|m| m.len()).unwrap_or(0);
-                files_to_delete.push((child_path, size));
+                files.push((child_path, size));
             } else {
                 info!("    Skipping directory {}", child_path.display());
             }
         }
+
+        dirs.push(scratch_dir);
     }
 
-    // Delete files and track progress
-    let mut total_nuked: u64 = 0;
-    let file_count = files_to_delete.len();
+    Ok(ScratchFiles {
+        root_mount,
+        dirs,
+        files,
+    })
+}
 
-    for (i, (child_path, size)) in files_to_delete.iter().enumerate() {
-        let next_size = files_to_delete.get(i + 1).map(|(_, s)| *s).unwrap_or(0);
-        info!(
-            "    ({} nuked, next: {}) rm {}",
-            format_gb(total_nuked),
-            format_gb(next_size),
-            child_path.display()
-        );
-        fs::remove_file(child_path)?;
-        total_nuked += size;
+/// Delete immediate children inside any scratch_files directories under the Dropbox root mount.
+/// With `dry_run`, only list what would be deleted.
+pub fn clean_scratch_files(layout: &Layout, dry_run: bool) -> Result<()> {
+    let scratch = find_scratch_files(layout)?;
+
+    for dir in &scratch.dirs {
+        if dry_run {
+            info!("  Would clean {}", dir.display());
+        } else {
+            info!("  Cleaning {}", dir.display());
+        }
     }
 
-    if !found_any {
+    let file_count = scratch.files.len();
+    if dry_run {
+        for (child_path, size) in &scratch.files {
+            info!("    ({} bytes) rm {}", size, child_path.display());
+        }
+    } else {
+        // Delete files and track progress
+        let mut total_nuked: u64 = 0;
+
+        for (i, (child_path, size)) in scratch.files.iter().enumerate() {
+            let next_size = scratch.files.get(i + 1).map(|(_, s)| *s).unwrap_or(0);
+            info!(
+                "    ({} nuked, next: {}) rm {}",
+                format_gb(total_nuked),
+                format_gb(next_size),
+                child_path.display()
+            );
+            fs::remove_file(child_path)?;
+            total_nuked += size;
+        }
+    }
+
+    if scratch.dirs.is_empty() {
         info!(
             "  No scratch_files directories found under {}",
-            root_mount.display()
+            scratch.root_mount.display()
         );
     }
 
     if file_count > 0 {
         info!("");
-        info!(
-            "Nuked {} over {} files.",
-            format_gb(total_nuked),
-            file_count
-        );
+        if dry_run {
+            info!(
+                "Would nuke {} ({} bytes) over {} files.",
+                format_gb(scratch.total_size()),
+                scratch.total_size(),
+                file_count
+            );
+        } else {
+            info!(
+                "Nuked {} over {} files.",
+                format_gb(scratch.total_size()),
+                file_count
+            );
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn cleaning_scratch_files_deletes_only_files_beneath_the_root() {
+        let mac = TestMac::new()
+            .with_scratch_file("chunk-1", 10)
+            .with_scratch_file("chunk-2", 20);
+        let nested = mac.scratch_dir().join("nested");
+        fs::create_dir_all(&nested).unwrap();
+
+        let scratch = find_scratch_files(&mac.layout).unwrap();
+        assert_eq!(scratch.dirs, vec![mac.scratch_dir()]);
+        assert_eq!(scratch.files.len(), 2);
+        assert_eq!(scratch.total_size(), 30);
+
+        clean_scratch_files(&mac.layout, true).unwrap();
+        assert_eq!(find_scratch_files(&mac.layout).unwrap().files.len(), 2);
+
+        clean_scratch_files(&mac.layout, false).unwrap();
+        assert!(find_scratch_files(&mac.layout).unwrap().files.is_empty());
+        assert!(nested.is_dir());
+    }
+
+    #[test]
+    fn status_json_describes_every_process() {
+        let mac = TestMac::new();
+        let sys = running_dropbox();
+        let status = get_status(&sys, &mac.layout).unwrap();
+
+        let document: serde_json::Value = serde_json::from_str(&status_json(&status)).unwrap();
+
+        let processes = document["processes"].as_array().unwrap();
+        assert_eq!(processes.len(), 2);
+        assert_eq!(processes[0]["pid"], 100);
+        assert_eq!(processes[0]["ppid"], 1);
+        assert_eq!(processes[0]["name"], DROPBOX);
+        assert_eq!(processes[0]["executable"], DROPBOX);
+        assert!(processes[0]["start_time"].as_f64().unwrap() > 0.0);
+        assert_eq!(processes[1]["executable"], FILEPROVIDER);
+    }
+
+    #[test]
+    fn finding_scratch_files_fails_without_a_root_mount() {
+        let mac = TestMac::new();
+        assert!(find_scratch_files(&mac.layout).is_err());
+    }
+}
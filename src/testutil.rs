@@ -0,0 +1,214 @@
+//! Fixtures shared by the unit tests: a temporary root laid out like a Mac with
+//! Dropbox installed, and a [`FakeBackend`] that runs Dropbox on it.
+
+use crate::backend::FakeBackend;
+use crate::commands::{OffOptions, OnOptions};
+use crate::config::Timings;
+use crate::discovery::Layout;
+use crate::launchagent::LaunchAgentStrategy;
+use crate::processes::TerminationPolicy;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+pub const HOME: &str = "/Users/me";
+pub const DROPBOX: &str = "/Applications/Dropbox.app/Contents/MacOS/Dropbox";
+pub const FILEPROVIDER: &str = "/Applications/Dropbox.app/Contents/PlugIns/\
+                                DropboxFileProvider.appex/Contents/MacOS/DropboxFileProvider";
+pub const UPDATER: &str = "/Library/DropboxHelperTools/DropboxMacUpdate";
+pub const AGENT: &str = "com.dropbox.DropboxMacUpdate.agent";
+pub const FILEPROVIDER_EXTENSION: &str = "com.getdropbox.dropbox.fileprovider";
+pub const GARCON_EXTENSION: &str = "com.getdropbox.dropbox.garcon";
+
+/// A temporary root with Dropbox.app installed and the user's home at [`HOME`].
+pub struct TestMac {
+    /// Removed when the fixture is dropped.
+    _dir: TempDir,
+    pub layout: Layout,
+}
+
+impl TestMac {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().expect("create temporary root");
+        let layout = Layout::new(dir.path(), HOME);
+        fs::create_dir_all(layout.resolve("/Applications/Dropbox.app/Contents/MacOS"))
+            .expect("create Dropbox.app");
+        fs::create_dir_all(layout.home_dir()).expect("create home");
+        Self { _dir: dir, layout }
+    }
+
+    /// Add a plist for the Dropbox updater agent to `~/Library/LaunchAgents`.
+    pub fn with_agent(self) -> Self {
+        let dir = self.layout.launch_agents_dir();
+        fs::create_dir_all(&dir).expect("create LaunchAgents");
+        fs::write(self.agent_plist(), agent_plist_contents()).expect("write agent plist");
+        self
+    }
+
+    pub fn agent_plist(&self) -> PathBuf {
+        self.layout
+            .launch_agents_dir()
+            .join(format!("{}.plist", AGENT))
+    }
+
+    /// Add a file of `size` bytes to a scratch_files directory under the root mount.
+    pub fn with_scratch_file(self, name: &str, size: usize) -> Self {
+        let dir = self.scratch_dir();
+        fs::create_dir_all(&dir).expect("create scratch_files");
+        fs::write(dir.join(name), vec![0u8; size]).expect("write scratch file");
+        self
+    }
+
+    /// Add an app extension at `path` inside Dropbox.app's `Contents` (e.g.
+    /// `PlugIns/FileProvider.appex`), with an `Info.plist` naming `bundle_id` and the
+    /// File Provider extension point.
+    pub fn with_bundled_extension(self, path: &str, bundle_id: &str) -> Self {
+        let info_dir = self.bundled_extension(path).join("Contents");
+        fs::create_dir_all(&info_dir).expect("create extension bundle");
+        fs::write(
+            info_dir.join("Info.plist"),
+            extension_info_plist_contents(bundle_id),
+        )
+        .expect("write extension Info.plist");
+        self
+    }
+
+    /// Where [`TestMac::with_bundled_extension`] puts the extension at `path`.
+    pub fn bundled_extension(&self, path: &str) -> PathBuf {
+        self.layout
+            .resolve("/Applications/Dropbox.app/Contents")
+            .join(path)
+    }
+
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.layout
+            .root_mount()
+            .join("00000000-0000-0000-0000-000000000000/scratch_files")
+    }
+}
+
+pub fn agent_plist_contents() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>{}</string>
+	<key>Program</key>
+	<string>{}</string>
+</dict>
+</plist>
+"#,
+        AGENT, UPDATER
+    )
+}
+
+pub fn extension_info_plist_contents(bundle_id: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>CFBundleIdentifier</key>
+	<string>{}</string>
+	<key>NSExtension</key>
+	<dict>
+		<key>NSExtensionPointIdentifier</key>
+		<string>com.apple.fileprovider-nonui</string>
+	</dict>
+</dict>
+</plist>
+"#,
+        bundle_id
+    )
+}
+
+/// The launchd service target of the updater agent in the fake's GUI domain.
+pub fn agent_service_target() -> String {
+    format!("gui/501/{}", AGENT)
+}
+
+/// Dropbox running as PID 100 with its FileProvider as PID 101, the updater agent
+/// loaded and the fileprovider and garcon extensions enabled. Launching Dropbox starts
+/// the same two processes again.
+pub fn running_dropbox() -> FakeBackend {
+    FakeBackend::new()
+        .with_process(100, DROPBOX)
+        .with_process(101, FILEPROVIDER)
+        .with_launched_process(100, DROPBOX)
+        .with_launched_process(101, FILEPROVIDER)
+        .with_loaded_job(&agent_service_target(), UPDATER, None)
+        .with_extension(FILEPROVIDER_EXTENSION, true)
+        .with_extension(GARCON_EXTENSION, true)
+}
+
+/// `off` as run without flags.
+pub fn off_options() -> OffOptions {
+    OffOptions {
+        rollback: true,
+        dry_run: false,
+        wait_for_idle: None,
+        termination: TerminationPolicy::Escalate,
+        launch_agent_strategy: LaunchAgentStrategy::Rename,
+        timings: Timings::default(),
+        report_json: false,
+    }
+}
+
+/// `on` as run without flags.
+pub fn on_options() -> OnOptions {
+    OnOptions {
+        rollback: true,
+        dry_run: false,
+        timings: Timings::default(),
+        report_json: false,
+    }
+}
+
+/// A copy of `sleep` running from `executable`, killed when dropped.
+#[cfg(target_os = "linux")]
+pub struct DummyProcess {
+    pub child: std::process::Child,
+}
+
+#[cfg(target_os = "linux")]
+impl DummyProcess {
+    /// Copy `sleep` to `executable` and run it for `seconds`.
+    pub fn spawn(executable: &std::path::Path, seconds: u32) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::create_dir_all(executable.parent().unwrap()).expect("create parent");
+        fs::copy("/bin/sleep", executable).expect("copy sleep");
+        fs::set_permissions(executable, fs::Permissions::from_mode(0o755))
+            .expect("make executable");
+
+        // Another test thread forking while the copy was open for writing can keep it
+        // busy for a moment.
+        for _ in 0..50 {
+            match std::process::Command::new(executable)
+                .arg(seconds.to_string())
+                .spawn()
+            {
+                Ok(child) => return Self { child },
+                Err(e) if e.raw_os_error() == Some(libc::ETXTBSY) => {
+                    std::thread::sleep(std::time::Duration::from_millis(20))
+                }
+                Err(e) => panic!("spawn {}: {}", executable.display(), e),
+            }
+        }
+        panic!("{} stayed busy", executable.display());
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DummyProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
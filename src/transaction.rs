@@ -0,0 +1,341 @@
+use crate::backend::SystemBackend;
+use crate::config::Timings;
+use crate::discovery::Layout;
+use crate::journal::{Action, Journal};
+use crate::{extensions, launchagent, processes};
+use anyhow::{Context, Result};
+use std::fs;
+use tracing::{info, warn};
+
+/// The changes a command has made so far, so that they can be undone in reverse order
+/// when a later step fails.
+pub struct Transaction<'a> {
+    sys: &'a dyn SystemBackend,
+    layout: &'a Layout,
+    /// How long undoing an extension election waits for pluginkit to reflect it.
+    timings: &'a Timings,
+    journal: Option<JournalBinding<'a>>,
+    completed: Vec<Action>,
+}
+
+struct JournalBinding<'a> {
+    journal: &'a mut Journal,
+    /// Number of actions in the journal before this transaction started.
+    start_len: usize,
+    /// Whether the journal was created for this transaction (as opposed to continuing
+    /// one left behind by an earlier `off`).
+    is_new: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(sys: &'a dyn SystemBackend, layout: &'a Layout, timings: &'a Timings) -> Self {
+        Self {
+            sys,
+            layout,
+            timings,
+            journal: None,
+            completed: Vec::new(),
+        }
+    }
+
+    /// A transaction that also records every action in `journal`.
+    pub fn journaled(
+        sys: &'a dyn SystemBackend,
+        layout: &'a Layout,
+        timings: &'a Timings,
+        journal: &'a mut Journal,
+        is_new: bool,
+    ) -> Self {
+        let start_len = journal.actions.len();
+        Self {
+            sys,
+            layout,
+            timings,
+            journal: Some(JournalBinding {
+                journal,
+                start_len,
+                is_new,
+            }),
+            completed: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, action: Action) -> Result<()> {
+        if let Some(binding) = &mut self.journal {
+            binding.journal.record(self.layout, action.clone())?;
+        }
+        self.completed.push(action);
+        Ok(())
+    }
+
+    /// Handle a failed command: undo everything in reverse order, or with `rollback`
+    /// unset, report exactly what was changed and leave it in place. Returns `error`
+    /// so the caller can propagate it.
+    pub fn abort(mut self, error: anyhow::Error, rollback: bool) -> anyhow::Error {
+        info!("");
+        if self.completed.is_empty() {
+            info!("  No changes were made");
+            return error;
+        }
+
+        if !rollback {
+            self.report();
+            return error;
+        }
+
+        info!("→ Rolling back {} change(s)...", self.completed.len());
+        let mut clean = true;
+        for (i, action) in self.completed.iter().enumerate().rev() {
+            let relaunch = self.relaunches(i);
+            let result = if relaunch {
+                processes::launch_dropbox(self.sys).map(|()| true)
+            } else {
+                undo(self.sys, self.timings, action)
+            };
+            match result {
+                Ok(true) if relaunch => {
+                    info!("  Undid: {} (launched Dropbox again)", action.describe())
+                }
+                Ok(true) => info!("  Undid: {}", action.describe()),
+                Ok(false) => info!("  Nothing to undo: {}", action.describe()),
+                Err(e) => {
+                    warn!("  Could not undo \"{}\": {}", action.describe(), e);
+                    clean = false;
+                }
+            }
+        }
+
+        if let Some(binding) = &mut self.journal {
+            // A complete rollback means the journal no longer describes anything this
+            // transaction did. After a partial one, keep it so `on` can finish the job.
+            let result = if !clean {
+                Ok(())
+            } else if binding.is_new {
+                Journal::remove(self.layout)
+            } else {
+                binding.journal.truncate(self.layout, binding.start_len)
+            };
+            if let Err(e) = result {
+                warn!("  Could not update journal: {}", e);
+            }
+        }
+
+        if clean {
+            info!("  Rolled back all changes");
+        } else {
+            warn!("  Rollback was incomplete; run `droponoff status` to inspect the machine");
+        }
+
+        error
+    }
+
+    /// Whether undoing the action at `index` has to launch Dropbox again: it is the
+    /// first process this transaction killed, and Dropbox was not asked to quit before
+    /// (`off --force`), so there is no [`Action::QuitDropbox`] whose undo would.
+    fn relaunches(&self, index: usize) -> bool {
+        matches!(self.completed[index], Action::KillProcess { .. })
+            && !self.completed[..index]
+                .iter()
+                .any(|a| matches!(a, Action::QuitDropbox | Action::KillProcess { .. }))
+    }
+
+    fn report(&self) {
+        info!("  Leaving the machine as is (--no-rollback). Changes made before the failure:");
+        for (i, action) in self.completed.iter().enumerate() {
+            info!("    {}. {}", i + 1, action.describe());
+        }
+        if self.journal.is_some() {
+            info!(
+                "  These are recorded in {}; `droponoff on` will undo them",
+                Journal::path(self.layout).display()
+            );
+        }
+    }
+}
+
+/// Undo a single action. Returns `false` for actions that cannot be undone on their
+/// own: a killed process only comes back when Dropbox is launched again, which undoing
+/// [`Action::QuitDropbox`] does, or [`Transaction::abort`] when there is none.
+pub fn undo(sys: &dyn SystemBackend, timings: &Timings, action: &Action) -> Result<bool> {
+    match action {
+        Action::QuitDropbox => processes::launch_dropbox(sys)?,
+        Action::LaunchDropbox => processes::quit_dropbox_gracefully(sys)?,
+        Action::UnloadLaunchAgent { job } => {
+            launchagent::load_launch_agent(sys, job)?;
+        }
+        Action::LoadLaunchAgent { job } => {
+            launchagent::unload_launch_agent(sys, job)?;
+        }
+        Action::RenameLaunchAgent { from, to, .. } | Action::BackUpLaunchAgent { from, to, .. } => {
+            fs::rename(to, from)
+                .with_context(|| format!("Failed to rename {} back", to.display()))?
+        }
+        Action::DisableLaunchAgentService { job } => {
+            sys.launchctl_enable(&job.service_target(sys)?)?
+        }
+        Action::EnableLaunchAgentService { job } => {
+            sys.launchctl_disable(&job.service_target(sys)?)?
+        }
+        Action::CreateLaunchAgentPlaceholder { job } => {
+            launchagent::remove_placeholder(sys, job)?;
+        }
+        Action::RemoveLaunchAgentPlaceholder { job } => {
+            launchagent::create_placeholder(sys, job)?;
+        }
+        Action::DisableExtension { bundle_id, path } => {
+            extensions::enable_extension(sys, bundle_id, path.as_deref(), timings)?
+        }
+        Action::EnableExtension { bundle_id, path } => {
+            extensions::disable_extension(sys, bundle_id, path.as_deref(), timings)?
+        }
+        Action::RestartFinder | Action::KillProcess { .. } => return Ok(false),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Call, Election, FakeBackend};
+    use crate::launchagent::LaunchdJob;
+    use crate::status::get_status;
+    use crate::testutil::*;
+
+    /// Unload the agent and disable the File Provider extension through `transaction`.
+    fn change_agent_and_extension(
+        sys: &FakeBackend,
+        job: &LaunchdJob,
+        timings: &Timings,
+        transaction: &mut Transaction,
+    ) {
+        launchagent::unload_launch_agent(sys, job).unwrap();
+        transaction
+            .record(Action::UnloadLaunchAgent { job: job.clone() })
+            .unwrap();
+        extensions::disable_extension(sys, FILEPROVIDER_EXTENSION, None, timings).unwrap();
+        transaction
+            .record(Action::DisableExtension {
+                bundle_id: FILEPROVIDER_EXTENSION.to_string(),
+                path: None,
+            })
+            .unwrap();
+    }
+
+    fn mutating_calls_since(sys: &FakeBackend, start: usize) -> Vec<Call> {
+        sys.calls()[start..]
+            .iter()
+            .filter(|c| c.mutates())
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn abort_undoes_every_change_in_reverse_order() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        let timings = Timings::default();
+        let mut transaction = Transaction::new(&sys, &mac.layout, &timings);
+        change_agent_and_extension(&sys, &job, &timings, &mut transaction);
+        let start = sys.calls().len();
+
+        let error = transaction.abort(anyhow::anyhow!("step failed"), true);
+
+        assert_eq!(error.to_string(), "step failed");
+        assert_eq!(
+            mutating_calls_since(&sys, start),
+            [
+                Call::PluginkitElect(FILEPROVIDER_EXTENSION.to_string(), Election::Use),
+                Call::LaunchctlBootstrap("gui/501".to_string(), job.plist.clone()),
+            ]
+        );
+        assert!(sys.is_job_loaded(&agent_service_target()));
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(true));
+    }
+
+    #[test]
+    fn abort_without_rollback_leaves_the_changes_and_the_journal() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        let timings = Timings::default();
+        let mut journal = Journal::new(get_status(&sys, &mac.layout).unwrap());
+        let mut transaction =
+            Transaction::journaled(&sys, &mac.layout, &timings, &mut journal, true);
+        change_agent_and_extension(&sys, &job, &timings, &mut transaction);
+        let start = sys.calls().len();
+
+        transaction.abort(anyhow::anyhow!("step failed"), false);
+
+        assert_eq!(mutating_calls_since(&sys, start), []);
+        assert!(!sys.is_job_loaded(&agent_service_target()));
+        let saved = Journal::load(&mac.layout).unwrap().unwrap();
+        assert_eq!(saved.actions.len(), 2);
+    }
+
+    #[test]
+    fn a_clean_rollback_removes_a_new_journal_and_truncates_a_continued_one() {
+        let mac = TestMac::new().with_agent();
+        let timings = Timings::default();
+        for is_new in [true, false] {
+            let sys = running_dropbox();
+            let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+            let mut journal = Journal::new(get_status(&sys, &mac.layout).unwrap());
+            if !is_new {
+                journal.record(&mac.layout, Action::RestartFinder).unwrap();
+            }
+            let mut transaction =
+                Transaction::journaled(&sys, &mac.layout, &timings, &mut journal, is_new);
+            change_agent_and_extension(&sys, &job, &timings, &mut transaction);
+
+            transaction.abort(anyhow::anyhow!("step failed"), true);
+
+            match Journal::load(&mac.layout).unwrap() {
+                None => assert!(is_new),
+                Some(saved) => {
+                    assert!(!is_new);
+                    assert_eq!(saved.actions, [Action::RestartFinder]);
+                }
+            }
+            Journal::remove(&mac.layout).unwrap();
+        }
+    }
+
+    #[test]
+    fn an_incomplete_rollback_keeps_the_journal_for_on() {
+        let mac = TestMac::new().with_agent();
+        let sys = running_dropbox();
+        let job = launchagent::discover_jobs(&mac.layout).unwrap().remove(0);
+        let timings = Timings::default();
+        let mut journal = Journal::new(get_status(&sys, &mac.layout).unwrap());
+        let mut transaction =
+            Transaction::journaled(&sys, &mac.layout, &timings, &mut journal, true);
+        change_agent_and_extension(&sys, &job, &timings, &mut transaction);
+        sys.script(
+            "launchctl_bootstrap",
+            Err("Bootstrap failed: 5: Input/output error".to_string()),
+        );
+
+        transaction.abort(anyhow::anyhow!("step failed"), true);
+
+        assert_eq!(sys.extension_enabled(FILEPROVIDER_EXTENSION), Some(true));
+        assert!(!sys.is_job_loaded(&agent_service_target()));
+        let saved = Journal::load(&mac.layout).unwrap().unwrap();
+        assert_eq!(saved.actions.len(), 2);
+    }
+
+    #[test]
+    fn killed_processes_and_finder_restarts_are_not_undone_on_their_own() {
+        let sys = FakeBackend::new();
+        let timings = Timings::default();
+        let kill = Action::KillProcess {
+            pid: 101,
+            name: FILEPROVIDER.to_string(),
+            signal: crate::backend::Signal::Kill,
+        };
+
+        assert!(!undo(&sys, &timings, &kill).unwrap());
+        assert!(!undo(&sys, &timings, &Action::RestartFinder).unwrap());
+        assert_eq!(mutating_calls_since(&sys, 0), []);
+    }
+}
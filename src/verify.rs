@@ -0,0 +1,309 @@
+//! Checking that `off`, `on` or `set` left every component in the state it was meant
+//! to.
+
+use crate::backend::SystemBackend;
+use crate::config::Timings;
+use crate::discovery::Layout;
+use crate::extensions::PluginElection;
+use crate::launchagent::LaunchdJob;
+use crate::status::{self, LaunchAgentState, LaunchdOverride, Status};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+const REPORT_FILE_NAME: &str = "verification.json";
+
+/// Bumped whenever a field of the report file is removed, renamed or changes meaning.
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// What one component should look like once a command completes.
+#[derive(Debug, Clone)]
+pub enum Expectation {
+    /// Whether any Dropbox process is running.
+    ProcessesRunning(bool),
+    /// The state of a job's plist files. A job no longer found counts as missing.
+    LaunchAgentState {
+        job: LaunchdJob,
+        state: LaunchAgentState,
+    },
+    /// Whether a job is disabled in launchd's overrides.
+    LaunchdOverrideDisabled { job: LaunchdJob, disabled: bool },
+    /// Whether launchd would load a job at the next login or boot, however it was
+    /// disabled. A job no longer found counts as disabled.
+    JobEnabled { job: LaunchdJob, enabled: bool },
+    /// Whether launchd has a job loaded.
+    JobLoaded { job: LaunchdJob, loaded: bool },
+    /// Whether an extension is elected for use: its registration at `path`, or the one
+    /// that decides whether it is enabled. An extension that is not registered is
+    /// consistent with either.
+    ExtensionEnabled {
+        bundle_id: String,
+        path: Option<PathBuf>,
+        enabled: bool,
+    },
+}
+
+impl Expectation {
+    /// Processes, plists and extensions are named like the reasons of `status`.
+    pub fn component(&self) -> String {
+        match self {
+            Expectation::ProcessesRunning(_) => "processes".to_string(),
+            Expectation::LaunchAgentState { job, .. } => format!("launch-agent:{}", job.label),
+            Expectation::LaunchdOverrideDisabled { job, .. } => {
+                format!("launchd-override:{}", job.label)
+            }
+            Expectation::JobEnabled { job, .. } => format!("launch-agent:{}", job.label),
+            Expectation::JobLoaded { job, .. } => format!("launchd:{}", job.label),
+            Expectation::ExtensionEnabled {
+                bundle_id,
+                path: Some(path),
+                ..
+            } => format!("{} at {}", bundle_id, path.display()),
+            Expectation::ExtensionEnabled { bundle_id, .. } => bundle_id.clone(),
+        }
+    }
+
+    pub fn expected(&self) -> String {
+        match self {
+            Expectation::ProcessesRunning(true) => "running".to_string(),
+            Expectation::ProcessesRunning(false) => "none running".to_string(),
+            Expectation::LaunchAgentState { state, .. } => state.as_str().to_string(),
+            Expectation::LaunchdOverrideDisabled { disabled: true, .. } => "disabled".to_string(),
+            Expectation::LaunchdOverrideDisabled {
+                disabled: false, ..
+            } => "not disabled".to_string(),
+            Expectation::JobEnabled { enabled: true, .. } => "enabled".to_string(),
+            Expectation::JobEnabled { enabled: false, .. } => "disabled".to_string(),
+            Expectation::JobLoaded { loaded: true, .. } => "loaded".to_string(),
+            Expectation::JobLoaded { loaded: false, .. } => "not loaded".to_string(),
+            Expectation::ExtensionEnabled { enabled: true, .. } => "enabled".to_string(),
+            Expectation::ExtensionEnabled { enabled: false, .. } => "disabled".to_string(),
+        }
+    }
+
+    /// What `status` shows for the component, and whether that meets the expectation.
+    pub fn check(&self, status: &Status) -> (String, bool) {
+        let agent = |job: &LaunchdJob| {
+            status
+                .launch_agents
+                .iter()
+                .find(|a| a.job.plist == job.plist)
+        };
+        match self {
+            Expectation::ProcessesRunning(running) => {
+                let actual = if status.processes.is_empty() {
+                    "none running".to_string()
+                } else {
+                    format!("{} running", status.processes.len())
+                };
+                (actual, status.processes.is_empty() != *running)
+            }
+            Expectation::LaunchAgentState { job, state } => {
+                let actual = agent(job)
+                    .map(|a| a.state.clone())
+                    .unwrap_or(LaunchAgentState::Missing);
+                (actual.as_str().to_string(), actual == *state)
+            }
+            Expectation::LaunchdOverrideDisabled { job, disabled } => {
+                let actual = agent(job)
+                    .map(|a| a.launchd_override)
+                    .unwrap_or(LaunchdOverride::Unset);
+                (
+                    actual.as_str().to_string(),
+                    (actual == LaunchdOverride::Disabled) == *disabled,
+                )
+            }
+            Expectation::JobEnabled { job, enabled } => {
+                let actual = agent(job).is_some_and(|a| a.loads());
+                let description = match agent(job) {
+                    Some(a) if a.loads() => "enabled",
+                    Some(a) if a.launchd_override == LaunchdOverride::Disabled => {
+                        "disabled in launchd's overrides"
+                    }
+                    Some(a) => a.state.as_str(),
+                    None => LaunchAgentState::Missing.as_str(),
+                };
+                (description.to_string(), actual == *enabled)
+            }
+            Expectation::JobLoaded { job, loaded } => {
+                let actual = agent(job).is_some_and(|a| a.runtime.loaded);
+                let description = if actual { "loaded" } else { "not loaded" };
+                (description.to_string(), actual == *loaded)
+            }
+            Expectation::ExtensionEnabled {
+                bundle_id,
+                path,
+                enabled,
+            } => {
+                let registration = status
+                    .extensions
+                    .iter()
+                    .find(|(id, _)| id == bundle_id)
+                    .and_then(|(_, s)| s.registration(path.as_deref()));
+                match registration {
+                    None => ("not found".to_string(), true),
+                    Some(r) => {
+                        let actual = r.election == PluginElection::Use;
+                        let description = if actual { "enabled" } else { "disabled" };
+                        (description.to_string(), actual == *enabled)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How one component looked on the last check.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentReport {
+    pub component: String,
+    pub expected: String,
+    pub actual: String,
+    pub met: bool,
+    /// The attempt from which the expectation was met through the last check, or
+    /// `None` if it was not met in the end.
+    pub converged_after: Option<u32>,
+}
+
+/// The outcome of checking every expectation until all were met or the attempts ran
+/// out.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerificationReport {
+    pub schema_version: u32,
+    /// The command's target, `on` or `off`, or `set`.
+    pub target: &'static str,
+    /// Checks made, at most `verify-attempts`.
+    pub attempts: u32,
+    pub passed: bool,
+    pub components: Vec<ComponentReport>,
+}
+
+impl VerificationReport {
+    pub fn failed(&self) -> impl Iterator<Item = &ComponentReport> {
+        self.components.iter().filter(|c| !c.met)
+    }
+
+    pub fn print(&self) {
+        for component in &self.components {
+            match component.converged_after {
+                Some(attempt) if component.met => info!(
+                    "  {}: {} (met on attempt {})",
+                    component.component, component.actual, attempt
+                ),
+                _ => warn!(
+                    "  {}: expected {}, found {}",
+                    component.component, component.expected, component.actual
+                ),
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a verification report always serializes")
+    }
+
+    /// Where the report of the last failed verification is kept.
+    pub fn path(layout: &Layout) -> PathBuf {
+        layout.app_support_dir().join(REPORT_FILE_NAME)
+    }
+
+    fn save(&self, layout: &Layout) -> Result<PathBuf> {
+        let path = Self::path(layout);
+        let dir = layout.app_support_dir();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        fs::write(&path, self.to_json())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Check `expectations` against the status up to `verify-attempts` times until all are
+/// met, print the report, and fail naming every component that never converged. The
+/// report of a failure is also written as JSON to [`VerificationReport::path`], and
+/// with `json` every report is printed as JSON on standard output.
+pub fn verify(
+    sys: &dyn SystemBackend,
+    layout: &Layout,
+    target: &'static str,
+    expectations: &[Expectation],
+    timings: &Timings,
+    json: bool,
+) -> Result<VerificationReport> {
+    let max_attempts = timings.verify_attempts;
+    let mut components: Vec<ComponentReport> = expectations
+        .iter()
+        .map(|e| ComponentReport {
+            component: e.component(),
+            expected: e.expected(),
+            actual: String::new(),
+            met: false,
+            converged_after: None,
+        })
+        .collect();
+
+    let mut attempts = 0;
+    for attempt in 1..=max_attempts {
+        attempts = attempt;
+        let status = status::get_status(sys, layout)?;
+        for (expectation, component) in expectations.iter().zip(components.iter_mut()) {
+            let (actual, met) = expectation.check(&status);
+            component.actual = actual;
+            component.met = met;
+            component.converged_after = match component.converged_after {
+                Some(since) if met => Some(since),
+                _ if met => Some(attempt),
+                _ => None,
+            };
+        }
+
+        if components.iter().all(|c| c.met) {
+            break;
+        }
+        if attempt < max_attempts {
+            sys.clock().sleep(timings.verify_interval);
+        }
+    }
+
+    let report = VerificationReport {
+        schema_version: REPORT_SCHEMA_VERSION,
+        target,
+        attempts,
+        passed: components.iter().all(|c| c.met),
+        components,
+    };
+    report.print();
+    if json {
+        println!("{}", report.to_json());
+    }
+
+    if report.passed {
+        let path = VerificationReport::path(layout);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        return Ok(report);
+    }
+
+    let failed: Vec<_> = report
+        .failed()
+        .map(|c| {
+            format!(
+                "{} (expected {}, found {})",
+                c.component, c.expected, c.actual
+            )
+        })
+        .collect();
+    let saved = match report.save(layout) {
+        Ok(path) => format!("; report written to {}", path.display()),
+        Err(e) => format!("; could not write the report: {:#}", e),
+    };
+    anyhow::bail!(
+        "Verification failed after {}: {}{}",
+        timings.describe_verify(),
+        failed.join(", "),
+        saved
+    )
+}